@@ -0,0 +1,7 @@
+fn main() {
+  capnpc::CompilerCommand::new()
+    .src_prefix("schema")
+    .file("schema/game.capnp")
+    .run()
+    .expect("Failed to compile Cap'n Proto schema. Is `capnp` installed?");
+}
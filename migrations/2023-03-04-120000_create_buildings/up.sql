@@ -0,0 +1,9 @@
+CREATE TABLE buildings (
+  id UUID PRIMARY KEY,
+  name TEXT NOT NULL,
+  owner UUID NOT NULL,
+  x INTEGER NOT NULL,
+  y INTEGER NOT NULL,
+  cooldown INTEGER,
+  tick_counter INTEGER
+)
@@ -0,0 +1 @@
+ALTER TABLE users ADD COLUMN watts BIGINT NOT NULL DEFAULT 0
@@ -0,0 +1,8 @@
+ALTER TABLE users ADD COLUMN credits BIGINT NOT NULL DEFAULT 0, ADD COLUMN watts BIGINT NOT NULL DEFAULT 0;
+
+UPDATE users SET credits = user_resources.amount FROM user_resources
+  WHERE user_resources.user_id = users.id AND user_resources.resource = 'Credit';
+UPDATE users SET watts = user_resources.amount FROM user_resources
+  WHERE user_resources.user_id = users.id AND user_resources.resource = 'Watt';
+
+DROP TABLE user_resources
@@ -0,0 +1,11 @@
+CREATE TABLE user_resources (
+  user_id UUID NOT NULL REFERENCES users(id) ON DELETE CASCADE,
+  resource TEXT NOT NULL,
+  amount BIGINT NOT NULL,
+  PRIMARY KEY(user_id, resource)
+);
+
+INSERT INTO user_resources (user_id, resource, amount) SELECT id, 'Credit', credits FROM users;
+INSERT INTO user_resources (user_id, resource, amount) SELECT id, 'Watt', watts FROM users;
+
+ALTER TABLE users DROP COLUMN credits, DROP COLUMN watts
@@ -0,0 +1,5 @@
+ALTER TABLE buildings ADD COLUMN tick_counter INTEGER;
+
+UPDATE buildings SET tick_counter = tick_counters[1];
+
+ALTER TABLE buildings DROP COLUMN tick_counters
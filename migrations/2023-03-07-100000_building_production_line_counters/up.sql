@@ -0,0 +1,5 @@
+ALTER TABLE buildings ADD COLUMN tick_counters INTEGER[] NOT NULL DEFAULT '{}';
+
+UPDATE buildings SET tick_counters = ARRAY[tick_counter] WHERE tick_counter IS NOT NULL;
+
+ALTER TABLE buildings DROP COLUMN tick_counter
@@ -0,0 +1 @@
+ALTER TABLE buildings ADD COLUMN construction_counter INTEGER
@@ -0,0 +1 @@
+ALTER TABLE buildings ADD COLUMN level INTEGER NOT NULL DEFAULT 1
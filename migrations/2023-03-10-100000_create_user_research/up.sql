@@ -0,0 +1,5 @@
+CREATE TABLE user_research (
+  user_id UUID NOT NULL REFERENCES users(id) ON DELETE CASCADE,
+  research TEXT NOT NULL,
+  PRIMARY KEY(user_id, research)
+)
@@ -0,0 +1,11 @@
+CREATE TABLE trades (
+  id UUID PRIMARY KEY,
+  proposer UUID NOT NULL REFERENCES users(id) ON DELETE CASCADE,
+  recipient UUID NOT NULL REFERENCES users(id) ON DELETE CASCADE,
+  offered_resources TEXT[] NOT NULL,
+  offered_amounts BIGINT[] NOT NULL,
+  requested_resources TEXT[] NOT NULL,
+  requested_amounts BIGINT[] NOT NULL,
+  status TEXT NOT NULL,
+  closed_at TIMESTAMP NOT NULL
+)
@@ -0,0 +1,2 @@
+DROP TABLE market_trades;
+DROP TABLE market_orders
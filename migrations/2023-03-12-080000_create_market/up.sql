@@ -0,0 +1,21 @@
+CREATE TABLE market_orders (
+  id UUID PRIMARY KEY,
+  owner UUID NOT NULL REFERENCES users(id) ON DELETE CASCADE,
+  resource TEXT NOT NULL,
+  side TEXT NOT NULL,
+  price BIGINT NOT NULL,
+  quantity BIGINT NOT NULL,
+  placed_at TIMESTAMP NOT NULL
+);
+
+CREATE TABLE market_trades (
+  id UUID PRIMARY KEY,
+  resource TEXT NOT NULL,
+  price BIGINT NOT NULL,
+  quantity BIGINT NOT NULL,
+  buyer UUID NOT NULL REFERENCES users(id) ON DELETE CASCADE,
+  seller UUID NOT NULL REFERENCES users(id) ON DELETE CASCADE,
+  traded_at TIMESTAMP NOT NULL
+);
+
+CREATE INDEX market_trades_resource_traded_at ON market_trades (resource, traded_at)
@@ -0,0 +1 @@
+ALTER TABLE buildings ADD COLUMN sequence BIGSERIAL NOT NULL
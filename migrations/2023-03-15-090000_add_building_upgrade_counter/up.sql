@@ -0,0 +1 @@
+ALTER TABLE buildings ADD COLUMN upgrade_counter INTEGER
@@ -1,5 +1,6 @@
 use clap::{Parser, Subcommand};
 
+use crate::game::building::BuildingDefinitionTable;
 use crate::properties::{GameProperties, GamePropertiesError};
 
 #[derive(Parser)]
@@ -16,6 +17,9 @@ pub enum Commands {
 
   /// Enables a debug window viewer to display the current world
   DebugView,
+
+  /// Validates the building definition files, reporting every problem found
+  ValidateBuildings,
 }
 
 #[derive(PartialEq, Eq)]
@@ -42,6 +46,18 @@ pub fn process_command(command: Option<Commands>) -> Option<ArgsSideEffect> {
         Some(ArgsSideEffect::Exit)
       },
       Commands::DebugView => Some(ArgsSideEffect::AddDebuggingWindowPlugins),
+      Commands::ValidateBuildings => {
+        println!("Validating building definitions...");
+        match BuildingDefinitionTable::try_load() {
+          Ok(table) => println!("All {} building definitions are valid", table.len()),
+          Err(errors) => {
+            errors.iter().for_each(|err| println!("Error: {}", err));
+            std::process::exit(1);
+          },
+        }
+
+        Some(ArgsSideEffect::Exit)
+      },
     }
   } else {
     None
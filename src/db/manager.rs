@@ -5,6 +5,7 @@
 //! waiting for a connection.
 
 use std::ops::{Deref, DerefMut};
+use std::sync::Arc;
 
 use bevy::prelude::Resource;
 use diesel::r2d2::{ConnectionManager, Pool, PooledConnection};
@@ -33,13 +34,14 @@ impl<'a> DerefMut for AcquiredDatabaseConnection<'a> {
   }
 }
 
-/// The Main Database Management Resource.
-#[derive(Resource)]
+/// The Main Database Management Resource. Clones share the same pool, so that
+/// the database can be used off of the game thread.
+#[derive(Resource, Clone)]
 pub struct DatabaseManager {
   /// Database Pool.
   pool: Option<Pool<ConnectionManager<PgConnection>>>,
   /// Tracks number of connections remaining avaliable.
-  take_count: Semaphore,
+  take_count: Arc<Semaphore>,
 }
 
 impl DatabaseManager {
@@ -47,14 +49,14 @@ impl DatabaseManager {
   pub fn new(connection: String) -> Result<Self, String> {
     let pool = Pool::new(ConnectionManager::new(connection)).map_err(|x| x.to_string())?;
     Ok(Self {
-      take_count: Semaphore::new(pool.state().idle_connections as usize),
+      take_count: Arc::new(Semaphore::new(pool.state().idle_connections as usize)),
       pool: Some(pool),
     })
   }
 
   pub fn test_harness() -> Self {
     Self {
-      take_count: Semaphore::new(0),
+      take_count: Arc::new(Semaphore::new(0)),
       pool: None,
     }
   }
@@ -0,0 +1,61 @@
+use diesel::{delete, insert_into, Connection, ExpressionMethods, PgConnection, QueryDsl, RunQueryDsl};
+use uuid::Uuid;
+
+use crate::db::schema::buildings;
+
+/// Persisted state of a building entity.
+#[derive(Queryable, Identifiable, Insertable, AsChangeset, Clone, Debug, PartialEq, Eq)]
+#[diesel(table_name = buildings, treat_none_as_null = true)]
+pub struct BuildingObj {
+  pub id: Uuid,
+  /// Name of the building definition.
+  pub name: String,
+  pub owner: Uuid,
+  pub x: i32,
+  pub y: i32,
+  /// Remaining cooldown, if the building is on cooldown.
+  pub cooldown: Option<i32>,
+  /// Progress towards the next tick of each production line, in the order of
+  /// the building definition.
+  pub tick_counters: Vec<Option<i32>>,
+  /// Progress towards completion, if the building is under construction.
+  pub construction_counter: Option<i32>,
+  /// Level of the building, starting at 1.
+  pub level: i32,
+  /// Order in which the building was placed, older buildings first.
+  pub sequence: i64,
+  /// Progress towards the next level, if the building is being upgraded.
+  pub upgrade_counter: Option<i32>,
+}
+
+impl BuildingObj {
+  /// Loads every building, in the order they were placed.
+  pub fn get_all(conn: &mut PgConnection) -> Result<Vec<Self>, diesel::result::Error> {
+    use crate::db::schema::buildings::dsl::*;
+
+    buildings.order(sequence).load::<Self>(conn)
+  }
+
+  /// Inserts or updates all given buildings within a single transaction.
+  pub fn save_all(conn: &mut PgConnection, records: &[Self]) -> Result<(), diesel::result::Error> {
+    use crate::db::schema::buildings::dsl::*;
+
+    conn.transaction(|conn| {
+      records.iter().try_for_each(|record| {
+        insert_into(buildings)
+          .values(record)
+          .on_conflict(id)
+          .do_update()
+          .set(record)
+          .execute(conn)
+          .map(|_| ())
+      })
+    })
+  }
+
+  pub fn delete_all(conn: &mut PgConnection, ids: &[Uuid]) -> Result<(), diesel::result::Error> {
+    use crate::db::schema::buildings::dsl::*;
+
+    delete(buildings.filter(id.eq_any(ids))).execute(conn).map(|_| ())
+  }
+}
@@ -1,4 +1,4 @@
-use diesel::{insert_into, ExpressionMethods, PgConnection, QueryDsl, RunQueryDsl};
+use diesel::{insert_into, Connection, ExpressionMethods, PgConnection, QueryDsl, RunQueryDsl};
 use tracing::warn;
 
 use super::World;
@@ -85,4 +85,37 @@ impl Chunk {
 
     insert_into(chunks).values(&chunk).execute(conn).map(|_| ())
   }
+
+  /// Overwrites a previously saved chunk, such as after tiles were mined.
+  pub fn update_chunk(
+    conn: &mut PgConnection,
+    chunk_x: i64,
+    chunk_y: i64,
+    chunk_tiles: &[TerrainTile],
+  ) -> Result<(), diesel::result::Error> {
+    use crate::db::schema::chunks::dsl::*;
+
+    let tile_ids = chunk_tiles
+      .iter()
+      .map(|tile| tile.into_chunk_tile_id())
+      .collect::<Vec<_>>();
+
+    let chunk = Chunk {
+      x: chunk_x,
+      y: chunk_y,
+      tiles: tile_ids,
+    };
+
+    conn.transaction(|conn| {
+      ComplexTile::replace_chunk(conn, chunk_x, chunk_y, chunk_tiles)?;
+
+      insert_into(chunks)
+        .values(&chunk)
+        .on_conflict((x, y))
+        .do_update()
+        .set(tiles.eq(&chunk.tiles))
+        .execute(conn)
+        .map(|_| ())
+    })
+  }
 }
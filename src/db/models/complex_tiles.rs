@@ -1,4 +1,4 @@
-use diesel::{insert_into, ExpressionMethods, PgConnection, QueryDsl, RunQueryDsl};
+use diesel::{delete, insert_into, ExpressionMethods, PgConnection, QueryDsl, RunQueryDsl};
 use hashbrown::HashMap;
 
 use crate::db::complex_tiles;
@@ -62,4 +62,17 @@ impl ComplexTile {
 
     insert_into(complex_tiles).values(inserts).execute(conn).map(|_| ())
   }
+
+  /// Replaces all stored complex tiles of the chunk.
+  pub fn replace_chunk(
+    conn: &mut PgConnection,
+    x_pos: i64,
+    y_pos: i64,
+    chunk: &[TerrainTile],
+  ) -> Result<(), diesel::result::Error> {
+    use crate::db::schema::complex_tiles::dsl::*;
+
+    delete(complex_tiles.filter(chunk_x.eq(x_pos)).filter(chunk_y.eq(y_pos))).execute(conn)?;
+    Self::save_chunk(conn, x_pos, y_pos, chunk)
+  }
 }
@@ -0,0 +1,149 @@
+use chrono::NaiveDateTime;
+use diesel::{delete, insert_into, Connection, ExpressionMethods, PgConnection, QueryDsl, RunQueryDsl};
+use hashbrown::HashMap;
+use tracing::warn;
+use uuid::Uuid;
+
+use crate::db::schema::{market_orders, market_trades};
+use crate::game::market::{MarketOrder, MarketTrade, OrderSide};
+use crate::game::resources::Resource;
+
+/// Persisted state of an open [MarketOrder].
+#[derive(Queryable, Insertable, AsChangeset, Clone, Debug, PartialEq, Eq)]
+#[diesel(table_name = market_orders)]
+pub struct MarketOrderObj {
+  pub id: Uuid,
+  pub owner: Uuid,
+  /// Name of the resource, see [Resource::name].
+  pub resource: String,
+  /// Name of the side, see [OrderSide::name].
+  pub side: String,
+  pub price: i64,
+  /// Quantity left to fill.
+  pub quantity: i64,
+  pub placed_at: NaiveDateTime,
+}
+
+impl From<&MarketOrder> for MarketOrderObj {
+  fn from(order: &MarketOrder) -> Self {
+    Self {
+      id: order.id,
+      owner: order.owner,
+      resource: order.resource.name(),
+      side: order.side.name(),
+      price: order.price,
+      quantity: order.quantity,
+      placed_at: order.placed_at,
+    }
+  }
+}
+
+impl MarketOrderObj {
+  /// Parses the stored order, skipping orders of resources that no longer
+  /// exist.
+  pub fn parse(self) -> Option<MarketOrder> {
+    match (Resource::from_name(&self.resource), OrderSide::from_name(&self.side)) {
+      (Some(resource), Some(side)) => Some(MarketOrder {
+        id: self.id,
+        owner: self.owner,
+        resource,
+        side,
+        price: self.price,
+        quantity: self.quantity,
+        placed_at: self.placed_at,
+      }),
+      _ => {
+        warn!(
+          "Skipping market order {} with unknown side {} or resource {}",
+          self.id, self.side, self.resource
+        );
+        None
+      },
+    }
+  }
+
+  pub fn get_all(conn: &mut PgConnection) -> Result<Vec<Self>, diesel::result::Error> {
+    use crate::db::schema::market_orders::dsl::*;
+
+    market_orders.load::<Self>(conn)
+  }
+
+  /// Replaces the stored orders with the given open orders, within a single
+  /// transaction.
+  pub fn replace_all(conn: &mut PgConnection, records: &[Self]) -> Result<(), diesel::result::Error> {
+    use crate::db::schema::market_orders::dsl::*;
+
+    conn.transaction(|conn| {
+      let open = records.iter().map(|record| record.id).collect::<Vec<_>>();
+      delete(market_orders.filter(id.ne_all(open))).execute(conn)?;
+
+      records.iter().try_for_each(|record| {
+        insert_into(market_orders)
+          .values(record)
+          .on_conflict(id)
+          .do_update()
+          .set(record)
+          .execute(conn)
+          .map(|_| ())
+      })
+    })
+  }
+}
+
+/// History of a fill between two [MarketOrder]s.
+#[derive(Queryable, Insertable, Clone, Debug, PartialEq, Eq)]
+#[diesel(table_name = market_trades)]
+pub struct MarketTradeObj {
+  pub id: Uuid,
+  /// Name of the resource, see [Resource::name].
+  pub resource: String,
+  /// Price of each unit, in Credits.
+  pub price: i64,
+  pub quantity: i64,
+  pub buyer: Uuid,
+  pub seller: Uuid,
+  pub traded_at: NaiveDateTime,
+}
+
+impl From<&MarketTrade> for MarketTradeObj {
+  fn from(trade: &MarketTrade) -> Self {
+    Self {
+      id: trade.id,
+      resource: trade.resource.name(),
+      price: trade.price,
+      quantity: trade.quantity,
+      buyer: trade.buyer,
+      seller: trade.seller,
+      traded_at: trade.traded_at,
+    }
+  }
+}
+
+impl MarketTradeObj {
+  /// Appends the given fills to the market history.
+  pub fn insert_all(conn: &mut PgConnection, records: &[Self]) -> Result<(), diesel::result::Error> {
+    use crate::db::schema::market_trades::dsl::*;
+
+    insert_into(market_trades)
+      .values(records)
+      .on_conflict_do_nothing()
+      .execute(conn)
+      .map(|_| ())
+  }
+
+  /// Price of the last trade of every resource that was ever traded.
+  pub fn last_prices(conn: &mut PgConnection) -> Result<HashMap<Resource, i64>, diesel::result::Error> {
+    use crate::db::schema::market_trades::dsl::*;
+
+    Ok(
+      market_trades
+        .distinct_on(resource)
+        .order((resource, traded_at.desc()))
+        .select((resource, price))
+        .load::<(String, i64)>(conn)?
+        .into_iter()
+        .filter_map(|(name, last_price)| Some((Resource::from_name(&name)?, last_price)))
+        .collect(),
+    )
+  }
+}
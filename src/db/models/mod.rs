@@ -1,11 +1,23 @@
 //! Database Models.
 
+mod building;
 mod chunk;
 mod complex_tiles;
+mod market;
+mod trade;
 mod user;
+mod user_credentials;
+mod user_research;
+mod user_resource;
 mod world;
 
+pub use building::*;
 pub use chunk::*;
 pub use complex_tiles::*;
+pub use market::*;
+pub use trade::*;
 pub use user::*;
+pub use user_credentials::*;
+pub use user_research::*;
+pub use user_resource::*;
 pub use world::*;
@@ -0,0 +1,40 @@
+use chrono::NaiveDateTime;
+use diesel::{insert_into, PgConnection, RunQueryDsl};
+use uuid::Uuid;
+
+use crate::db::schema::trades;
+
+/// History of a closed trade offer between two users.
+#[derive(Queryable, Insertable, Clone, Debug, PartialEq, Eq)]
+#[diesel(table_name = trades)]
+pub struct TradeObj {
+  pub id: Uuid,
+  pub proposer: Uuid,
+  pub recipient: Uuid,
+  /// Names of the resources offered by the proposer, see
+  /// [Resource::name](crate::game::resources::Resource::name).
+  pub offered_resources: Vec<Option<String>>,
+  /// Amount of each offered resource.
+  pub offered_amounts: Vec<Option<i64>>,
+  /// Names of the resources requested from the recipient.
+  pub requested_resources: Vec<Option<String>>,
+  /// Amount of each requested resource.
+  pub requested_amounts: Vec<Option<i64>>,
+  /// How the offer was closed, such as `Accepted`.
+  pub status: String,
+  pub closed_at: NaiveDateTime,
+}
+
+impl TradeObj {
+  /// Inserts the history of all given trades. Closed trades never change, so
+  /// existing rows are left untouched.
+  pub fn insert_all(conn: &mut PgConnection, records: &[Self]) -> Result<(), diesel::result::Error> {
+    use crate::db::schema::trades::dsl::*;
+
+    insert_into(trades)
+      .values(records)
+      .on_conflict_do_nothing()
+      .execute(conn)
+      .map(|_| ())
+  }
+}
@@ -1,92 +1,124 @@
-use diesel::{insert_into, ExpressionMethods, QueryDsl, RunQueryDsl};
-use hashbrown::HashMap;
+use diesel::{insert_into, Connection, ExpressionMethods, PgConnection, QueryDsl, RunQueryDsl};
+use hashbrown::{HashMap, HashSet};
 use tracing::warn;
 use uuid::Uuid;
 
-use crate::db::schema::users;
+use super::{UserCredentials, UserResearch, UserResource};
 use crate::db::PooledPgConnection;
 use crate::game::resources::{Resource, ResourceDelta};
 
-#[derive(Queryable, Identifiable, Insertable, Clone, Debug)]
-#[diesel(table_name = users)]
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
 pub struct User {
   pub id: Uuid,
-  pub credits: i64,
-}
-
-impl Default for User {
-  fn default() -> Self {
-    Self {
-      id: Default::default(),
-      credits: Default::default(),
-    }
-  }
+  /// Amount of every resource held by the user, stored in `user_resources`.
+  pub resources: HashMap<Resource, i64>,
+  /// Research completed by the user, stored in `user_research`.
+  pub research: HashSet<String>,
 }
 
 impl User {
-  pub fn new(conn: &mut PooledPgConnection, user_id: Uuid) -> Self {
+  /// Creates a user along with their credentials, within a single
+  /// transaction.
+  pub fn register(conn: &mut PgConnection, username: &str, password_hash: &str) -> Result<Self, diesel::result::Error> {
     use crate::db::schema::users::dsl::*;
 
-    if let Ok(found_user) = users.find(user_id).first::<User>(conn) {
-      return found_user;
-    } else {
-      let new_user = User {
-        id: user_id,
-        ..Default::default()
-      };
+    let user = Self {
+      id: Uuid::new_v4(),
+      ..Default::default()
+    };
+
+    conn.transaction(|conn| {
+      insert_into(users).values(id.eq(user.id)).execute(conn)?;
+      UserCredentials {
+        user_id: user.id,
+        username: username.to_string(),
+        password_hash: password_hash.to_string(),
+      }
+      .insert(conn)
+    })?;
 
-      new_user.save(conn).ok();
+    Ok(user)
+  }
 
-      return new_user;
+  pub fn with_resources(id: Uuid, resources: impl IntoIterator<Item = (Resource, i64)>) -> Self {
+    Self {
+      id,
+      resources: resources.into_iter().collect(),
+      ..Default::default()
     }
   }
 
   pub fn save(&self, conn: &mut PooledPgConnection) -> Result<(), diesel::result::Error> {
+    Self::save_all(conn, std::slice::from_ref(self)).map_err(|err| {
+      warn!("Error while saving user {}", err);
+      err
+    })
+  }
+
+  /// Inserts or updates all given users within a single transaction.
+  pub fn save_all(conn: &mut PgConnection, all_users: &[Self]) -> Result<(), diesel::result::Error> {
     use crate::db::schema::users::dsl::*;
 
-    if let Ok(found_user) = users.find(self.id).first::<User>(conn) {
-      match diesel::update(&found_user).set(credits.eq(self.credits)).execute(conn) {
-        Ok(_) => Ok(()),
-        Err(err) => {
-          warn!("Error while updating user {}", err);
-          Err(err)
-        },
-      }
-    } else {
-      match insert_into(users).values(self).execute(conn) {
-        Ok(_) => Ok(()),
-        Err(err) => {
-          warn!("Error while inserting user {}", err);
-          Err(err)
-        },
-      }
-    }
+    conn.transaction(|conn| {
+      all_users.iter().try_for_each(|user| {
+        insert_into(users)
+          .values(id.eq(user.id))
+          .on_conflict_do_nothing()
+          .execute(conn)?;
+        UserResource::save_user(conn, user)?;
+        UserResearch::save_user(conn, user)
+      })
+    })
   }
 
   pub fn get_all_users(conn: &mut PooledPgConnection) -> HashMap<Uuid, Self> {
     use crate::db::schema::users::dsl::*;
 
-    let all_users = users
-      .load::<Self>(conn)
-      .expect("Failed to get all users, connection dead?");
-    all_users.into_iter().map(|u| (u.id.clone(), u)).collect()
-  }
+    let mut all_users = users
+      .select(id)
+      .load::<Uuid>(conn)
+      .expect("Failed to get all users, connection dead?")
+      .into_iter()
+      .map(|user_id| (user_id, Self::with_resources(user_id, [])))
+      .collect::<HashMap<_, _>>();
 
-  pub fn pay_resources(&mut self, delta: &ResourceDelta) -> bool {
-    macro_rules! resource_cost {
-      ($access:expr) => {
-        if $access >= delta.value.abs() {
-          $access += delta.value;
-          true
-        } else {
-          false
+    UserResource::get_all(conn)
+      .expect("Failed to get all user resources, connection dead?")
+      .into_iter()
+      .for_each(|(user_id, resource, amount)| {
+        if let Some(user) = all_users.get_mut(&user_id) {
+          user.set(resource, amount);
         }
-      };
-    }
+      });
 
-    match delta.resource {
-      Resource::Watt => todo!(),
-      Resource::Credit => resource_cost!(self.credits),
+    UserResearch::get_all(conn)
+      .expect("Failed to get all user research, connection dead?")
+      .into_iter()
+      .for_each(|completed| {
+        if let Some(user) = all_users.get_mut(&completed.user_id) {
+          user.research.insert(completed.research);
+        }
+      });
+
+    all_users
+  }
+
+  /// Amount of the resource held by the user.
+  pub fn get(&self, resource: Resource) -> i64 {
+    self.resources.get(&resource).copied().unwrap_or_default()
+  }
+
+  pub fn set(&mut self, resource: Resource, amount: i64) {
+    self.resources.insert(resource, amount);
+  }
+
+  pub fn pay_resources(&mut self, delta: &ResourceDelta) -> bool {
+    let amount = self.get(delta.resource);
+    if amount >= delta.value.abs() {
+      self.set(delta.resource, amount + delta.value);
+      true
+    } else {
+      false
     }
   }
 
@@ -99,9 +131,7 @@ impl User {
       pay_attempt
         .into_iter()
         .filter(|(success, _)| *success)
-        .for_each(|(_, delta)| {
-          self.give_resources(&delta);
-        });
+        .for_each(|(_, delta)| self.return_resources(&delta.as_product()));
 
       false
     } else {
@@ -109,10 +139,9 @@ impl User {
     }
   }
 
-  pub fn give_resources(&mut self, delta: &ResourceDelta) {
-    match delta.resource {
-      Resource::Watt => todo!(),
-      Resource::Credit => self.credits += delta.value,
-    }
+  /// Gives resources the user already paid for, such as refunds, escrow and
+  /// settled trades, regardless of their storage capacity.
+  pub fn return_resources(&mut self, delta: &ResourceDelta) {
+    self.set(delta.resource, self.get(delta.resource) + delta.value);
   }
 }
@@ -0,0 +1,31 @@
+use diesel::{insert_into, ExpressionMethods, OptionalExtension, PgConnection, QueryDsl, RunQueryDsl};
+use uuid::Uuid;
+
+use crate::db::schema::user_credentials;
+
+/// Login of a user, with their password hashed by
+/// [hash_password](crate::rpc::hash_password).
+#[derive(Queryable, Insertable, Clone, Debug, PartialEq, Eq)]
+#[diesel(table_name = user_credentials)]
+pub struct UserCredentials {
+  pub user_id: Uuid,
+  pub username: String,
+  pub password_hash: String,
+}
+
+impl UserCredentials {
+  pub fn find(conn: &mut PgConnection, name: &str) -> Result<Option<Self>, diesel::result::Error> {
+    use crate::db::schema::user_credentials::dsl::*;
+
+    user_credentials
+      .filter(username.eq(name))
+      .first::<Self>(conn)
+      .optional()
+  }
+
+  pub fn insert(&self, conn: &mut PgConnection) -> Result<(), diesel::result::Error> {
+    use crate::db::schema::user_credentials::dsl::*;
+
+    insert_into(user_credentials).values(self).execute(conn).map(|_| ())
+  }
+}
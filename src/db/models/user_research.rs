@@ -0,0 +1,46 @@
+use diesel::{insert_into, PgConnection, RunQueryDsl};
+use uuid::Uuid;
+
+use super::User;
+use crate::db::schema::user_research;
+
+/// A research completed by a user, unlocking buildings that require it.
+#[derive(Queryable, Insertable, Clone, Debug)]
+#[diesel(table_name = user_research)]
+pub struct UserResearch {
+  pub user_id: Uuid,
+  pub research: String,
+}
+
+impl UserResearch {
+  pub fn get_all(conn: &mut PgConnection) -> Result<Vec<Self>, diesel::result::Error> {
+    use crate::db::schema::user_research::dsl::*;
+
+    user_research.load::<Self>(conn)
+  }
+
+  /// Inserts every research completed by the user. Research is never lost, so
+  /// rows are only ever added.
+  pub fn save_user(conn: &mut PgConnection, user: &User) -> Result<(), diesel::result::Error> {
+    use crate::db::schema::user_research::dsl::*;
+
+    let rows = user
+      .research
+      .iter()
+      .map(|completed| Self {
+        user_id: user.id,
+        research: completed.clone(),
+      })
+      .collect::<Vec<_>>();
+
+    if rows.is_empty() {
+      return Ok(());
+    }
+
+    insert_into(user_research)
+      .values(rows)
+      .on_conflict_do_nothing()
+      .execute(conn)
+      .map(|_| ())
+  }
+}
@@ -0,0 +1,70 @@
+use diesel::upsert::excluded;
+use diesel::{insert_into, ExpressionMethods, PgConnection, RunQueryDsl};
+use tracing::warn;
+use uuid::Uuid;
+
+use super::User;
+use crate::db::schema::user_resources;
+use crate::game::resources::Resource;
+
+/// Amount of a single [Resource] held by a user.
+#[derive(Queryable, Insertable, Clone, Debug)]
+#[diesel(table_name = user_resources)]
+pub struct UserResource {
+  pub user_id: Uuid,
+  /// Name of the resource, see [Resource::name].
+  pub resource: String,
+  pub amount: i64,
+}
+
+impl UserResource {
+  /// Parses the stored resource, skipping resources that no longer exist.
+  fn parse(self) -> Option<(Uuid, Resource, i64)> {
+    match Resource::from_name(&self.resource) {
+      Some(resource) => Some((self.user_id, resource, self.amount)),
+      None => {
+        warn!("Skipping unknown resource {} of user {}", self.resource, self.user_id);
+        None
+      },
+    }
+  }
+
+  pub fn get_all(conn: &mut PgConnection) -> Result<Vec<(Uuid, Resource, i64)>, diesel::result::Error> {
+    use crate::db::schema::user_resources::dsl::*;
+
+    Ok(
+      user_resources
+        .load::<Self>(conn)?
+        .into_iter()
+        .filter_map(Self::parse)
+        .collect(),
+    )
+  }
+
+  /// Inserts or updates every resource held by the user.
+  pub fn save_user(conn: &mut PgConnection, user: &User) -> Result<(), diesel::result::Error> {
+    use crate::db::schema::user_resources::dsl::*;
+
+    let rows = user
+      .resources
+      .iter()
+      .map(|(held_resource, held_amount)| Self {
+        user_id: user.id,
+        resource: held_resource.name(),
+        amount: *held_amount,
+      })
+      .collect::<Vec<_>>();
+
+    if rows.is_empty() {
+      return Ok(());
+    }
+
+    insert_into(user_resources)
+      .values(rows)
+      .on_conflict((user_id, resource))
+      .do_update()
+      .set(amount.eq(excluded(amount)))
+      .execute(conn)
+      .map(|_| ())
+  }
+}
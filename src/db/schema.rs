@@ -1,5 +1,21 @@
 // @generated automatically by Diesel CLI.
 
+diesel::table! {
+    buildings (id) {
+        id -> Uuid,
+        name -> Text,
+        owner -> Uuid,
+        x -> Int4,
+        y -> Int4,
+        cooldown -> Nullable<Int4>,
+        tick_counters -> Array<Nullable<Int4>>,
+        construction_counter -> Nullable<Int4>,
+        level -> Int4,
+        sequence -> Int8,
+        upgrade_counter -> Nullable<Int4>,
+    }
+}
+
 diesel::table! {
     chunks (x, y) {
         x -> Int8,
@@ -18,10 +34,70 @@ diesel::table! {
     }
 }
 
+diesel::table! {
+    market_orders (id) {
+        id -> Uuid,
+        owner -> Uuid,
+        resource -> Text,
+        side -> Text,
+        price -> Int8,
+        quantity -> Int8,
+        placed_at -> Timestamp,
+    }
+}
+
+diesel::table! {
+    market_trades (id) {
+        id -> Uuid,
+        resource -> Text,
+        price -> Int8,
+        quantity -> Int8,
+        buyer -> Uuid,
+        seller -> Uuid,
+        traded_at -> Timestamp,
+    }
+}
+
+diesel::table! {
+    trades (id) {
+        id -> Uuid,
+        proposer -> Uuid,
+        recipient -> Uuid,
+        offered_resources -> Array<Nullable<Text>>,
+        offered_amounts -> Array<Nullable<Int8>>,
+        requested_resources -> Array<Nullable<Text>>,
+        requested_amounts -> Array<Nullable<Int8>>,
+        status -> Text,
+        closed_at -> Timestamp,
+    }
+}
+
+diesel::table! {
+    user_credentials (user_id) {
+        user_id -> Uuid,
+        username -> Text,
+        password_hash -> Text,
+    }
+}
+
+diesel::table! {
+    user_research (user_id, research) {
+        user_id -> Uuid,
+        research -> Text,
+    }
+}
+
+diesel::table! {
+    user_resources (user_id, resource) {
+        user_id -> Uuid,
+        resource -> Text,
+        amount -> Int8,
+    }
+}
+
 diesel::table! {
     users (id) {
         id -> Uuid,
-        credits -> Int8,
     }
 }
 
@@ -33,4 +109,9 @@ diesel::table! {
     }
 }
 
-diesel::allow_tables_to_appear_in_same_query!(chunks, complex_tiles, users, worlds,);
+diesel::joinable!(market_orders -> users (owner));
+diesel::joinable!(user_credentials -> users (user_id));
+diesel::joinable!(user_research -> users (user_id));
+diesel::joinable!(user_resources -> users (user_id));
+
+diesel::allow_tables_to_appear_in_same_query!(buildings, chunks, complex_tiles, market_orders, market_trades, trades, user_credentials, user_research, user_resources, users, worlds,);
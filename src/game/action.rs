@@ -4,56 +4,778 @@
 //! intervention.
 
 use bevy::prelude::*;
+use hashbrown::HashSet;
 use uuid::Uuid;
 
-use super::building::{BuildingPerformAction, BUILDING_TABLE};
+use super::building::{
+  Building, BuildingDefinitionTable, BuildingDemolish, BuildingId, BuildingLookup, BuildingPerformAction,
+  BuildingPlacement, BuildingUpgrade, PlacementError, PrerequisiteError,
+};
+use super::market::OrderSide;
+use super::resources::{Resource, ResourceDelta};
 use super::stages::GameStage;
+use super::user::{UserOwned, UserResourceTable};
 
 /// All game actions, performed by a given [User]
 pub enum GameAction {
   /// Build a building
   BuildBuilding { building_id: String, position: IVec2 },
   /// Attempt to perform an action on a building
-  PerformBuildingAction { building_entity_id: u32, action_id: String },
+  PerformBuildingAction { building: BuildingId, action_id: String },
+  /// Demolish a building, refunding part of its construction costs
+  DemolishBuilding { building: BuildingId },
+  /// Move a building to another position
+  MoveBuilding { building: BuildingId, position: IVec2 },
+  /// Upgrade a building to its next level
+  UpgradeBuilding { building: BuildingId },
+  /// Offer resources to another user in exchange for some of theirs
+  ProposeTrade {
+    recipient: Uuid,
+    offered: Vec<ResourceDelta>,
+    requested: Vec<ResourceDelta>,
+  },
+  /// Accept a trade offered to the user
+  AcceptTrade { trade: Uuid },
+  /// Cancel or decline a trade offer
+  CancelTrade { trade: Uuid },
+  /// Post a limit order on the market, priced in Credits per unit
+  PlaceOrder {
+    resource: Resource,
+    side: OrderSide,
+    price: i64,
+    quantity: i64,
+  },
+  /// Cancel a market order, returning what is left of its escrow
+  CancelOrder { order: Uuid },
 }
 
+/// Identifies a submitted [UserGameAction], so that its [ActionOutcome] can
+/// be matched back to whoever submitted it.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub struct ActionTicket(pub u64);
+
 /// An action tied to a [User]
 pub struct UserGameAction {
   pub user_id: Uuid,
   pub action: GameAction,
+  pub ticket: Option<ActionTicket>,
+}
+
+/// Reasons a [GameAction] can be rejected.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ActionRejection {
+  /// No building definition exists with the given id.
+  UnknownBuilding,
+  /// The targeted building, trade offer or market order does not exist.
+  UnknownEntity,
+  /// The building has no action with the given id.
+  UnknownAction,
+  /// The acting user does not exist.
+  UnknownUser,
+  /// The acting user does not own the building.
+  NotOwner,
+  /// The acting user could not pay the costs of the action.
+  InsufficientResources,
+  /// The building is on cooldown.
+  OnCooldown,
+  /// The building is still under construction.
+  UnderConstruction,
+  /// The building is already at its highest level.
+  MaxLevel,
+  /// The building cannot be placed at the requested position.
+  InvalidPlacement(PlacementError),
+  /// The acting user has not unlocked the building yet.
+  Locked(PrerequisiteError),
+  /// The trade exchanges nothing, or is with the acting user themselves.
+  InvalidTrade,
+  /// The order is not for a positive quantity of a tradeable resource at a
+  /// positive price.
+  InvalidOrder,
+  /// Another request was already made on the target this tick.
+  Busy,
 }
 
-pub fn process_game_actions(mut commands: Commands, mut events: EventReader<UserGameAction>) {
-  events
-    .iter()
-    .for_each(|user_game_action| match &user_game_action.action {
+/// Result of processing a [GameAction], the entity acted upon if accepted.
+pub type ActionResult = Result<Entity, ActionRejection>;
+
+/// Emitted exactly once for every processed [UserGameAction].
+#[derive(Debug)]
+pub struct ActionOutcome {
+  pub user_id: Uuid,
+  pub ticket: Option<ActionTicket>,
+  pub result: ActionResult,
+}
+
+pub fn process_game_actions(
+  mut commands: Commands,
+  mut events: EventReader<UserGameAction>,
+  mut outcomes: EventWriter<ActionOutcome>,
+  mut placement: BuildingPlacement,
+  mut user_table: ResMut<UserResourceTable>,
+  building_table: Res<BuildingDefinitionTable>,
+  buildings: BuildingLookup,
+) {
+  // Requests are stored as a component on their target. Only one request is
+  // accepted per building and tick, whatever its kind, so that a move and a
+  // demolish never act on the same building at once.
+  let mut requested = HashSet::new();
+
+  events.iter().for_each(|user_game_action| {
+    let UserGameAction { user_id, ticket, .. } = *user_game_action;
+    let outcome = |result| ActionOutcome {
+      user_id,
+      ticket,
+      result,
+    };
+    let mut request = |ent| requested.insert(ent);
+
+    match &user_game_action.action {
       GameAction::BuildBuilding { building_id, position } => {
-        if let Some(building_def) = BUILDING_TABLE.get(building_id) {
-          building_def.spawn(&mut commands, user_game_action.user_id, *position);
-        } else {
-          warn!("Attempted to spawn unknown building with id {}", building_id);
-        }
+        let result = building_table
+          .get(building_id)
+          .ok_or(ActionRejection::UnknownBuilding)
+          .and_then(|building_def| {
+            let user = user_table.get_mut(&user_id).ok_or(ActionRejection::UnknownUser)?;
+
+            building_def
+              .check_prerequisites(&user.research, &buildings.owned_levels(user_id))
+              .map_err(ActionRejection::Locked)?;
+
+            placement
+              .check(building_def, *position)
+              .map_err(ActionRejection::InvalidPlacement)?;
+
+            if !user.pay_resource_transaction(building_def.costs.clone().unwrap_or_default()) {
+              return Err(ActionRejection::InsufficientResources);
+            }
+
+            let sequence = placement.next_sequence();
+            let ent = building_def.construct(&mut commands, sequence, user_id, *position);
+            placement.occupy(ent, building_def, *position);
+            Ok(ent)
+          });
+
+        outcomes.send(outcome(result));
       },
-      GameAction::PerformBuildingAction {
-        building_entity_id,
-        action_id,
-      } => {
-        commands
-          .entity(Entity::from_raw(*building_entity_id))
-          .insert(BuildingPerformAction {
-            user_origin: user_game_action.user_id,
+      GameAction::PerformBuildingAction { building, action_id } => match buildings.find(*building) {
+        Some(ent) if !request(ent) => outcomes.send(outcome(Err(ActionRejection::Busy))),
+        // The outcome is emitted once the building processes the action.
+        Some(ent) => {
+          commands.entity(ent).insert(BuildingPerformAction {
+            user_origin: user_id,
             id: action_id.clone(),
+            ticket,
           });
+        },
+        None => outcomes.send(outcome(Err(ActionRejection::UnknownEntity))),
       },
+      GameAction::DemolishBuilding { building } => match buildings.find(*building) {
+        Some(ent) if !request(ent) => outcomes.send(outcome(Err(ActionRejection::Busy))),
+        Some(ent) => {
+          commands.entity(ent).insert(BuildingDemolish {
+            user_origin: user_id,
+            ticket,
+          });
+        },
+        None => outcomes.send(outcome(Err(ActionRejection::UnknownEntity))),
+      },
+      GameAction::MoveBuilding { building, position } => match buildings.find(*building) {
+        Some(ent) if !request(ent) => outcomes.send(outcome(Err(ActionRejection::Busy))),
+        Some(ent) => {
+          commands.entity(ent).insert(BuildingMove {
+            user_origin: user_id,
+            position: *position,
+            ticket,
+          });
+        },
+        None => outcomes.send(outcome(Err(ActionRejection::UnknownEntity))),
+      },
+      GameAction::UpgradeBuilding { building } => match buildings.find(*building) {
+        Some(ent) if !request(ent) => outcomes.send(outcome(Err(ActionRejection::Busy))),
+        Some(ent) => {
+          commands.entity(ent).insert(BuildingUpgrade {
+            user_origin: user_id,
+            ticket,
+          });
+        },
+        None => outcomes.send(outcome(Err(ActionRejection::UnknownEntity))),
+      },
+      // Handled by process_trade_actions and process_market_actions.
+      GameAction::ProposeTrade { .. }
+      | GameAction::AcceptTrade { .. }
+      | GameAction::CancelTrade { .. }
+      | GameAction::PlaceOrder { .. }
+      | GameAction::CancelOrder { .. } => {},
+    }
+  });
+}
+
+/// Represents a request to move a building to another position.
+#[derive(Component)]
+pub struct BuildingMove {
+  pub user_origin: Uuid,
+  pub position: IVec2,
+  pub ticket: Option<ActionTicket>,
+}
+
+/// Moves buildings after re-validating their placement. Moved buildings keep
+/// their production progress and cooldown.
+fn move_buildings(
+  mut commands: Commands,
+  mut placement: BuildingPlacement,
+  mut outcomes: EventWriter<ActionOutcome>,
+  building_table: Res<BuildingDefinitionTable>,
+  mut query: Query<(Entity, &BuildingMove, &Building, &UserOwned, &mut Transform)>,
+) {
+  query.for_each_mut(|(e, building_move, building, owner, mut transform)| {
+    let result = if owner.0 != building_move.user_origin {
+      Err(ActionRejection::NotOwner)
+    } else {
+      building_table
+        .get(&building.0)
+        .ok_or(ActionRejection::UnknownBuilding)
+        .and_then(|building_def| {
+          placement
+            .check_move(e, building_def, building_move.position)
+            .map_err(ActionRejection::InvalidPlacement)?;
+
+          let position = building_move.position.as_vec2();
+          transform.translation.x = position.x;
+          transform.translation.y = position.y;
+          placement.occupy(e, building_def, building_move.position);
+          Ok(e)
+        })
+    };
+
+    outcomes.send(ActionOutcome {
+      user_id: building_move.user_origin,
+      ticket: building_move.ticket,
+      result,
     });
+    commands.entity(e).remove::<BuildingMove>();
+  });
+}
+
+fn log_action_outcomes(mut outcomes: EventReader<ActionOutcome>) {
+  outcomes.iter().for_each(|outcome| match outcome.result {
+    Ok(ent) => debug!("User {} performed an action on {:?}", outcome.user_id, ent),
+    Err(rejection) => warn!("Rejected action from user {}: {:?}", outcome.user_id, rejection),
+  });
 }
 
 pub struct GameActionPlugin;
 
 impl Plugin for GameActionPlugin {
   fn build(&self, app: &mut App) {
+    info!("Loading Game Actions...");
     app
       .add_event::<UserGameAction>()
-      .add_system_to_stage(GameStage::Start, process_game_actions);
+      .add_event::<ActionOutcome>()
+      .add_system_to_stage(GameStage::Start, process_game_actions)
+      .add_system_to_stage(GameStage::OnResourcesPaid, move_buildings)
+      .add_system_to_stage(GameStage::Cleanup, log_action_outcomes);
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use bevy::prelude::*;
+  use chrono::NaiveDateTime;
+  use hashbrown::HashMap;
+  use uuid::Uuid;
+
+  use super::{ActionOutcome, ActionRejection, ActionTicket, GameAction, GameActionPlugin, UserGameAction};
+  use crate::db::models::{User, World, WorldObj};
+  use crate::db::DatabaseManager;
+  use crate::game::building::{
+    BuildingCooldown, BuildingId, BuildingIndex, BuildingLevel, BuildingOccupancy, BuildingPlugin, PlacementError,
+    PrerequisiteError, UnderConstruction, Upgrading,
+  };
+  use crate::game::resources::{Resource, ResourcePlugin};
+  use crate::game::stages::StagePlugin;
+  use crate::game::tick::TickPlugin;
+  use crate::game::user::UserResourceTable;
+  use crate::game::world::WorldGenPlugin;
+  use crate::properties::GameProperties;
+
+  fn build_app(users: Vec<User>) -> App {
+    let mut app = App::new();
+    app
+      .add_plugins(MinimalPlugins)
+      .add_plugin(StagePlugin)
+      .insert_resource(DatabaseManager::test_harness())
+      .insert_resource::<World>(
+        WorldObj {
+          id: 0,
+          origin_time: NaiveDateTime::MIN,
+          seed: 1337,
+        }
+        .into(),
+      )
+      .add_plugin(WorldGenPlugin)
+      .add_plugin(TickPlugin)
+      .add_plugin(ResourcePlugin)
+      .add_plugin(BuildingPlugin)
+      .add_plugin(GameActionPlugin)
+      .init_resource::<GameProperties>()
+      .insert_resource(UserResourceTable::new(
+        users.into_iter().map(|user| (user.id, user)).collect::<HashMap<_, _>>(),
+      ));
+    app
+  }
+
+  fn send(app: &mut App, user_id: Uuid, action: GameAction) {
+    app.world.send_event(UserGameAction {
+      user_id,
+      action,
+      ticket: None,
+    });
+  }
+
+  fn outcomes(app: &App) -> Vec<&ActionOutcome> {
+    let events = app.world.resource::<Events<ActionOutcome>>();
+    events.iter_current_update_events().collect()
+  }
+
+  fn build(app: &mut App, owner: Uuid, building_id: &str, position: IVec2) {
+    send(
+      app,
+      owner,
+      GameAction::BuildBuilding {
+        building_id: building_id.to_string(),
+        position,
+      },
+    );
+    app.update();
+  }
+
+  fn building_id(app: &App, ent: Entity) -> BuildingId {
+    *app.world.get::<BuildingId>(ent).unwrap()
+  }
+
+  fn build_headquarters(app: &mut App, owner: Uuid) -> Entity {
+    build(app, owner, "Headquarters", IVec2::ZERO);
+    outcomes(app)[0].result.unwrap()
+  }
+
+  fn perform(app: &mut App, user_id: Uuid, building: BuildingId, action_id: &str) {
+    send(
+      app,
+      user_id,
+      GameAction::PerformBuildingAction {
+        building,
+        action_id: action_id.to_string(),
+      },
+    );
+    app.update();
+  }
+
+  #[test]
+  fn build_outcomes() {
+    let id = Uuid::new_v4();
+    let mut app = build_app(vec![User {
+      id,
+      ..Default::default()
+    }]);
+
+    let ent = build_headquarters(&mut app, id);
+    assert!(app.world.get_entity(ent).is_some());
+
+    build(&mut app, id, "Not a Building", IVec2::ZERO);
+    assert_eq!(outcomes(&app)[0].result, Err(ActionRejection::UnknownBuilding));
+  }
+
+  #[test]
+  fn build_validates_placement() {
+    let id = Uuid::new_v4();
+    let mut app = build_app(vec![User {
+      id,
+      ..Default::default()
+    }]);
+
+    // Headquarters may be built on minerals, such as the copper at (8, 32).
+    build(&mut app, id, "Headquarters", IVec2::new(7, 31));
+    assert!(outcomes(&app)[0].result.is_ok());
+
+    // Lake north of the origin.
+    build(&mut app, id, "Headquarters", IVec2::new(18, 2));
+    assert_eq!(
+      outcomes(&app)[0].result,
+      Err(ActionRejection::InvalidPlacement(PlacementError::Water))
+    );
+
+    // Mountains east of the origin.
+    build(&mut app, id, "Headquarters", IVec2::new(40, 0));
+    assert_eq!(
+      outcomes(&app)[0].result,
+      Err(ActionRejection::InvalidPlacement(PlacementError::Impassable))
+    );
+  }
+
+  #[test]
+  fn build_rejects_overlap() {
+    let id = Uuid::new_v4();
+    let mut app = build_app(vec![User {
+      id,
+      ..Default::default()
+    }]);
+
+    // Both actions are processed in the same tick.
+    let build_at = |position| GameAction::BuildBuilding {
+      building_id: "Headquarters".to_string(),
+      position,
+    };
+    send(&mut app, id, build_at(IVec2::ZERO));
+    send(&mut app, id, build_at(IVec2::ONE));
+    app.update();
+
+    let outcomes = outcomes(&app);
+    assert!(outcomes[0].result.is_ok());
+    assert_eq!(
+      outcomes[1].result,
+      Err(ActionRejection::InvalidPlacement(PlacementError::Occupied))
+    );
+  }
+
+  #[test]
+  fn build_pays_construction_costs() {
+    let id = Uuid::new_v4();
+    let mut app = build_app(vec![User::with_resources(id, [(Resource::Credit, 25)])]);
+
+    build(&mut app, id, "Copper Mine", IVec2::ZERO);
+    let ent = outcomes(&app)[0].result.unwrap();
+    assert!(app.world.entity(ent).contains::<UnderConstruction>());
+    assert_eq!(app.world.resource::<UserResourceTable>()[&id].get(Resource::Credit), 0);
+
+    build(&mut app, id, "Copper Mine", IVec2::X);
+    assert_eq!(outcomes(&app)[0].result, Err(ActionRejection::InsufficientResources));
+
+    build(&mut app, Uuid::new_v4(), "Copper Mine", IVec2::X);
+    assert_eq!(outcomes(&app)[0].result, Err(ActionRejection::UnknownUser));
+  }
+
+  #[test]
+  fn build_requires_prerequisites() {
+    let id = Uuid::new_v4();
+    let mut app = build_app(vec![User::with_resources(id, [(Resource::Credit, 100)])]);
+
+    build(&mut app, id, "Iron Mine", IVec2::new(3, 0));
+    assert_eq!(
+      outcomes(&app)[0].result,
+      Err(ActionRejection::Locked(PrerequisiteError::MissingBuilding))
+    );
+
+    let hq = build_headquarters(&mut app, id);
+    build(&mut app, id, "Iron Mine", IVec2::new(3, 0));
+    assert!(outcomes(&app)[0].result.is_ok());
+
+    build(&mut app, id, "Coal Mine", IVec2::new(4, 0));
+    assert_eq!(
+      outcomes(&app)[0].result,
+      Err(ActionRejection::Locked(PrerequisiteError::MissingResearch))
+    );
+
+    let building = building_id(&app, hq);
+    perform(&mut app, id, building, "research_prospecting");
+    assert_eq!(outcomes(&app)[0].result, Ok(hq));
+    assert!(app.world.resource::<UserResourceTable>()[&id]
+      .research
+      .contains("prospecting"));
+
+    build(&mut app, id, "Coal Mine", IVec2::new(4, 0));
+    assert!(outcomes(&app)[0].result.is_ok());
+  }
+
+  #[test]
+  fn demolish_building_outcomes() {
+    let id = Uuid::new_v4();
+    let other = Uuid::new_v4();
+    let mut app = build_app(vec![
+      User::with_resources(id, [(Resource::Credit, 25)]),
+      User {
+        id: other,
+        ..Default::default()
+      },
+    ]);
+
+    build(&mut app, id, "Copper Mine", IVec2::ZERO);
+    let ent = outcomes(&app)[0].result.unwrap();
+    let building = building_id(&app, ent);
+
+    let demolish = GameAction::DemolishBuilding { building };
+    send(&mut app, other, demolish);
+    app.update();
+    assert_eq!(outcomes(&app)[0].result, Err(ActionRejection::NotOwner));
+
+    // Refunds are paid in full, even without any storage left.
+    app
+      .world
+      .resource_mut::<UserResourceTable>()
+      .set_capacity(id, HashMap::from([(Resource::Credit, 0)]));
+    let demolish = GameAction::DemolishBuilding { building };
+    send(&mut app, id, demolish);
+    app.update();
+    assert_eq!(outcomes(&app)[0].result, Ok(ent));
+    assert!(app.world.get_entity(ent).is_none());
+    assert_eq!(app.world.resource::<BuildingOccupancy>().get(IVec2::ZERO), None);
+
+    assert_eq!(app.world.resource::<BuildingIndex>().entity(building), None);
+
+    // Half of the construction costs are refunded, rounded down.
+    assert_eq!(app.world.resource::<UserResourceTable>()[&id].get(Resource::Credit), 12);
+
+    // The id of the demolished building never reaches the building that
+    // replaces it, even if its entity is recycled.
+    app
+      .world
+      .resource_mut::<UserResourceTable>()
+      .get_mut(&id)
+      .unwrap()
+      .set(Resource::Credit, 25);
+    build(&mut app, id, "Copper Mine", IVec2::ZERO);
+    let replacement = outcomes(&app)[0].result.unwrap();
+
+    let demolish = GameAction::DemolishBuilding { building };
+    send(&mut app, id, demolish);
+    app.update();
+    assert_eq!(outcomes(&app)[0].result, Err(ActionRejection::UnknownEntity));
+    assert!(app.world.get_entity(replacement).is_some());
+  }
+
+  #[test]
+  fn move_building_outcomes() {
+    let id = Uuid::new_v4();
+    let other = Uuid::new_v4();
+    let mut app = build_app(vec![
+      User {
+        id,
+        ..Default::default()
+      },
+      User {
+        id: other,
+        ..Default::default()
+      },
+    ]);
+    let ent = build_headquarters(&mut app, id);
+
+    let building = building_id(&app, ent);
+    let move_to = |position| GameAction::MoveBuilding { building, position };
+
+    send(&mut app, other, move_to(IVec2::X));
+    app.update();
+    assert_eq!(outcomes(&app)[0].result, Err(ActionRejection::NotOwner));
+
+    // Lake north of the origin.
+    send(&mut app, id, move_to(IVec2::new(18, 2)));
+    app.update();
+    assert_eq!(
+      outcomes(&app)[0].result,
+      Err(ActionRejection::InvalidPlacement(PlacementError::Water))
+    );
+
+    // The building may overlap the tiles it currently covers.
+    send(&mut app, id, move_to(IVec2::X));
+    app.update();
+    assert_eq!(outcomes(&app)[0].result, Ok(ent));
+    assert_eq!(
+      app.world.entity(ent).get::<Transform>().unwrap().translation.truncate(),
+      Vec2::X
+    );
+
+    let occupancy = app.world.resource::<BuildingOccupancy>();
+    assert_eq!(occupancy.get(IVec2::ZERO), None);
+    assert_eq!(occupancy.get(IVec2::new(2, 1)), Some(ent));
+
+    // Only the first move of a tick is kept, but both get an outcome.
+    send(&mut app, id, move_to(IVec2::new(4, 0)));
+    send(&mut app, id, move_to(IVec2::new(6, 0)));
+    app.update();
+    let results = outcomes(&app).iter().map(|outcome| outcome.result).collect::<Vec<_>>();
+    assert_eq!(results, vec![Err(ActionRejection::Busy), Ok(ent)]);
+    assert_eq!(
+      app.world.entity(ent).get::<Transform>().unwrap().translation.truncate(),
+      Vec2::new(4.0, 0.0)
+    );
+  }
+
+  #[test]
+  fn one_building_request_per_tick() {
+    let id = Uuid::new_v4();
+    let mut app = build_app(vec![User {
+      id,
+      ..Default::default()
+    }]);
+    let ent = build_headquarters(&mut app, id);
+    let building = building_id(&app, ent);
+
+    // Only the first request is kept, even if the second is of another kind.
+    send(
+      &mut app,
+      id,
+      GameAction::MoveBuilding {
+        building,
+        position: IVec2::new(4, 0),
+      },
+    );
+    send(&mut app, id, GameAction::DemolishBuilding { building });
+    app.update();
+    let results = outcomes(&app).iter().map(|outcome| outcome.result).collect::<Vec<_>>();
+    assert_eq!(results, vec![Err(ActionRejection::Busy), Ok(ent)]);
+
+    assert!(app.world.get_entity(ent).is_some());
+    let occupancy = app.world.resource::<BuildingOccupancy>();
+    assert_eq!(occupancy.get(IVec2::ZERO), None);
+    assert_eq!(occupancy.get(IVec2::new(4, 0)), Some(ent));
+  }
+
+  #[test]
+  fn perform_action_outcomes() {
+    let id = Uuid::new_v4();
+    let other = Uuid::new_v4();
+    let mut app = build_app(vec![
+      User {
+        id,
+        ..Default::default()
+      },
+      User::with_resources(other, [(Resource::Credit, 10)]),
+    ]);
+    let ent = build_headquarters(&mut app, id);
+    let building = building_id(&app, ent);
+
+    perform(&mut app, id, BuildingId(Uuid::new_v4()), "increase_cash_flow");
+    assert_eq!(outcomes(&app)[0].result, Err(ActionRejection::UnknownEntity));
+
+    perform(&mut app, id, building, "not_an_action");
+    assert_eq!(outcomes(&app)[0].result, Err(ActionRejection::UnknownAction));
+
+    perform(&mut app, other, building, "increase_cash_flow");
+    assert_eq!(outcomes(&app)[0].result, Err(ActionRejection::NotOwner));
+
+    // Stop idle generation so the user stays broke.
+    app.world.entity_mut(ent).despawn_descendants();
+    app
+      .world
+      .resource_mut::<UserResourceTable>()
+      .get_mut(&id)
+      .unwrap()
+      .set(Resource::Credit, 0);
+    perform(&mut app, id, building, "increase_cash_flow");
+    assert_eq!(outcomes(&app)[0].result, Err(ActionRejection::InsufficientResources));
+
+    app
+      .world
+      .resource_mut::<UserResourceTable>()
+      .get_mut(&id)
+      .unwrap()
+      .set(Resource::Credit, 1);
+    perform(&mut app, id, building, "increase_cash_flow");
+    assert_eq!(outcomes(&app)[0].result, Ok(ent));
+
+    app.world.entity_mut(ent).insert(BuildingCooldown(5));
+    perform(&mut app, id, building, "increase_cash_flow");
+    assert_eq!(outcomes(&app)[0].result, Err(ActionRejection::OnCooldown));
+  }
+
+  #[test]
+  fn perform_action_once_per_tick() {
+    let id = Uuid::new_v4();
+    let mut app = build_app(vec![User::with_resources(id, [(Resource::Credit, 10)])]);
+    let ent = build_headquarters(&mut app, id);
+    let building = building_id(&app, ent);
+
+    (0..2).for_each(|ticket| {
+      app.world.send_event(UserGameAction {
+        user_id: id,
+        action: GameAction::PerformBuildingAction {
+          building,
+          action_id: "increase_cash_flow".to_string(),
+        },
+        ticket: Some(ActionTicket(ticket)),
+      });
+    });
+    app.update();
+
+    // Every action gets an outcome, even if it can't be queued.
+    let results = outcomes(&app)
+      .iter()
+      .map(|outcome| (outcome.ticket.unwrap().0, outcome.result))
+      .collect::<HashMap<_, _>>();
+    assert_eq!(results, HashMap::from([(0, Ok(ent)), (1, Err(ActionRejection::Busy))]));
+  }
+
+  #[test]
+  fn upgrade_building_outcomes() {
+    let id = Uuid::new_v4();
+    let other = Uuid::new_v4();
+    let mut app = build_app(vec![
+      User {
+        id,
+        ..Default::default()
+      },
+      User {
+        id: other,
+        ..Default::default()
+      },
+    ]);
+    let ent = build_headquarters(&mut app, id);
+
+    let building = building_id(&app, ent);
+    let upgrade = || GameAction::UpgradeBuilding { building };
+    let set_credits = |app: &mut App, value| {
+      app
+        .world
+        .resource_mut::<UserResourceTable>()
+        .get_mut(&id)
+        .unwrap()
+        .set(Resource::Credit, value)
+    };
+    let credits = |app: &App| app.world.resource::<UserResourceTable>()[&id].get(Resource::Credit);
+
+    send(&mut app, other, upgrade());
+    app.update();
+    assert_eq!(outcomes(&app)[0].result, Err(ActionRejection::NotOwner));
+
+    set_credits(&mut app, 10);
+    send(&mut app, id, upgrade());
+    app.update();
+    assert_eq!(outcomes(&app)[0].result, Err(ActionRejection::InsufficientResources));
+
+    set_credits(&mut app, 50);
+    send(&mut app, id, upgrade());
+    app.update();
+    assert_eq!(outcomes(&app)[0].result, Ok(ent));
+    assert!(credits(&app) < 50);
+    assert!(app.world.entity(ent).contains::<Upgrading>());
+
+    send(&mut app, id, upgrade());
+    app.update();
+    assert_eq!(outcomes(&app)[0].result, Err(ActionRejection::UnderConstruction));
+
+    // The building keeps its level and production until the upgrade is built,
+    // and still unlocks the buildings that depend on it.
+    assert_eq!(app.world.entity(ent).get::<BuildingLevel>(), Some(&BuildingLevel(1)));
+    let before = credits(&app);
+    app.update();
+    assert_eq!(credits(&app) - before, 1);
+
+    set_credits(&mut app, 100);
+    build(&mut app, id, "Iron Mine", IVec2::new(3, 0));
+    assert!(outcomes(&app)[0].result.is_ok());
+
+    for _ in 0..7 {
+      app.update();
+    }
+    assert!(!app.world.entity(ent).contains::<Upgrading>());
+    assert_eq!(app.world.entity(ent).get::<BuildingLevel>(), Some(&BuildingLevel(2)));
+
+    // Level 2 produces 3 credits per tick.
+    let before = credits(&app);
+    app.update();
+    assert_eq!(credits(&app) - before, 3);
+
+    app.world.entity_mut(ent).insert(BuildingLevel(3));
+    send(&mut app, id, upgrade());
+    app.update();
+    assert_eq!(outcomes(&app)[0].result, Err(ActionRejection::MaxLevel));
   }
 }
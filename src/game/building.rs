@@ -1,42 +1,26 @@
 use std::fs;
 use std::ops::Deref;
 
+use bevy::ecs::system::SystemParam;
 use bevy::prelude::*;
+use bevy::reflect::TypeUuid;
 use glob::glob;
-use hashbrown::HashMap;
+use hashbrown::{HashMap, HashSet};
+use itertools::Itertools;
 use serde::Deserialize;
 use uuid::Uuid;
 
-use super::resources::{ResourceDelta, TickedResourceCost};
+use super::action::{ActionOutcome, ActionRejection, ActionResult, ActionTicket};
+use super::definitions::{parse_definition_file, BuildingDefinitionError, BuildingDefinitionProblem};
+use super::mining::{BuildingMiner, BuildingMiningDefinition};
+use super::resources::{CostPriority, ResourceDelta, ResourceWasted, TickedResourceCost};
 use super::tick::Ticked;
 use super::user::{UserOwned, UserResourceTable};
+use super::world::{StaticTerrainTile, Terrain, TerrainTile};
 use crate::game::stages::GameStage;
+use crate::properties::GameProperties;
 
-lazy_static::lazy_static! {
-  /// All building definitions present in the game.
-  pub static ref BUILDING_TABLE: HashMap<String, BuildingDefinition> = load_building_definitions();
-}
-
-/// Loads all building files into a single list
-fn load_building_definitions() -> HashMap<String, BuildingDefinition> {
-  glob("buildings/*.toml")
-    .unwrap()
-    .filter_map(|x| x.ok())
-    .filter_map(|file| fs::read_to_string(file).ok())
-    .filter_map(|contents| toml::from_str::<BuildingDefinitionFile>(&contents).ok())
-    .map(|files| {
-      files
-        .buildings
-        .iter()
-        .for_each(|building| debug!("Registered Building {}", building.name));
-      files.buildings
-    })
-    .flatten()
-    .map(|x| (x.name.clone(), x))
-    .collect()
-}
-
-#[derive(Deserialize, Clone)]
+#[derive(Deserialize, Clone, PartialEq)]
 pub struct BuildingAction {
   id: String,
   #[allow(dead_code)]
@@ -44,53 +28,329 @@ pub struct BuildingAction {
   cooldown: u32,
   products: Option<Vec<ResourceDelta>>,
   costs: Option<Vec<ResourceDelta>>,
+  /// Research completed by the owner once the action is performed.
+  research: Option<String>,
 }
 
-#[derive(Deserialize, Clone)]
+#[derive(Deserialize, Clone, PartialEq)]
 pub struct BuildingPlacementFlags {
   pub on_water: bool,
   pub on_mineral: bool,
 }
 
-#[derive(Deserialize, Clone)]
+/// Reasons a building cannot be placed on a tile.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum PlacementError {
+  /// Nothing can be built on impassable terrain.
+  Impassable,
+  /// The building cannot be placed on water.
+  Water,
+  /// The building cannot be placed on mineral deposits.
+  Mineral,
+  /// Another building already occupies the tile.
+  Occupied,
+}
+
+impl BuildingPlacementFlags {
+  /// Checks whether a building with these flags may be placed on the tile.
+  pub fn check(&self, tile: &TerrainTile) -> Result<(), PlacementError> {
+    match tile {
+      TerrainTile::Static(StaticTerrainTile::Impassable) => Err(PlacementError::Impassable),
+      TerrainTile::Static(StaticTerrainTile::Water) if !self.on_water => Err(PlacementError::Water),
+      TerrainTile::Complex(_) if !self.on_mineral => Err(PlacementError::Mineral),
+      _ => Ok(()),
+    }
+  }
+}
+
+/// Something a user must have before being allowed to build a building.
+#[derive(Deserialize, Clone, Debug, PartialEq)]
+#[serde(untagged)]
+pub enum BuildingPrerequisite {
+  /// Owning a finished building of the given name, at least at the level.
+  Building {
+    building: String,
+    #[serde(default = "BuildingPrerequisite::default_level")]
+    level: u32,
+  },
+  /// Having completed the research.
+  Research { research: String },
+}
+
+impl BuildingPrerequisite {
+  fn default_level() -> u32 {
+    1
+  }
+}
+
+/// Reasons a building is locked for a user.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum PrerequisiteError {
+  /// The user owns no finished building of a required kind.
+  MissingBuilding,
+  /// The user's required building has not reached the required level.
+  BuildingLevelTooLow,
+  /// The user has not completed a required research.
+  MissingResearch,
+}
+
+#[derive(Deserialize, Clone, PartialEq)]
 pub struct BuildingTickedAction {
-  every_n_ticks: u32,
-  products: Option<Vec<ResourceDelta>>,
-  costs: Option<Vec<ResourceDelta>>,
+  pub every_n_ticks: u32,
+  pub products: Option<Vec<ResourceDelta>>,
+  pub costs: Option<Vec<ResourceDelta>>,
+  pub mining: Option<BuildingMiningDefinition>,
 }
 
-#[derive(Deserialize, Clone, Component)]
+/// An upgrade of a building, replacing its production lines and actions when
+/// they are defined.
+#[derive(Deserialize, Clone, PartialEq)]
+pub struct BuildingLevelDefinition {
+  /// Resources paid to upgrade the building to this level.
+  pub costs: Option<Vec<ResourceDelta>>,
+  /// Number of ticks the building stays [Upgrading] before reaching this level.
+  #[serde(default)]
+  pub build_ticks: u32,
+  pub actions: Option<Vec<BuildingAction>>,
+  pub ticked: Option<Vec<BuildingTickedAction>>,
+  /// Storage capacity added for each resource.
+  pub storage: Option<Vec<ResourceDelta>>,
+}
+
+#[derive(Deserialize, Clone, PartialEq, Component)]
 pub struct BuildingDefinition {
   pub name: String,
   pub size: [i32; 2],
+  /// Order in which the ticked costs of the building are paid when its owner
+  /// runs short, lowest first, then by [BuildingSequence]. See
+  /// [CostPriority].
   pub priority: u32,
+  /// Resources paid when the building is built.
+  pub costs: Option<Vec<ResourceDelta>>,
+  /// Number of ticks the building stays [UnderConstruction] once built.
+  #[serde(default)]
+  pub build_ticks: u32,
   pub placement: BuildingPlacementFlags,
+  /// Everything a user must have before building it.
+  pub requires: Option<Vec<BuildingPrerequisite>>,
   pub actions: Option<Vec<BuildingAction>>,
   pub ticked: Option<Vec<BuildingTickedAction>>,
+  /// Storage capacity added for each resource.
+  pub storage: Option<Vec<ResourceDelta>>,
+  /// Levels the building can be upgraded to, in order, starting at level 2.
+  pub upgrades: Option<Vec<BuildingLevelDefinition>>,
+}
+
+/// Returns every tile covered by a building of the given size and position.
+pub fn building_tiles(position: IVec2, size: IVec2) -> impl Iterator<Item = IVec2> {
+  (0..size.x)
+    .cartesian_product(0..size.y)
+    .map(move |(x, y)| position + IVec2::new(x, y))
 }
 
 impl BuildingDefinition {
-  pub fn spawn(&self, commands: &mut Commands, owner: Uuid, position: IVec2) -> Entity {
-    let ent = commands
+  /// Returns every tile covered by the building when placed at the position.
+  pub fn tiles(&self, position: IVec2) -> impl Iterator<Item = IVec2> {
+    building_tiles(position, IVec2::from(self.size))
+  }
+
+  /// Verifies that every tile under the building allows it to be placed.
+  pub fn validate_placement(&self, terrain: &mut Terrain, position: IVec2) -> Result<(), PlacementError> {
+    self
+      .tiles(position)
+      .try_for_each(|tile| self.placement.check(&terrain.get_tile([tile.x as i64, tile.y as i64])))
+  }
+
+  /// Verifies that a user meets every prerequisite of the building, given
+  /// their completed research and the highest level of each finished building
+  /// they own.
+  pub fn check_prerequisites(
+    &self,
+    research: &HashSet<String>,
+    owned: &HashMap<&str, u32>,
+  ) -> Result<(), PrerequisiteError> {
+    self
+      .requires
+      .iter()
+      .flatten()
+      .try_for_each(|prerequisite| match prerequisite {
+        BuildingPrerequisite::Building { building, level } => match owned.get(building.as_str()) {
+          None => Err(PrerequisiteError::MissingBuilding),
+          Some(owned_level) if owned_level < level => Err(PrerequisiteError::BuildingLevelTooLow),
+          Some(_) => Ok(()),
+        },
+        BuildingPrerequisite::Research { research: required } if !research.contains(required) => {
+          Err(PrerequisiteError::MissingResearch)
+        },
+        BuildingPrerequisite::Research { .. } => Ok(()),
+      })
+  }
+
+  /// Returns the upgrade to the given level, if any. Level 1 is the building
+  /// itself.
+  pub fn upgrade(&self, level: u32) -> Option<&BuildingLevelDefinition> {
+    let index = level.checked_sub(2)?;
+    self.upgrades.as_ref()?.get(index as usize)
+  }
+
+  /// Production lines at the given level, as redefined by the highest upgrade
+  /// up to that level.
+  pub fn ticked_at(&self, level: u32) -> Option<&Vec<BuildingTickedAction>> {
+    (2..=level)
+      .rev()
+      .find_map(|level| self.upgrade(level)?.ticked.as_ref())
+      .or(self.ticked.as_ref())
+  }
+
+  /// Storage capacity at the given level, as redefined by the highest upgrade
+  /// up to that level.
+  pub fn storage_at(&self, level: u32) -> Option<&Vec<ResourceDelta>> {
+    (2..=level)
+      .rev()
+      .find_map(|level| self.upgrade(level)?.storage.as_ref())
+      .or(self.storage.as_ref())
+  }
+
+  /// Actions at the given level, as redefined by the highest upgrade up to
+  /// that level.
+  pub fn actions_at(&self, level: u32) -> Option<&Vec<BuildingAction>> {
+    (2..=level)
+      .rev()
+      .find_map(|level| self.upgrade(level)?.actions.as_ref())
+      .or(self.actions.as_ref())
+  }
+
+  /// Longest cooldown of the actions at the given level.
+  pub fn longest_cooldown_at(&self, level: u32) -> u32 {
+    self
+      .actions_at(level)
+      .into_iter()
+      .flatten()
+      .map(|action| action.cooldown)
+      .max()
+      .unwrap_or_default()
+  }
+
+  /// Number of ticks it takes to build the given level.
+  pub fn build_ticks_at(&self, level: u32) -> u32 {
+    self
+      .upgrade(level)
+      .map_or(self.build_ticks, |upgrade| upgrade.build_ticks)
+  }
+
+  /// Resources refunded when the building is demolished, the given fraction
+  /// of its construction and upgrade costs rounded down.
+  pub fn refund(&self, level: u32, fraction: f32) -> Vec<ResourceDelta> {
+    let upgrade_costs = (2..=level).filter_map(|level| self.upgrade(level)?.costs.as_ref());
+
+    self
+      .costs
+      .iter()
+      .chain(upgrade_costs)
+      .flatten()
+      .map(|cost| (cost.resource, cost.value))
+      .into_grouping_map()
+      .sum()
+      .into_iter()
+      .map(|(resource, value)| resource.d((value as f64 * fraction as f64).floor() as i64))
+      .filter(|refund| refund.value > 0)
+      .collect()
+  }
+
+  pub fn spawn(&self, commands: &mut Commands, sequence: BuildingSequence, owner: Uuid, position: IVec2) -> Entity {
+    let ent = self.spawn_site(commands, Uuid::new_v4(), sequence, owner, position, 1);
+    self.spawn_production_lines(commands, ent, sequence, owner, 1, &[]);
+    ent
+  }
+
+  /// Spawns a newly built building, which stays [UnderConstruction] for
+  /// `build_ticks` before it starts producing.
+  pub fn construct(&self, commands: &mut Commands, sequence: BuildingSequence, owner: Uuid, position: IVec2) -> Entity {
+    if self.build_ticks == 0 {
+      return self.spawn(commands, sequence, owner, position);
+    }
+
+    let ent = self.spawn_site(commands, Uuid::new_v4(), sequence, owner, position, 1);
+    self.start_construction(commands, ent, 1, 0);
+    ent
+  }
+
+  /// Puts the building [UnderConstruction] until the given level is built,
+  /// resuming from the given construction progress.
+  pub fn start_construction(&self, commands: &mut Commands, ent: Entity, level: u32, progress: u32) {
+    let mut construction = Ticked::new(self.build_ticks_at(level));
+    construction.set_counter(progress);
+    commands.entity(ent).insert((UnderConstruction, construction));
+  }
+
+  /// Starts upgrading the building from the given level to the next one,
+  /// resuming from the given upgrade progress. The building keeps its current
+  /// level until the upgrade completes.
+  pub fn start_upgrade(&self, commands: &mut Commands, ent: Entity, level: u32, progress: u32) {
+    let mut upgrade = Ticked::new(self.build_ticks_at(level + 1));
+    upgrade.set_counter(progress);
+    commands.entity(ent).insert((Upgrading, upgrade));
+  }
+
+  /// Spawns the building itself at the given level, without any production
+  /// lines.
+  pub fn spawn_site(
+    &self,
+    commands: &mut Commands,
+    id: Uuid,
+    sequence: BuildingSequence,
+    owner: Uuid,
+    position: IVec2,
+    level: u32,
+  ) -> Entity {
+    commands
       .spawn((
         Building(self.name.clone()),
+        BuildingId(id),
+        sequence,
+        BuildingLevel(level),
         UserOwned(owner),
         Transform::from_xyz(position.x as f32, position.y as f32, 0.0)
           .with_scale(Vec2::new(self.size[0] as f32, self.size[1] as f32).extend(0.0)),
       ))
-      .id();
-
-    if let Some(ticked) = &self.ticked {
-      ticked.iter().for_each(|x| {
-        commands
-          .entity(ent)
-          .insert(Ticked::new(x.every_n_ticks))
-          .insert(TickedResourceCost::new(x.costs.clone().unwrap_or_default()))
-          .insert(BuildingTickedResourceProduct(x.products.clone().unwrap_or_default()));
-      });
-    }
+      .id()
+  }
 
-    ent
+  /// Spawns a [ProductionLine] child on the building for every ticked action
+  /// at its level, resuming each line from the tick counter at its index in
+  /// `counters`.
+  pub fn spawn_production_lines(
+    &self,
+    commands: &mut Commands,
+    ent: Entity,
+    sequence: BuildingSequence,
+    owner: Uuid,
+    level: u32,
+    counters: &[u32],
+  ) {
+    let Some(ticked) = self.ticked_at(level) else {
+      return;
+    };
+
+    commands.entity(ent).with_children(|parent| {
+      ticked.iter().enumerate().for_each(|(index, x)| {
+        let mut timer = Ticked::new(x.every_n_ticks);
+        timer.set_counter(counters.get(index).copied().unwrap_or_default());
+
+        let mut line = parent.spawn((
+          ProductionLine(index),
+          UserOwned(owner),
+          timer,
+          TickedResourceCost::new(x.costs.clone().unwrap_or_default()),
+          CostPriority::new(self.priority, sequence.0),
+          BuildingTickedResourceProduct(x.products.clone().unwrap_or_default()),
+        ));
+
+        if let Some(mining) = &x.mining {
+          line.insert(BuildingMiner(mining.clone()));
+        }
+      });
+    });
   }
 }
 
@@ -99,14 +359,98 @@ impl BuildingDefinition {
 #[derive(Component)]
 pub struct Building(pub String);
 
-#[derive(Deserialize)]
+/// Current level of a building, starting at 1 and increased by upgrades.
+#[derive(Component, Debug, Clone, Copy, PartialEq, Eq)]
+pub struct BuildingLevel(pub u32);
+
+/// Persistent identifier of a building, stable across restarts unlike its
+/// [Entity].
+#[derive(Component, Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub struct BuildingId(pub Uuid);
+
+/// Order in which a building was placed, persisted so that older buildings
+/// keep paying their ticked costs first across upgrades, reloads and restarts.
+#[derive(Component, Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+pub struct BuildingSequence(pub u64);
+
+/// The [BuildingSequence] of the next building placed.
+#[derive(Resource, Debug, Default)]
+pub struct NextBuildingSequence(pub u64);
+
+impl NextBuildingSequence {
+  pub fn take(&mut self) -> BuildingSequence {
+    self.0 += 1;
+    BuildingSequence(self.0 - 1)
+  }
+}
+
+#[derive(Deserialize, TypeUuid)]
+#[uuid = "5a0e5f4c-3d0b-4c36-a0f6-9b8d7c1e2f41"]
 pub struct BuildingDefinitionFile {
   pub buildings: Vec<BuildingDefinition>,
 }
 
-#[derive(Resource)]
+/// All building definitions present in the game, by name.
+#[derive(Resource, Default)]
 pub struct BuildingDefinitionTable(HashMap<String, BuildingDefinition>);
 
+impl BuildingDefinitionTable {
+  /// Builds the table from the definitions of every given file.
+  pub fn from_files<'a>(files: impl Iterator<Item = &'a BuildingDefinitionFile>) -> Self {
+    Self(
+      files
+        .flat_map(|file| file.buildings.iter().cloned())
+        .map(|x| (x.name.clone(), x))
+        .collect(),
+    )
+  }
+
+  /// Loads and validates all building files into a single table, returning
+  /// every problem found if any file is invalid.
+  pub fn try_load() -> Result<Self, Vec<BuildingDefinitionError>> {
+    let mut names = HashSet::new();
+    let mut files = Vec::new();
+    let mut errors = Vec::new();
+
+    glob("buildings/*.toml")
+      .unwrap()
+      .filter_map(|x| x.ok())
+      .for_each(|path| {
+        let parsed = fs::read_to_string(&path)
+          .map_err(|err| {
+            vec![BuildingDefinitionError {
+              path: path.clone(),
+              line: None,
+              problem: BuildingDefinitionProblem::Io(err),
+            }]
+          })
+          .and_then(|contents| parse_definition_file(&path, &contents, &mut names));
+
+        match parsed {
+          Ok(file) => files.push(file),
+          Err(file_errors) => errors.extend(file_errors),
+        }
+      });
+
+    if !errors.is_empty() {
+      return Err(errors);
+    }
+
+    let table = Self::from_files(files.iter());
+    table.keys().for_each(|name| debug!("Registered Building {}", name));
+    Ok(table)
+  }
+
+  /// Loads all building files into a single table, panicking if any file is
+  /// invalid.
+  pub fn load() -> Self {
+    Self::try_load().unwrap_or_else(|errors| {
+      errors.iter().for_each(|err| error!("{}", err));
+      panic!("Found {} problems in the building definitions.", errors.len());
+    })
+  }
+}
+
 impl Deref for BuildingDefinitionTable {
   type Target = HashMap<String, BuildingDefinition>;
 
@@ -115,19 +459,262 @@ impl Deref for BuildingDefinitionTable {
   }
 }
 
-/// Represents the products a building produces if the costs are paid.
+/// Index of the world tiles occupied by [Building] entities, built from their
+/// [Transform]s, where the translation is the position and the scale is the
+/// size of the building.
+#[derive(Default, Resource)]
+pub struct BuildingOccupancy {
+  tiles: HashMap<IVec2, Entity>,
+  buildings: HashMap<Entity, Vec<IVec2>>,
+}
+
+impl BuildingOccupancy {
+  /// Returns the building occupying the tile, if any.
+  pub fn get(&self, tile: IVec2) -> Option<Entity> {
+    self.tiles.get(&tile).copied()
+  }
+
+  /// Verifies that none of the tiles are occupied.
+  pub fn check(&self, mut tiles: impl Iterator<Item = IVec2>) -> Result<(), PlacementError> {
+    if tiles.any(|tile| self.tiles.contains_key(&tile)) {
+      Err(PlacementError::Occupied)
+    } else {
+      Ok(())
+    }
+  }
+
+  /// Verifies that none of the tiles are occupied by a building other than
+  /// the given one, such as when moving it.
+  pub fn check_for(&self, ent: Entity, mut tiles: impl Iterator<Item = IVec2>) -> Result<(), PlacementError> {
+    if tiles.any(|tile| self.get(tile).is_some_and(|occupant| occupant != ent)) {
+      Err(PlacementError::Occupied)
+    } else {
+      Ok(())
+    }
+  }
+
+  /// Marks the tiles covered by the building as occupied, replacing any tiles
+  /// it previously occupied.
+  pub fn insert(&mut self, ent: Entity, position: IVec2, size: IVec2) {
+    self.remove(ent);
+
+    let tiles = building_tiles(position, size).collect::<Vec<_>>();
+    tiles.iter().for_each(|tile| {
+      self.tiles.insert(*tile, ent);
+    });
+    self.buildings.insert(ent, tiles);
+  }
+
+  /// Frees all tiles occupied by the building.
+  pub fn remove(&mut self, ent: Entity) {
+    if let Some(tiles) = self.buildings.remove(&ent) {
+      tiles.iter().for_each(|tile| {
+        self.tiles.remove(tile);
+      });
+    }
+  }
+
+  pub fn insert_transform(&mut self, ent: Entity, transform: &Transform) {
+    self.insert(
+      ent,
+      transform.translation.truncate().as_ivec2(),
+      transform.scale.truncate().as_ivec2(),
+    );
+  }
+}
+
+/// System parameter to validate building placement against both the terrain
+/// and other buildings.
+#[derive(SystemParam)]
+pub struct BuildingPlacement<'w, 's> {
+  terrain: Terrain<'w, 's>,
+  occupancy: ResMut<'w, BuildingOccupancy>,
+  sequence: ResMut<'w, NextBuildingSequence>,
+}
+
+impl<'w, 's> BuildingPlacement<'w, 's> {
+  /// Verifies that the building may be placed at the position.
+  pub fn check(&mut self, building_def: &BuildingDefinition, position: IVec2) -> Result<(), PlacementError> {
+    building_def
+      .validate_placement(&mut self.terrain, position)
+      .and_then(|_| self.occupancy.check(building_def.tiles(position)))
+  }
+
+  /// Verifies that an existing building may be moved to the position.
+  pub fn check_move(
+    &mut self,
+    ent: Entity,
+    building_def: &BuildingDefinition,
+    position: IVec2,
+  ) -> Result<(), PlacementError> {
+    building_def
+      .validate_placement(&mut self.terrain, position)
+      .and_then(|_| self.occupancy.check_for(ent, building_def.tiles(position)))
+  }
+
+  /// Occupies the tiles of a building right away, so that buildings placed
+  /// later in the same tick can't overlap it.
+  pub fn occupy(&mut self, ent: Entity, building_def: &BuildingDefinition, position: IVec2) {
+    self.occupancy.insert(ent, position, IVec2::from(building_def.size));
+  }
+
+  /// Takes the [BuildingSequence] of a building about to be placed.
+  pub fn next_sequence(&mut self) -> BuildingSequence {
+    self.sequence.take()
+  }
+}
+
+/// Entity of every building by its [BuildingId].
+#[derive(Resource, Default)]
+pub struct BuildingIndex {
+  entities: HashMap<BuildingId, Entity>,
+  ids: HashMap<Entity, BuildingId>,
+}
+
+impl BuildingIndex {
+  pub fn entity(&self, id: BuildingId) -> Option<Entity> {
+    self.entities.get(&id).copied()
+  }
+
+  /// Id of the building entity, kept until the end of the frame in which the
+  /// building is despawned.
+  pub fn id(&self, ent: Entity) -> Option<BuildingId> {
+    self.ids.get(&ent).copied()
+  }
+
+  fn insert(&mut self, id: BuildingId, ent: Entity) {
+    self.entities.insert(id, ent);
+    self.ids.insert(ent, id);
+  }
+
+  fn remove(&mut self, ent: Entity) {
+    if let Some(id) = self.ids.remove(&ent) {
+      self.entities.remove(&id);
+    }
+  }
+}
+
+fn index_building_ids(
+  mut index: ResMut<BuildingIndex>,
+  spawned: Query<(Entity, &BuildingId), Added<BuildingId>>,
+  removed: RemovedComponents<BuildingId>,
+) {
+  removed.iter().for_each(|ent| index.remove(ent));
+  spawned.for_each(|(ent, id)| index.insert(*id, ent));
+}
+
+type BuildingLookupState = (
+  &'static Building,
+  &'static BuildingLevel,
+  &'static UserOwned,
+  Option<&'static UnderConstruction>,
+);
+
+/// System parameter to find buildings by their [BuildingId].
+#[derive(SystemParam)]
+pub struct BuildingLookup<'w, 's> {
+  index: Res<'w, BuildingIndex>,
+  buildings: Query<'w, 's, BuildingLookupState>,
+}
+
+impl<'w, 's> BuildingLookup<'w, 's> {
+  /// Entity of the building, if it still exists.
+  pub fn find(&self, id: BuildingId) -> Option<Entity> {
+    self.index.entity(id).filter(|ent| self.buildings.contains(*ent))
+  }
+
+  /// Highest level of every finished building owned by the user, by name.
+  pub fn owned_levels(&self, user_id: Uuid) -> HashMap<&str, u32> {
+    let mut owned = HashMap::new();
+    self
+      .buildings
+      .iter()
+      .filter(|(_, _, owner, under_construction)| owner.0 == user_id && under_construction.is_none())
+      .for_each(|(building, level, ..)| {
+        let owned_level = owned.entry(building.0.as_str()).or_insert(level.0);
+        *owned_level = level.0.max(*owned_level);
+      });
+    owned
+  }
+}
+
+/// Buildings that were spawned or moved.
+type MovedBuildingFilter = (With<Building>, Changed<Transform>);
+
+fn index_building_occupancy(
+  mut occupancy: ResMut<BuildingOccupancy>,
+  changed: Query<(Entity, &Transform), MovedBuildingFilter>,
+  removed: RemovedComponents<Building>,
+) {
+  removed.iter().for_each(|ent| occupancy.remove(ent));
+  changed.for_each(|(ent, transform)| occupancy.insert_transform(ent, transform));
+}
+
+/// A ticked production line of a building, spawned as a child of the building
+/// for every `[[buildings.ticked]]` entry of its definition. Each line ticks,
+/// pays its costs and produces independently of the others.
+#[derive(Component, Debug, Clone, Copy, PartialEq, Eq)]
+pub struct ProductionLine(pub usize);
+
+/// Represents a building that is still being built. Its [Ticked] fires once
+/// construction completes, after which the building gets its production lines
+/// and accepts actions.
+#[derive(Component, Debug)]
+pub struct UnderConstruction;
+
+/// Represents a building being upgraded to its next level. The building keeps
+/// its current level, production lines and actions until its [Ticked] fires.
+#[derive(Component, Debug)]
+pub struct Upgrading;
+
+type ConstructionState<'a> = (
+  Entity,
+  &'a Building,
+  &'a BuildingSequence,
+  &'a BuildingLevel,
+  &'a UserOwned,
+  &'a Ticked,
+);
+
+fn complete_construction(
+  mut commands: Commands,
+  building_table: Res<BuildingDefinitionTable>,
+  query: Query<ConstructionState, With<UnderConstruction>>,
+) {
+  query.for_each(|(ent, building, sequence, level, owner, ticked)| {
+    if ticked.fire_count() == 0 {
+      return;
+    }
+
+    commands.entity(ent).remove::<(UnderConstruction, Ticked)>();
+    match building_table.get(&building.0) {
+      Some(building_def) => building_def.spawn_production_lines(&mut commands, ent, *sequence, owner.0, level.0, &[]),
+      None => warn!("Completed building {:?} with unknown definition {}", ent, building.0),
+    }
+  });
+}
+
+/// Represents the products a production line produces if the costs are paid.
 #[derive(Component)]
 pub struct BuildingTickedResourceProduct(pub Vec<ResourceDelta>);
 
-fn on_tick_building_ticked_resources(
+pub fn on_tick_building_ticked_resources(
   mut user_table: ResMut<UserResourceTable>,
-  ticked_owned_building: Query<(&TickedResourceCost, &BuildingTickedResourceProduct, &UserOwned), With<Building>>,
+  mut wasted: EventWriter<ResourceWasted>,
+  ticked_owned_building: Query<(&TickedResourceCost, &BuildingTickedResourceProduct, &UserOwned), With<ProductionLine>>,
 ) {
   ticked_owned_building.for_each(|(cost, product, user_owned)| {
     if cost.paid() {
-      if let Some(user) = user_table.get_mut(&user_owned.0) {
-        product.0.iter().for_each(|delta| user.give_resources(delta));
-      }
+      product.0.iter().for_each(|delta| {
+        let amount = user_table.give_resources(&user_owned.0, delta);
+        if amount > 0 {
+          wasted.send(ResourceWasted {
+            user_id: user_owned.0,
+            resource: delta.resource,
+            amount,
+          });
+        }
+      });
     }
   });
 }
@@ -155,40 +742,296 @@ fn tick_down_building_cooldowns(
 pub struct BuildingPerformAction {
   pub user_origin: Uuid,
   pub id: String,
+  pub ticket: Option<ActionTicket>,
+}
+
+impl BuildingPerformAction {
+  fn outcome(&self, result: ActionResult) -> ActionOutcome {
+    ActionOutcome {
+      user_id: self.user_origin,
+      ticket: self.ticket,
+      result,
+    }
+  }
+}
+
+type PendingActionState<'a> = (Entity, &'a BuildingPerformAction);
+
+fn dismiss_actions_when_under_construction(
+  mut commands: Commands,
+  mut outcomes: EventWriter<ActionOutcome>,
+  query: Query<PendingActionState, (With<Building>, With<UnderConstruction>)>,
+) {
+  query.for_each(|(e, action_command)| {
+    outcomes.send(action_command.outcome(Err(ActionRejection::UnderConstruction)));
+    commands.entity(e).remove::<BuildingPerformAction>();
+  });
 }
 
 fn dismiss_actions_when_on_cooldown(
   mut commands: Commands,
-  query: Query<Entity, (With<Building>, With<BuildingPerformAction>, With<BuildingCooldown>)>,
+  mut outcomes: EventWriter<ActionOutcome>,
+  query: Query<PendingActionState, (With<Building>, With<BuildingCooldown>)>,
 ) {
-  query.for_each(|e| {
+  query.for_each(|(e, action_command)| {
+    outcomes.send(action_command.outcome(Err(ActionRejection::OnCooldown)));
     commands.entity(e).remove::<BuildingPerformAction>();
   });
 }
 
-fn process_actions(
+/// Pays for and completes the requested action, if allowed.
+fn perform_action<'a>(
+  building_table: &'a BuildingDefinitionTable,
+  user_table: &mut UserResourceTable,
+  wasted: &mut EventWriter<ResourceWasted>,
+  action_command: &BuildingPerformAction,
+  building: &Building,
+  level: &BuildingLevel,
+  owner: &UserOwned,
+) -> Result<&'a BuildingAction, ActionRejection> {
+  let action = building_table
+    .get(&building.0)
+    .ok_or(ActionRejection::UnknownBuilding)?
+    .actions_at(level.0)
+    .into_iter()
+    .flatten()
+    .find(|x| x.id == action_command.id)
+    .ok_or(ActionRejection::UnknownAction)?;
+
+  if owner.0 != action_command.user_origin {
+    return Err(ActionRejection::NotOwner);
+  }
+
+  let user = user_table.get_mut(&owner.0).ok_or(ActionRejection::UnknownUser)?;
+  if !user.pay_resource_transaction(action.costs.clone().unwrap_or_default()) {
+    return Err(ActionRejection::InsufficientResources);
+  }
+
+  if let Some(research) = &action.research {
+    user.research.insert(research.clone());
+  }
+  action.products.iter().flatten().for_each(|x| {
+    let amount = user_table.give_resources(&owner.0, x);
+    if amount > 0 {
+      wasted.send(ResourceWasted {
+        user_id: owner.0,
+        resource: x.resource,
+        amount,
+      });
+    }
+  });
+
+  Ok(action)
+}
+
+type ActionState<'a> = (
+  Entity,
+  &'a BuildingPerformAction,
+  &'a Building,
+  &'a BuildingLevel,
+  &'a UserOwned,
+);
+
+/// Buildings that can perform actions.
+type IdleBuildingFilter = (Without<BuildingCooldown>, Without<UnderConstruction>);
+
+pub fn process_actions(
   mut commands: Commands,
   mut user_table: ResMut<UserResourceTable>,
-  query: Query<(Entity, &BuildingPerformAction, &Building, &UserOwned), Without<BuildingCooldown>>,
+  mut outcomes: EventWriter<ActionOutcome>,
+  mut wasted: EventWriter<ResourceWasted>,
+  building_table: Res<BuildingDefinitionTable>,
+  query: Query<ActionState, IdleBuildingFilter>,
 ) {
-  query.for_each(|(e, action_command, building, owner)| {
-    if let Some(user) = user_table.get_mut(&owner.0)
-    && let Some(building) = BUILDING_TABLE.get(&building.0)
-    && let Some(action) = building.actions.as_ref().unwrap_or(&Vec::new()).iter().find(|x| x.id == action_command.id)
-    && owner.0 == action_command.user_origin
-    && user.pay_resource_transaction(action.costs.clone().unwrap_or(Vec::new())) {
-      if let Some(products) = &action.products {
-        products.iter().for_each(|x| {
-          user.give_resources(x);
-        });
-
+  query.for_each(|(e, action_command, building, level, owner)| {
+    let result = perform_action(
+      &building_table,
+      &mut user_table,
+      &mut wasted,
+      action_command,
+      building,
+      level,
+      owner,
+    )
+    .map(|action| {
+      if action.products.is_some() {
         commands.entity(e).insert(BuildingCooldown(action.cooldown));
       }
+      e
+    });
+
+    outcomes.send(action_command.outcome(result));
+    commands.entity(e).remove::<BuildingPerformAction>();
+  });
+}
+
+/// Represents a request to upgrade a building to its next level.
+#[derive(Component)]
+pub struct BuildingUpgrade {
+  pub user_origin: Uuid,
+  pub ticket: Option<ActionTicket>,
+}
+
+/// Pays for the next level of the building, returning its definition.
+fn pay_upgrade<'a>(
+  building_table: &'a BuildingDefinitionTable,
+  user_table: &mut UserResourceTable,
+  upgrade: &BuildingUpgrade,
+  building: &Building,
+  level: &BuildingLevel,
+  owner: &UserOwned,
+) -> Result<&'a BuildingDefinition, ActionRejection> {
+  if owner.0 != upgrade.user_origin {
+    return Err(ActionRejection::NotOwner);
+  }
+
+  let building_def = building_table
+    .get(&building.0)
+    .ok_or(ActionRejection::UnknownBuilding)?;
+  let next_level = building_def.upgrade(level.0 + 1).ok_or(ActionRejection::MaxLevel)?;
+
+  let user = user_table.get_mut(&owner.0).ok_or(ActionRejection::UnknownUser)?;
+  if !user.pay_resource_transaction(next_level.costs.clone().unwrap_or_default()) {
+    return Err(ActionRejection::InsufficientResources);
+  }
+
+  Ok(building_def)
+}
+
+type UpgradeState<'a> = (
+  Entity,
+  &'a BuildingUpgrade,
+  &'a Building,
+  &'a BuildingSequence,
+  &'a mut BuildingLevel,
+  &'a UserOwned,
+  // Set while the building is still being built or upgraded.
+  Option<AnyOf<(&'a UnderConstruction, &'a Upgrading)>>,
+);
+
+/// Upgrades buildings to their next level. Upgrades that take time to build
+/// are completed by [complete_upgrades].
+fn upgrade_buildings(
+  mut commands: Commands,
+  mut user_table: ResMut<UserResourceTable>,
+  mut outcomes: EventWriter<ActionOutcome>,
+  building_table: Res<BuildingDefinitionTable>,
+  mut query: Query<UpgradeState>,
+) {
+  query.for_each_mut(|(e, upgrade, building, order, mut level, owner, in_progress)| {
+    let result = if in_progress.is_some() {
+      Err(ActionRejection::UnderConstruction)
     } else {
-      warn!("Unable to perform action");
+      pay_upgrade(&building_table, &mut user_table, upgrade, building, &level, owner).map(|building_def| {
+        if building_def.build_ticks_at(level.0 + 1) == 0 {
+          level.0 += 1;
+          commands.entity(e).despawn_descendants();
+          building_def.spawn_production_lines(&mut commands, e, *order, owner.0, level.0, &[]);
+        } else {
+          building_def.start_upgrade(&mut commands, e, level.0, 0);
+        }
+        e
+      })
+    };
+
+    outcomes.send(ActionOutcome {
+      user_id: upgrade.user_origin,
+      ticket: upgrade.ticket,
+      result,
+    });
+    commands.entity(e).remove::<BuildingUpgrade>();
+  });
+}
+
+type UpgradeProgressState<'a> = (
+  Entity,
+  &'a Building,
+  &'a BuildingSequence,
+  &'a mut BuildingLevel,
+  &'a UserOwned,
+  &'a Ticked,
+);
+
+/// Switches upgraded buildings to their next level, replacing their production
+/// lines with those of the new level. This runs once the current lines have
+/// produced for the tick.
+fn complete_upgrades(
+  mut commands: Commands,
+  building_table: Res<BuildingDefinitionTable>,
+  mut query: Query<UpgradeProgressState, With<Upgrading>>,
+) {
+  query.for_each_mut(|(ent, building, sequence, mut level, owner, ticked)| {
+    if ticked.fire_count() == 0 {
+      return;
     }
 
-    commands.entity(e).remove::<BuildingPerformAction>();
+    level.0 += 1;
+    commands
+      .entity(ent)
+      .remove::<(Upgrading, Ticked)>()
+      .despawn_descendants();
+    match building_table.get(&building.0) {
+      Some(building_def) => building_def.spawn_production_lines(&mut commands, ent, *sequence, owner.0, level.0, &[]),
+      None => warn!("Upgraded building {:?} with unknown definition {}", ent, building.0),
+    }
+  });
+}
+
+/// Represents a request to demolish a building, refunding part of its
+/// construction costs.
+#[derive(Component)]
+pub struct BuildingDemolish {
+  pub user_origin: Uuid,
+  pub ticket: Option<ActionTicket>,
+}
+
+type DemolishState<'a> = (
+  Entity,
+  &'a BuildingDemolish,
+  &'a Building,
+  &'a BuildingLevel,
+  &'a UserOwned,
+  Option<&'a Upgrading>,
+);
+
+pub fn demolish_buildings(
+  mut commands: Commands,
+  mut user_table: ResMut<UserResourceTable>,
+  mut outcomes: EventWriter<ActionOutcome>,
+  building_table: Res<BuildingDefinitionTable>,
+  properties: Res<GameProperties>,
+  query: Query<DemolishState>,
+) {
+  query.for_each(|(e, demolish, building, level, owner, upgrading)| {
+    let outcome = |result| ActionOutcome {
+      user_id: demolish.user_origin,
+      ticket: demolish.ticket,
+      result,
+    };
+
+    if owner.0 != demolish.user_origin {
+      outcomes.send(outcome(Err(ActionRejection::NotOwner)));
+      commands.entity(e).remove::<BuildingDemolish>();
+      return;
+    }
+
+    // Buildings whose definition was removed can still be demolished, but
+    // there is nothing left to refund. An upgrade in progress was already paid
+    // for, so it is refunded as well.
+    if let Some(building_def) = building_table.get(&building.0)
+      && let Some(user) = user_table.get_mut(&owner.0)
+    {
+      let paid_level = level.0 + upgrading.is_some() as u32;
+      building_def
+        .refund(paid_level, properties.demolish_refund)
+        .iter()
+        .for_each(|refund| user.return_resources(refund));
+    }
+
+    // Production lines, cooldowns and pending requests all go with the
+    // building, and its row is deleted once the removal is observed.
+    commands.entity(e).despawn_recursive();
+    outcomes.send(outcome(Ok(e)));
   });
 }
 
@@ -197,14 +1040,29 @@ pub struct BuildingPlugin;
 impl Plugin for BuildingPlugin {
   fn build(&self, app: &mut App) {
     info!("Loading Buildings...");
-    info!("Buildings Loaded: {}", BUILDING_TABLE.len());
+    let building_table = BuildingDefinitionTable::load();
+    info!("Buildings Loaded: {}", building_table.len());
 
     app
-      .insert_resource(BuildingDefinitionTable(BUILDING_TABLE.clone()))
+      .insert_resource(building_table)
+      .init_resource::<BuildingOccupancy>()
+      .init_resource::<BuildingIndex>()
+      .init_resource::<NextBuildingSequence>()
+      .add_event::<ActionOutcome>()
       .add_system_to_stage(GameStage::OnResourcesPaid, on_tick_building_ticked_resources)
       .add_system_to_stage(GameStage::Start, tick_down_building_cooldowns)
       .add_system_to_stage(GameStage::OnResourcesPaid, process_actions)
-      .add_system_to_stage(GameStage::OnTicked, dismiss_actions_when_on_cooldown);
+      .add_system_to_stage(GameStage::OnResourcesPaid, upgrade_buildings.after(process_actions))
+      // Demolish last, so that no other system queues commands on the building
+      // after it is despawned. Systems of other plugins that queue commands on
+      // buildings or their lines must be ordered before it as well.
+      .add_system_to_stage(GameStage::OnResourcesPaid, demolish_buildings.after(upgrade_buildings))
+      .add_system_to_stage(GameStage::OnTicked, dismiss_actions_when_on_cooldown)
+      .add_system_to_stage(GameStage::OnTicked, dismiss_actions_when_under_construction)
+      .add_system_to_stage(GameStage::OnTicked, complete_construction)
+      .add_system_to_stage(GameStage::Cleanup, complete_upgrades)
+      .add_system_to_stage(CoreStage::PostUpdate, index_building_occupancy)
+      .add_system_to_stage(CoreStage::PostUpdate, index_building_ids);
   }
 }
 
@@ -212,16 +1070,20 @@ impl Plugin for BuildingPlugin {
 mod tests {
   use bevy::ecs::system::CommandQueue;
   use bevy::prelude::*;
-  use hashbrown::HashMap;
+  use hashbrown::{HashMap, HashSet};
   use uuid::Uuid;
 
-  use super::{BuildingCooldown, BuildingPerformAction, BUILDING_TABLE};
+  use super::{
+    BuildingCooldown, BuildingDefinitionFile, BuildingDefinitionTable, BuildingOccupancy, BuildingPerformAction,
+    BuildingPlacementFlags, PlacementError, PrerequisiteError, ProductionLine, UnderConstruction,
+  };
   use crate::db::models::User;
-  use crate::game::building::{Building, BuildingPlugin};
-  use crate::game::resources::ResourcePlugin;
+  use crate::game::building::{Building, BuildingPlugin, BuildingSequence};
+  use crate::game::resources::{Resource, ResourcePlugin};
   use crate::game::stages::StagePlugin;
   use crate::game::tick::TickPlugin;
   use crate::game::user::UserResourceTable;
+  use crate::game::world::{ComplexTerrainTile, StaticTerrainTile, TerrainTile};
   use crate::properties::GameProperties;
 
   #[test]
@@ -233,12 +1095,13 @@ mod tests {
 
     let owner = Uuid::new_v4();
     // Spawn from the building definitions
-    BUILDING_TABLE["Headquarters"].spawn(commands, owner, IVec2 { x: 2, y: 4 });
+    BuildingDefinitionTable::load()["Headquarters"].spawn(commands, BuildingSequence(0), owner, IVec2 { x: 2, y: 4 });
     queue.apply(&mut world);
 
-    assert_eq!(world.entities().len(), 1, "Building was not created");
     let building: Vec<&Building> = world.query::<&Building>().iter(&world).collect::<Vec<_>>();
+    assert_eq!(building.len(), 1, "Building was not created");
     assert_eq!(building[0].0, "Headquarters");
+    assert_eq!(world.query::<&ProductionLine>().iter(&world).count(), 1);
   }
 
   #[test]
@@ -267,23 +1130,218 @@ mod tests {
     let mut queue = CommandQueue::default();
     let commands = &mut Commands::new(&mut queue, &app.world);
 
-    BUILDING_TABLE["Headquarters"].spawn(commands, id.clone(), IVec2 { x: 0, y: 0 });
+    BuildingDefinitionTable::load()["Headquarters"].spawn(
+      commands,
+      BuildingSequence(0),
+      id.clone(),
+      IVec2 { x: 0, y: 0 },
+    );
     queue.apply(&mut app.world);
 
     app.update();
 
     let user_table: &UserResourceTable = app.world.get_resource().unwrap();
-    assert_eq!(user_table.get(&id).unwrap().credits, 1);
+    assert_eq!(user_table.get(&id).unwrap().get(Resource::Credit), 1);
 
     app.update();
 
     let user_table: &UserResourceTable = app.world.get_resource().unwrap();
-    assert_eq!(user_table.get(&id).unwrap().credits, 2);
+    assert_eq!(user_table.get(&id).unwrap().get(Resource::Credit), 2);
 
     app.update();
 
     let user_table: &UserResourceTable = app.world.get_resource().unwrap();
-    assert_eq!(user_table.get(&id).unwrap().credits, 3);
+    assert_eq!(user_table.get(&id).unwrap().get(Resource::Credit), 3);
+  }
+
+  #[test]
+  fn building_multiple_production_lines() {
+    let definitions: BuildingDefinitionFile = toml::from_str(
+      r#"
+      [[buildings]]
+      name = "Factory"
+      size = [1, 1]
+      priority = 0
+
+      [buildings.placement]
+      on_water = false
+      on_mineral = false
+
+      [[buildings.ticked]]
+      every_n_ticks = 1
+
+      [[buildings.ticked.products]]
+      resource = "Credit"
+      value = 1
+
+      [[buildings.ticked]]
+      every_n_ticks = 2
+
+      [[buildings.ticked.products]]
+      resource = "Credit"
+      value = 5
+      "#,
+    )
+    .unwrap();
+
+    // Build App
+    let mut app = App::new();
+    app
+      .add_plugins(MinimalPlugins)
+      .add_plugin(StagePlugin)
+      .add_plugin(TickPlugin)
+      .add_plugin(ResourcePlugin)
+      .add_plugin(BuildingPlugin)
+      .init_resource::<GameProperties>();
+
+    let id = Uuid::new_v4();
+    let user = User {
+      id,
+      ..Default::default()
+    };
+
+    // Insert a User with Data
+    app
+      .world
+      .insert_resource(UserResourceTable::new(HashMap::from([(user.id, user)])));
+
+    let mut queue = CommandQueue::default();
+    let commands = &mut Commands::new(&mut queue, &app.world);
+
+    definitions.buildings[0].spawn(commands, BuildingSequence(0), id, IVec2 { x: 0, y: 0 });
+    queue.apply(&mut app.world);
+
+    // Both lines produce independently at their own interval.
+    for expected in [1, 7, 8, 14] {
+      app.update();
+
+      let user_table: &UserResourceTable = app.world.get_resource().unwrap();
+      assert_eq!(user_table.get(&id).unwrap().get(Resource::Credit), expected);
+    }
+  }
+
+  #[test]
+  fn building_prerequisites() {
+    let file: BuildingDefinitionFile = toml::from_str(
+      r#"
+      [[buildings]]
+      name = "Smelter"
+      size = [1, 1]
+      priority = 1
+
+      [buildings.placement]
+      on_water = false
+      on_mineral = false
+
+      [[buildings.requires]]
+      building = "Headquarters"
+      level = 2
+
+      [[buildings.requires]]
+      research = "metallurgy"
+      "#,
+    )
+    .unwrap();
+    let smelter = &file.buildings[0];
+
+    let research = HashSet::from(["metallurgy".to_string()]);
+    assert_eq!(
+      smelter.check_prerequisites(&research, &HashMap::new()),
+      Err(PrerequisiteError::MissingBuilding)
+    );
+    assert_eq!(
+      smelter.check_prerequisites(&research, &HashMap::from([("Headquarters", 1)])),
+      Err(PrerequisiteError::BuildingLevelTooLow)
+    );
+    assert_eq!(
+      smelter.check_prerequisites(&HashSet::new(), &HashMap::from([("Headquarters", 2)])),
+      Err(PrerequisiteError::MissingResearch)
+    );
+    assert_eq!(
+      smelter.check_prerequisites(&research, &HashMap::from([("Headquarters", 3)])),
+      Ok(())
+    );
+  }
+
+  #[test]
+  fn building_construction() {
+    let definitions: BuildingDefinitionFile = toml::from_str(
+      r#"
+      [[buildings]]
+      name = "Factory"
+      size = [1, 1]
+      priority = 0
+      build_ticks = 2
+
+      [buildings.placement]
+      on_water = false
+      on_mineral = false
+
+      [[buildings.actions]]
+      id = "work"
+      name = "Work"
+      cooldown = 1
+
+      [[buildings.ticked]]
+      every_n_ticks = 1
+
+      [[buildings.ticked.products]]
+      resource = "Credit"
+      value = 1
+      "#,
+    )
+    .unwrap();
+
+    // Build App
+    let mut app = App::new();
+    app
+      .add_plugins(MinimalPlugins)
+      .add_plugin(StagePlugin)
+      .add_plugin(TickPlugin)
+      .add_plugin(ResourcePlugin)
+      .add_plugin(BuildingPlugin)
+      .init_resource::<GameProperties>();
+
+    let factory = definitions.buildings[0].clone();
+    app
+      .world
+      .resource_mut::<BuildingDefinitionTable>()
+      .0
+      .insert(factory.name.clone(), factory.clone());
+
+    let id = Uuid::new_v4();
+    let user = User {
+      id,
+      ..Default::default()
+    };
+
+    // Insert a User with Data
+    app
+      .world
+      .insert_resource(UserResourceTable::new(HashMap::from([(user.id, user)])));
+
+    let mut queue = CommandQueue::default();
+    let commands = &mut Commands::new(&mut queue, &app.world);
+
+    let ent = factory.construct(commands, BuildingSequence(0), id, IVec2 { x: 0, y: 0 });
+    commands.entity(ent).insert(BuildingPerformAction {
+      user_origin: id,
+      id: "work".to_string(),
+      ticket: None,
+    });
+    queue.apply(&mut app.world);
+
+    // Nothing is produced until construction completes.
+    for expected in [0, 0, 1, 2] {
+      app.update();
+
+      let user_table: &UserResourceTable = app.world.get_resource().unwrap();
+      assert_eq!(user_table.get(&id).unwrap().get(Resource::Credit), expected);
+    }
+
+    let building = app.world.entity(ent);
+    assert!(!building.contains::<UnderConstruction>());
+    assert!(!building.contains::<BuildingPerformAction>());
   }
 
   #[test]
@@ -343,10 +1401,7 @@ mod tests {
       .init_resource::<GameProperties>();
 
     let id = Uuid::new_v4();
-    let user = User {
-      id: id.clone(),
-      credits: 1,
-    };
+    let user = User::with_resources(id.clone(), [(Resource::Credit, 1)]);
 
     // Insert a User with Data
     app
@@ -356,28 +1411,34 @@ mod tests {
     let mut queue = CommandQueue::default();
     let commands = &mut Commands::new(&mut queue, &app.world);
 
-    let ent = BUILDING_TABLE["Headquarters"].spawn(commands, id.clone(), IVec2 { x: 0, y: 0 });
+    let ent = BuildingDefinitionTable::load()["Headquarters"].spawn(
+      commands,
+      BuildingSequence(0),
+      id.clone(),
+      IVec2 { x: 0, y: 0 },
+    );
     commands.entity(ent).insert(BuildingPerformAction {
       user_origin: id,
       id: "increase_cash_flow".to_string(),
+      ticket: None,
     });
 
     // Before spawn in
     let user_table: &UserResourceTable = app.world.get_resource().unwrap();
-    assert_eq!(user_table.get(&id).unwrap().credits, 1);
+    assert_eq!(user_table.get(&id).unwrap().get(Resource::Credit), 1);
 
     queue.apply(&mut app.world);
     app.update();
 
     // Spawned in, action is processed and idle gen proced
     let user_table: &UserResourceTable = app.world.get_resource().unwrap();
-    assert_eq!(user_table.get(&id).unwrap().credits, 4);
+    assert_eq!(user_table.get(&id).unwrap().get(Resource::Credit), 4);
 
     app.update();
 
     // Idle Gen
     let user_table: &UserResourceTable = app.world.get_resource().unwrap();
-    assert_eq!(user_table.get(&id).unwrap().credits, 5);
+    assert_eq!(user_table.get(&id).unwrap().get(Resource::Credit), 5);
   }
 
   #[test]
@@ -393,10 +1454,7 @@ mod tests {
       .init_resource::<GameProperties>();
 
     let id = Uuid::new_v4();
-    let user = User {
-      id: id.clone(),
-      credits: 1,
-    };
+    let user = User::with_resources(id.clone(), [(Resource::Credit, 1)]);
 
     // Insert a User with Data
     app
@@ -406,31 +1464,117 @@ mod tests {
     let mut queue = CommandQueue::default();
     let commands = &mut Commands::new(&mut queue, &app.world);
 
-    let ent = BUILDING_TABLE["Headquarters"].spawn(commands, id.clone(), IVec2 { x: 0, y: 0 });
+    let ent = BuildingDefinitionTable::load()["Headquarters"].spawn(
+      commands,
+      BuildingSequence(0),
+      id.clone(),
+      IVec2 { x: 0, y: 0 },
+    );
     commands
       .entity(ent)
       .insert(BuildingPerformAction {
         user_origin: id,
         id: "increase_cash_flow".to_string(),
+        ticket: None,
       })
       .insert(BuildingCooldown(5));
 
     // Start
     let user_table: &UserResourceTable = app.world.get_resource().unwrap();
-    assert_eq!(user_table.get(&id).unwrap().credits, 1);
+    assert_eq!(user_table.get(&id).unwrap().get(Resource::Credit), 1);
 
     queue.apply(&mut app.world);
     app.update();
 
     // Spawned in, action didn't proc
     let user_table: &UserResourceTable = app.world.get_resource().unwrap();
-    assert_eq!(user_table.get(&id).unwrap().credits, 2);
+    assert_eq!(user_table.get(&id).unwrap().get(Resource::Credit), 2);
 
     queue.apply(&mut app.world);
     app.update();
 
     // Verify won't proc
     let user_table: &UserResourceTable = app.world.get_resource().unwrap();
-    assert_eq!(user_table.get(&id).unwrap().credits, 3);
+    assert_eq!(user_table.get(&id).unwrap().get(Resource::Credit), 3);
+  }
+
+  #[test]
+  fn placement_flags() {
+    let stone = TerrainTile::Static(StaticTerrainTile::Stone);
+    let water = TerrainTile::Static(StaticTerrainTile::Water);
+    let impassable = TerrainTile::Static(StaticTerrainTile::Impassable);
+    let copper = TerrainTile::Complex(ComplexTerrainTile::Copper(100));
+
+    let land_only = BuildingPlacementFlags {
+      on_water: false,
+      on_mineral: false,
+    };
+    assert_eq!(land_only.check(&stone), Ok(()));
+    assert_eq!(land_only.check(&water), Err(PlacementError::Water));
+    assert_eq!(land_only.check(&copper), Err(PlacementError::Mineral));
+    assert_eq!(land_only.check(&impassable), Err(PlacementError::Impassable));
+
+    let anywhere = BuildingPlacementFlags {
+      on_water: true,
+      on_mineral: true,
+    };
+    assert_eq!(anywhere.check(&stone), Ok(()));
+    assert_eq!(anywhere.check(&water), Ok(()));
+    assert_eq!(anywhere.check(&copper), Ok(()));
+    assert_eq!(anywhere.check(&impassable), Err(PlacementError::Impassable));
+  }
+
+  #[test]
+  fn building_occupancy() {
+    // Build App
+    let mut app = App::new();
+    app
+      .add_plugins(MinimalPlugins)
+      .add_plugin(StagePlugin)
+      .add_plugin(TickPlugin)
+      .add_plugin(ResourcePlugin)
+      .add_plugin(BuildingPlugin)
+      .init_resource::<GameProperties>()
+      .insert_resource(UserResourceTable::new(HashMap::new()));
+
+    let mut queue = CommandQueue::default();
+    let commands = &mut Commands::new(&mut queue, &app.world);
+
+    let ent = BuildingDefinitionTable::load()["Headquarters"].spawn(
+      commands,
+      BuildingSequence(0),
+      Uuid::new_v4(),
+      IVec2 { x: 2, y: 4 },
+    );
+    queue.apply(&mut app.world);
+    app.update();
+
+    let occupancy: &BuildingOccupancy = app.world.get_resource().unwrap();
+    assert_eq!(occupancy.get(IVec2::new(2, 4)), Some(ent));
+    assert_eq!(occupancy.get(IVec2::new(3, 5)), Some(ent));
+    assert_eq!(occupancy.get(IVec2::new(4, 4)), None);
+
+    let building_table = BuildingDefinitionTable::load();
+    let headquarters = &building_table["Headquarters"];
+    assert_eq!(
+      occupancy.check(headquarters.tiles(IVec2::new(3, 3))),
+      Err(PlacementError::Occupied)
+    );
+    assert_eq!(occupancy.check(headquarters.tiles(IVec2::new(4, 4))), Ok(()));
+
+    // Moving the building frees the tiles it used to cover
+    app.world.entity_mut(ent).get_mut::<Transform>().unwrap().translation.x = 10.0;
+    app.update();
+
+    let occupancy: &BuildingOccupancy = app.world.get_resource().unwrap();
+    assert_eq!(occupancy.get(IVec2::new(2, 4)), None);
+    assert_eq!(occupancy.get(IVec2::new(10, 4)), Some(ent));
+
+    // Despawning the building frees all tiles
+    app.world.despawn(ent);
+    app.update();
+
+    let occupancy: &BuildingOccupancy = app.world.get_resource().unwrap();
+    assert_eq!(occupancy.get(IVec2::new(10, 4)), None);
   }
 }
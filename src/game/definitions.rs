@@ -0,0 +1,713 @@
+//! Building Definition Assets
+//!
+//! Building definitions are loaded from the `buildings` folder through the
+//! asset server, which watches the files for changes. Whenever a file changes
+//! the [BuildingDefinitionTable] is rebuilt, and buildings whose definition
+//! changed are refreshed before the next tick:
+//!
+//! - Production lines are respawned, keeping their progress.
+//! - Cooldowns in progress are shortened to the longest cooldown of the new
+//!   actions. Longer cooldowns apply from the next action performed.
+//! - Resized buildings keep their position, unless the new footprint would
+//!   overlap another building. The terrain under them is not checked again.
+//!
+//! The folder is only listed at startup, so files added to it later are
+//! picked up on the next restart. Changes to existing files apply right away.
+//!
+//! Every file is validated as it is parsed, reporting the file, line and
+//! reason of each problem found.
+
+use std::fmt::{self, Display};
+use std::io;
+use std::path::{Path, PathBuf};
+
+use bevy::asset::{AssetLoader, BoxedFuture, LoadContext, LoadState, LoadedAsset};
+use bevy::prelude::*;
+use hashbrown::HashSet;
+use itertools::Itertools;
+use serde::Deserialize;
+use toml::Spanned;
+
+use super::building::{
+  building_tiles, Building, BuildingCooldown, BuildingDefinition, BuildingDefinitionFile, BuildingDefinitionTable,
+  BuildingLevel, BuildingOccupancy, BuildingSequence, ProductionLine, UnderConstruction,
+};
+use super::tick::Ticked;
+use super::user::UserOwned;
+
+/// Reasons a building definition file is invalid.
+#[derive(Debug)]
+pub enum BuildingDefinitionProblem {
+  /// The file could not be read.
+  Io(io::Error),
+  /// The file does not match the definition format, such as when it uses an
+  /// unknown resource.
+  Parse(toml::de::Error),
+  /// Another definition already uses the name.
+  DuplicateBuilding(String),
+  /// The building has a zero or negative size.
+  InvalidSize { building: String, size: [i32; 2] },
+  /// A production line of the building has `every_n_ticks = 0`.
+  ZeroTickInterval { building: String },
+  /// The building has several actions with the same id.
+  DuplicateAction { building: String, action: String },
+}
+
+/// A problem found in a building definition file.
+#[derive(Debug)]
+pub struct BuildingDefinitionError {
+  pub path: PathBuf,
+  /// Line the problem was found on, starting from 1.
+  pub line: Option<usize>,
+  pub problem: BuildingDefinitionProblem,
+}
+
+impl Display for BuildingDefinitionError {
+  fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+    write!(f, "{}", self.path.display())?;
+    match &self.problem {
+      // Parsing errors already mention the line.
+      BuildingDefinitionProblem::Io(err) => return write!(f, ": {}", err),
+      BuildingDefinitionProblem::Parse(err) => return write!(f, ": {}", err),
+      _ => {},
+    }
+
+    if let Some(line) = self.line {
+      write!(f, ":{}", line)?;
+    }
+
+    match &self.problem {
+      BuildingDefinitionProblem::DuplicateBuilding(building) => {
+        write!(f, ": building {} is defined more than once", building)
+      },
+      BuildingDefinitionProblem::InvalidSize { building, size } => {
+        write!(f, ": building {} has invalid size {:?}", building, size)
+      },
+      BuildingDefinitionProblem::ZeroTickInterval { building } => {
+        write!(
+          f,
+          ": building {} has a production line with every_n_ticks = 0",
+          building
+        )
+      },
+      BuildingDefinitionProblem::DuplicateAction { building, action } => {
+        write!(f, ": building {} defines action {} more than once", building, action)
+      },
+      BuildingDefinitionProblem::Io(_) | BuildingDefinitionProblem::Parse(_) => Ok(()),
+    }
+  }
+}
+
+impl std::error::Error for BuildingDefinitionError {}
+
+/// The validated parts of a [BuildingDefinitionFile], along with where they
+/// are in the file.
+#[derive(Deserialize)]
+struct SpannedDefinitionFile {
+  buildings: Vec<SpannedDefinition>,
+}
+
+#[derive(Deserialize)]
+struct SpannedDefinition {
+  name: Spanned<String>,
+  size: Spanned<[i32; 2]>,
+  actions: Option<Vec<SpannedAction>>,
+  ticked: Option<Vec<SpannedTickedAction>>,
+  upgrades: Option<Vec<SpannedLevel>>,
+}
+
+#[derive(Deserialize)]
+struct SpannedLevel {
+  actions: Option<Vec<SpannedAction>>,
+  ticked: Option<Vec<SpannedTickedAction>>,
+}
+
+#[derive(Deserialize)]
+struct SpannedAction {
+  id: Spanned<String>,
+}
+
+#[derive(Deserialize)]
+struct SpannedTickedAction {
+  every_n_ticks: Spanned<u32>,
+}
+
+/// Finds the production lines with a zero tick interval and the duplicate
+/// actions of a building level, along with their offset in the file.
+fn level_problems(
+  building: &str,
+  ticked: &Option<Vec<SpannedTickedAction>>,
+  actions: &Option<Vec<SpannedAction>>,
+) -> Vec<(usize, BuildingDefinitionProblem)> {
+  let zero_intervals = ticked
+    .iter()
+    .flatten()
+    .filter(|ticked| *ticked.every_n_ticks.get_ref() == 0)
+    .map(|ticked| {
+      let problem = BuildingDefinitionProblem::ZeroTickInterval {
+        building: building.to_string(),
+      };
+      (ticked.every_n_ticks.start(), problem)
+    });
+
+  let mut action_ids = HashSet::new();
+  let duplicate_actions = actions
+    .iter()
+    .flatten()
+    .filter(|action| !action_ids.insert(action.id.get_ref()))
+    .map(|action| {
+      let problem = BuildingDefinitionProblem::DuplicateAction {
+        building: building.to_string(),
+        action: action.id.get_ref().clone(),
+      };
+      (action.id.start(), problem)
+    });
+
+  zero_intervals.chain(duplicate_actions).collect()
+}
+
+/// Parses and validates a building definition file. `names` holds the
+/// buildings defined by previously parsed files, and is extended with the
+/// buildings of this one.
+pub fn parse_definition_file(
+  path: &Path,
+  contents: &str,
+  names: &mut HashSet<String>,
+) -> Result<BuildingDefinitionFile, Vec<BuildingDefinitionError>> {
+  let error = |line, problem| BuildingDefinitionError {
+    path: path.to_path_buf(),
+    line,
+    problem,
+  };
+
+  let parsed = toml::from_str::<BuildingDefinitionFile>(contents)
+    .and_then(|file| Ok((file, toml::from_str::<SpannedDefinitionFile>(contents)?)));
+  let (file, spanned) = match parsed {
+    Ok(parsed) => parsed,
+    Err(err) => {
+      let line = err.line_col().map(|(line, _)| line + 1);
+      return Err(vec![error(line, BuildingDefinitionProblem::Parse(err))]);
+    },
+  };
+
+  let line_of = |offset: usize| Some(contents[..offset].matches('\n').count() + 1);
+  let mut errors = Vec::new();
+  spanned.buildings.iter().for_each(|building_def| {
+    let building = building_def.name.get_ref();
+
+    if !names.insert(building.clone()) {
+      errors.push(error(
+        line_of(building_def.name.start()),
+        BuildingDefinitionProblem::DuplicateBuilding(building.clone()),
+      ));
+    }
+
+    let size = *building_def.size.get_ref();
+    if size.iter().any(|x| *x <= 0) {
+      errors.push(error(
+        line_of(building_def.size.start()),
+        BuildingDefinitionProblem::InvalidSize {
+          building: building.clone(),
+          size,
+        },
+      ));
+    }
+
+    // Upgrades replace the production lines and actions of the building, so
+    // each level is checked on its own.
+    let levels = building_def
+      .upgrades
+      .iter()
+      .flatten()
+      .map(|level| (&level.ticked, &level.actions));
+    [(&building_def.ticked, &building_def.actions)]
+      .into_iter()
+      .chain(levels)
+      .flat_map(|(ticked, actions)| level_problems(building, ticked, actions))
+      .for_each(|(offset, problem)| errors.push(error(line_of(offset), problem)));
+  });
+
+  if errors.is_empty() {
+    Ok(file)
+  } else {
+    Err(errors)
+  }
+}
+
+/// Loads a `.toml` file of building definitions.
+#[derive(Default)]
+pub struct BuildingDefinitionLoader;
+
+impl AssetLoader for BuildingDefinitionLoader {
+  fn load<'a>(
+    &'a self,
+    bytes: &'a [u8],
+    load_context: &'a mut LoadContext,
+  ) -> BoxedFuture<'a, Result<(), bevy::asset::Error>> {
+    Box::pin(async move {
+      let contents = std::str::from_utf8(bytes)?;
+      let file = parse_definition_file(load_context.path(), contents, &mut HashSet::new())
+        .map_err(|errors| bevy::asset::Error::msg(errors.iter().join("\n")))?;
+      load_context.set_default_asset(LoadedAsset::new(file));
+      Ok(())
+    })
+  }
+
+  fn extensions(&self) -> &[&str] {
+    &["toml"]
+  }
+}
+
+/// Handles of every building definition file, which keep them loaded.
+#[derive(Resource)]
+pub struct BuildingDefinitionHandles(Vec<HandleUntyped>);
+
+fn load_building_definition_files(mut commands: Commands, asset_server: Res<AssetServer>) {
+  match asset_server.load_folder("buildings") {
+    Ok(handles) => commands.insert_resource(BuildingDefinitionHandles(handles)),
+    Err(err) => warn!("Failed to load building definitions: {}", err),
+  }
+}
+
+/// Emitted whenever the [BuildingDefinitionTable] is rebuilt with different
+/// definitions.
+pub struct BuildingDefinitionsReloaded {
+  /// Names of the definitions that were added or modified.
+  pub changed: HashSet<String>,
+  /// Names of the definitions that no longer exist.
+  pub removed: HashSet<String>,
+}
+
+/// Rebuilds the [BuildingDefinitionTable] once definition files have been
+/// loaded or modified.
+fn reload_building_definitions(
+  mut events: EventReader<AssetEvent<BuildingDefinitionFile>>,
+  mut reloaded_events: EventWriter<BuildingDefinitionsReloaded>,
+  mut pending: Local<bool>,
+  asset_server: Res<AssetServer>,
+  handles: Option<Res<BuildingDefinitionHandles>>,
+  files: Res<Assets<BuildingDefinitionFile>>,
+  mut building_table: ResMut<BuildingDefinitionTable>,
+) {
+  *pending |= events.iter().count() > 0;
+
+  // Wait for every file, so that a partially loaded folder doesn't look like
+  // removed definitions.
+  let loading = handles.is_some_and(|handles| {
+    asset_server.get_group_load_state(handles.0.iter().map(|handle| handle.id)) == LoadState::Loading
+  });
+  if !*pending || loading {
+    return;
+  }
+  *pending = false;
+
+  // Files are validated on their own as they load, duplicates between them
+  // can only be found once all of them are.
+  let duplicates = files
+    .iter()
+    .flat_map(|(_, file)| file.buildings.iter().map(|building_def| &building_def.name))
+    .duplicates()
+    .collect::<Vec<_>>();
+  if !duplicates.is_empty() {
+    error!(
+      "Keeping the current building definitions, buildings are defined more than once: {:?}",
+      duplicates
+    );
+    return;
+  }
+
+  let reloaded = BuildingDefinitionTable::from_files(files.iter().map(|(_, file)| file));
+  let removed = building_table
+    .keys()
+    .filter(|name| !reloaded.contains_key(*name))
+    .cloned()
+    .collect::<HashSet<_>>();
+  let changed = reloaded
+    .iter()
+    .filter(|(name, building_def)| building_table.get(*name) != Some(*building_def))
+    .map(|(name, _)| name.clone())
+    .collect::<HashSet<_>>();
+
+  if removed.is_empty() && changed.is_empty() {
+    return;
+  }
+
+  info!(
+    "Reloaded building definitions: {} changed, {} removed",
+    changed.len(),
+    removed.len()
+  );
+  *building_table = reloaded;
+  reloaded_events.send(BuildingDefinitionsReloaded { changed, removed });
+}
+
+type ReloadedBuildingState<'a> = (
+  Entity,
+  &'a Building,
+  &'a BuildingSequence,
+  &'a BuildingLevel,
+  &'a UserOwned,
+  &'a mut Transform,
+  Option<&'a mut BuildingCooldown>,
+  Option<&'a UnderConstruction>,
+  Option<&'a Children>,
+);
+
+/// Resizes the building to the size of its definition, unless it would
+/// overlap another building.
+fn resize_building(
+  occupancy: &mut BuildingOccupancy,
+  ent: Entity,
+  building_def: &BuildingDefinition,
+  transform: &mut Mut<Transform>,
+) {
+  let size = IVec2::from(building_def.size);
+  if transform.scale.truncate().as_ivec2() == size {
+    return;
+  }
+
+  let position = transform.translation.truncate().as_ivec2();
+  match occupancy.check_for(ent, building_tiles(position, size)) {
+    Ok(()) => {
+      transform.scale = size.as_vec2().extend(0.0);
+      occupancy.insert(ent, position, size);
+    },
+    Err(_) => warn!(
+      "Building {:?} keeps its current size, {} would overlap another building",
+      ent, building_def.name
+    ),
+  }
+}
+
+/// Refreshes every building whose definition changed. Tick counters of the
+/// production lines carry over by line index.
+fn refresh_reloaded_buildings(
+  mut commands: Commands,
+  mut events: EventReader<BuildingDefinitionsReloaded>,
+  building_table: Res<BuildingDefinitionTable>,
+  mut occupancy: ResMut<BuildingOccupancy>,
+  mut buildings: Query<ReloadedBuildingState>,
+  lines: Query<(&ProductionLine, &Ticked)>,
+) {
+  events
+    .iter()
+    .for_each(|BuildingDefinitionsReloaded { changed, removed }| {
+      removed.iter().for_each(|name| {
+        let count = buildings.iter().filter(|(_, building, ..)| &building.0 == name).count();
+        warn!(
+          "Building definition {} was removed, {} existing buildings keep their current production lines",
+          name, count
+        );
+      });
+
+      buildings.for_each_mut(
+        |(ent, building, sequence, level, owner, mut transform, cooldown, under_construction, children)| {
+          if !changed.contains(&building.0) {
+            return;
+          }
+
+          let building_def = &building_table[&building.0];
+          resize_building(&mut occupancy, ent, building_def, &mut transform);
+
+          if let Some(mut cooldown) = cooldown {
+            let longest = building_def.longest_cooldown_at(level.0);
+            if cooldown.0 > longest {
+              cooldown.0 = longest;
+            }
+          }
+
+          // Lines are spawned once construction completes.
+          if under_construction.is_some() {
+            return;
+          }
+
+          let counters = children
+            .iter()
+            .flat_map(|children| children.iter())
+            .filter_map(|child| lines.get(*child).ok())
+            .sorted_by_key(|(line, _)| line.0)
+            .map(|(_, ticked)| ticked.counter())
+            .collect::<Vec<_>>();
+
+          commands.entity(ent).despawn_descendants();
+          building_def.spawn_production_lines(&mut commands, ent, *sequence, owner.0, level.0, &counters);
+        },
+      );
+    });
+}
+
+pub struct BuildingDefinitionAssetPlugin;
+
+impl Plugin for BuildingDefinitionAssetPlugin {
+  fn build(&self, app: &mut App) {
+    info!("Loading Building Definition Assets...");
+    app
+      .add_asset::<BuildingDefinitionFile>()
+      .init_asset_loader::<BuildingDefinitionLoader>()
+      .add_event::<BuildingDefinitionsReloaded>()
+      .add_startup_system(load_building_definition_files)
+      .add_system_to_stage(CoreStage::PreUpdate, reload_building_definitions)
+      .add_system_to_stage(
+        CoreStage::PreUpdate,
+        refresh_reloaded_buildings.after(reload_building_definitions),
+      );
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use std::path::Path;
+
+  use bevy::ecs::system::CommandQueue;
+  use bevy::prelude::*;
+  use hashbrown::{HashMap, HashSet};
+  use uuid::Uuid;
+
+  use super::{parse_definition_file, BuildingDefinitionAssetPlugin, BuildingDefinitionProblem};
+  use crate::db::models::User;
+  use crate::game::building::{
+    BuildingCooldown, BuildingDefinitionFile, BuildingDefinitionTable, BuildingOccupancy, BuildingPlugin,
+    BuildingSequence,
+  };
+  use crate::game::resources::{Resource, ResourcePlugin};
+  use crate::game::stages::StagePlugin;
+  use crate::game::tick::TickPlugin;
+  use crate::game::user::UserResourceTable;
+  use crate::properties::GameProperties;
+
+  #[test]
+  fn reloads_changed_definitions() {
+    // Build App
+    let mut app = App::new();
+    app
+      .add_plugins(MinimalPlugins)
+      .add_plugin(AssetPlugin::default())
+      .add_plugin(StagePlugin)
+      .add_plugin(TickPlugin)
+      .add_plugin(ResourcePlugin)
+      .add_plugin(BuildingPlugin)
+      .add_plugin(BuildingDefinitionAssetPlugin)
+      .init_resource::<GameProperties>();
+
+    let id = Uuid::new_v4();
+    let user = User {
+      id,
+      ..Default::default()
+    };
+
+    // Insert a User with Data
+    app
+      .world
+      .insert_resource(UserResourceTable::new(HashMap::from([(user.id, user)])));
+
+    let mut queue = CommandQueue::default();
+    let commands = &mut Commands::new(&mut queue, &app.world);
+
+    let hq =
+      BuildingDefinitionTable::load()["Headquarters"].spawn(commands, BuildingSequence(0), id, IVec2 { x: 0, y: 0 });
+    commands.entity(hq).insert(BuildingCooldown(20));
+    queue.apply(&mut app.world);
+
+    app.update();
+
+    let user_table: &UserResourceTable = app.world.get_resource().unwrap();
+    assert_eq!(user_table.get(&id).unwrap().get(Resource::Credit), 1);
+
+    // Headquarters now produce more, are larger with no action cooldowns, and
+    // the Coal Mine is gone.
+    let mut buildings = BuildingDefinitionTable::load()
+      .values()
+      .filter(|building_def| building_def.name != "Coal Mine")
+      .cloned()
+      .collect::<Vec<_>>();
+    buildings
+      .iter_mut()
+      .filter(|building_def| building_def.name == "Headquarters")
+      .for_each(|building_def| {
+        building_def.ticked.as_mut().unwrap()[0].products = Some(vec![Resource::Credit.d(10)]);
+        building_def.size = [3, 3];
+        building_def.actions = None;
+      });
+    app
+      .world
+      .resource_mut::<Assets<BuildingDefinitionFile>>()
+      .add(BuildingDefinitionFile { buildings });
+
+    // The file is only loaded at the end of the update.
+    app.update();
+
+    let user_table: &UserResourceTable = app.world.get_resource().unwrap();
+    assert_eq!(user_table.get(&id).unwrap().get(Resource::Credit), 2);
+
+    app.update();
+
+    let user_table: &UserResourceTable = app.world.get_resource().unwrap();
+    assert_eq!(user_table.get(&id).unwrap().get(Resource::Credit), 12);
+
+    assert_eq!(
+      app.world.resource::<BuildingOccupancy>().get(IVec2::new(2, 2)),
+      Some(hq)
+    );
+    assert_eq!(app.world.get::<Transform>(hq).unwrap().scale, Vec3::new(3.0, 3.0, 0.0));
+    // The cooldown was cut short, then removed on the tick it ran out.
+    assert!(app.world.get::<BuildingCooldown>(hq).is_none());
+
+    let building_table = app.world.resource::<BuildingDefinitionTable>();
+    assert!(building_table.contains_key("Headquarters"));
+    assert!(!building_table.contains_key("Coal Mine"));
+  }
+
+  #[test]
+  fn validates_definitions() {
+    let contents = r#"
+      [[buildings]]
+      name = "Factory"
+      size = [0, 1]
+      priority = 0
+
+      [buildings.placement]
+      on_water = false
+      on_mineral = false
+
+      [[buildings.ticked]]
+      every_n_ticks = 0
+
+      [[buildings.actions]]
+      id = "work"
+      name = "Work"
+      cooldown = 1
+
+      [[buildings.actions]]
+      id = "work"
+      name = "Work Harder"
+      cooldown = 1
+
+      [[buildings.upgrades]]
+      build_ticks = 1
+
+      [[buildings.upgrades.ticked]]
+      every_n_ticks = 0
+
+      [[buildings.upgrades.actions]]
+      id = "work"
+      name = "Work"
+      cooldown = 1
+
+      [[buildings.upgrades.actions]]
+      id = "work"
+      name = "Work Harder"
+      cooldown = 1
+
+      [[buildings]]
+      name = "Headquarters"
+      size = [1, 1]
+      priority = 0
+
+      [buildings.placement]
+      on_water = false
+      on_mineral = false
+      "#;
+
+    let mut names = HashSet::from(["Headquarters".to_string()]);
+    let Err(errors) = parse_definition_file(Path::new("factory.toml"), contents, &mut names) else {
+      panic!("Expected the definitions to be invalid");
+    };
+    let problems = errors
+      .iter()
+      .map(|err| (err.line, format!("{:?}", err.problem)))
+      .collect::<Vec<_>>();
+    assert_eq!(
+      problems,
+      vec![
+        (
+          Some(4),
+          format!(
+            "{:?}",
+            BuildingDefinitionProblem::InvalidSize {
+              building: "Factory".to_string(),
+              size: [0, 1]
+            }
+          )
+        ),
+        (
+          Some(12),
+          format!(
+            "{:?}",
+            BuildingDefinitionProblem::ZeroTickInterval {
+              building: "Factory".to_string()
+            }
+          )
+        ),
+        (
+          Some(20),
+          format!(
+            "{:?}",
+            BuildingDefinitionProblem::DuplicateAction {
+              building: "Factory".to_string(),
+              action: "work".to_string()
+            }
+          )
+        ),
+        (
+          Some(28),
+          format!(
+            "{:?}",
+            BuildingDefinitionProblem::ZeroTickInterval {
+              building: "Factory".to_string()
+            }
+          )
+        ),
+        (
+          Some(36),
+          format!(
+            "{:?}",
+            BuildingDefinitionProblem::DuplicateAction {
+              building: "Factory".to_string(),
+              action: "work".to_string()
+            }
+          )
+        ),
+        (
+          Some(41),
+          format!(
+            "{:?}",
+            BuildingDefinitionProblem::DuplicateBuilding("Headquarters".to_string())
+          )
+        ),
+      ]
+    );
+    assert_eq!(
+      errors[0].to_string(),
+      "factory.toml:4: building Factory has invalid size [0, 1]"
+    );
+  }
+
+  #[test]
+  fn reports_unknown_resources() {
+    let contents = r#"
+      [[buildings]]
+      name = "Goldsmith"
+      size = [1, 1]
+      priority = 0
+
+      [buildings.placement]
+      on_water = false
+      on_mineral = false
+
+      [[buildings.ticked]]
+      every_n_ticks = 1
+
+      [[buildings.ticked.products]]
+      resource = "Gold"
+      value = 1
+      "#;
+
+    let Err(errors) = parse_definition_file(Path::new("goldsmith.toml"), contents, &mut HashSet::new()) else {
+      panic!("Expected the definitions to be invalid");
+    };
+    assert_eq!(errors.len(), 1);
+    assert!(matches!(errors[0].problem, BuildingDefinitionProblem::Parse(_)));
+    // Reported at the table holding the unknown resource.
+    assert_eq!(errors[0].line, Some(14));
+  }
+}
@@ -0,0 +1,526 @@
+//! Market
+//!
+//! Users post limit orders to buy or sell resources for Credits. The offered
+//! side of an order is held in escrow until the order is filled or cancelled.
+//! Once per tick, the highest bids are matched against the lowest asks of each
+//! resource, at the price of whichever order was placed first. Orders may be
+//! partially filled, staying on the book with their remaining quantity.
+
+use std::cmp::Reverse;
+
+use bevy::prelude::*;
+use chrono::{NaiveDateTime, Utc};
+use hashbrown::{HashMap, HashSet};
+use itertools::Itertools;
+use uuid::Uuid;
+
+use super::action::{ActionOutcome, ActionRejection, ActionResult, GameAction, UserGameAction};
+use super::resources::{Resource, ResourceDelta};
+use super::stages::GameStage;
+use super::user::UserResourceTable;
+
+/// Whether an order buys or sells its resource.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum OrderSide {
+  Buy,
+  Sell,
+}
+
+impl OrderSide {
+  /// Name of the side, as stored in the database.
+  pub fn name(&self) -> String {
+    format!("{:?}", self)
+  }
+
+  /// Parses a side from its [OrderSide::name].
+  pub fn from_name(name: &str) -> Option<Self> {
+    match name {
+      "Buy" => Some(Self::Buy),
+      "Sell" => Some(Self::Sell),
+      _ => None,
+    }
+  }
+}
+
+/// A limit order on the market.
+#[derive(Component, Debug, Clone, PartialEq)]
+pub struct MarketOrder {
+  /// Persistent identifier of the order.
+  pub id: Uuid,
+  pub owner: Uuid,
+  pub resource: Resource,
+  pub side: OrderSide,
+  /// Highest price paid, or lowest price accepted, in Credits per unit.
+  pub price: i64,
+  /// Quantity left to fill.
+  pub quantity: i64,
+  pub placed_at: NaiveDateTime,
+}
+
+impl MarketOrder {
+  /// Checks that the order trades a positive quantity of a storable resource
+  /// at a positive price.
+  fn validate(&self) -> Result<(), ActionRejection> {
+    let tradeable = !matches!(self.resource, Resource::Credit | Resource::Watt);
+    if !tradeable || self.price <= 0 || self.quantity <= 0 || self.price.checked_mul(self.quantity).is_none() {
+      return Err(ActionRejection::InvalidOrder);
+    }
+
+    Ok(())
+  }
+
+  /// Resources held by the market for the remaining quantity of the order.
+  pub fn escrow(&self) -> ResourceDelta {
+    match self.side {
+      OrderSide::Buy => Resource::Credit.d(self.price * self.quantity),
+      OrderSide::Sell => self.resource.d(self.quantity),
+    }
+  }
+}
+
+/// Sent for every fill between two orders.
+#[derive(Debug, Clone, PartialEq)]
+pub struct MarketTrade {
+  pub id: Uuid,
+  pub resource: Resource,
+  pub price: i64,
+  pub quantity: i64,
+  pub buyer: Uuid,
+  pub seller: Uuid,
+  pub traded_at: NaiveDateTime,
+}
+
+/// Total quantity of the orders at a price.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct PriceLevel {
+  pub price: i64,
+  pub quantity: i64,
+}
+
+/// Open orders of a resource, grouped by price from the best price down.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct MarketDepth {
+  pub bids: Vec<PriceLevel>,
+  pub asks: Vec<PriceLevel>,
+  /// Price of the last trade of the resource, if it was ever traded.
+  pub last_price: Option<i64>,
+}
+
+/// Order book depth and last traded price of every resource, as of the last
+/// tick.
+#[derive(Resource, Default)]
+pub struct Market {
+  depth: HashMap<Resource, MarketDepth>,
+  last_prices: HashMap<Resource, i64>,
+}
+
+impl Market {
+  pub fn new(last_prices: HashMap<Resource, i64>) -> Self {
+    Self {
+      depth: HashMap::new(),
+      last_prices,
+    }
+  }
+
+  pub fn depth(&self, resource: Resource) -> MarketDepth {
+    self.depth.get(&resource).cloned().unwrap_or_else(|| MarketDepth {
+      last_price: self.last_prices.get(&resource).copied(),
+      ..Default::default()
+    })
+  }
+}
+
+/// Entity of every open [MarketOrder] by its id.
+#[derive(Resource, Default)]
+pub struct OrderIndex {
+  entities: HashMap<Uuid, Entity>,
+  ids: HashMap<Entity, Uuid>,
+}
+
+impl OrderIndex {
+  pub fn entity(&self, id: Uuid) -> Option<Entity> {
+    self.entities.get(&id).copied()
+  }
+
+  /// Id of the order entity, kept until the end of the frame in which the
+  /// order is filled or cancelled.
+  pub fn id(&self, ent: Entity) -> Option<Uuid> {
+    self.ids.get(&ent).copied()
+  }
+
+  fn insert(&mut self, id: Uuid, ent: Entity) {
+    self.entities.insert(id, ent);
+    self.ids.insert(ent, id);
+  }
+
+  fn remove(&mut self, ent: Entity) {
+    if let Some(id) = self.ids.remove(&ent) {
+      self.entities.remove(&id);
+    }
+  }
+}
+
+fn index_market_orders(
+  mut index: ResMut<OrderIndex>,
+  placed: Query<(Entity, &MarketOrder), Added<MarketOrder>>,
+  removed: RemovedComponents<MarketOrder>,
+) {
+  removed.iter().for_each(|ent| index.remove(ent));
+  placed.for_each(|(ent, order)| index.insert(order.id, ent));
+}
+
+fn place_order(commands: &mut Commands, user_table: &mut UserResourceTable, order: MarketOrder) -> ActionResult {
+  order.validate()?;
+
+  let user = user_table.get_mut(&order.owner).ok_or(ActionRejection::UnknownUser)?;
+  if !user.pay_resource_transaction(vec![order.escrow()]) {
+    return Err(ActionRejection::InsufficientResources);
+  }
+
+  Ok(commands.spawn(order).id())
+}
+
+pub fn process_market_actions(
+  mut commands: Commands,
+  mut events: EventReader<UserGameAction>,
+  mut outcomes: EventWriter<ActionOutcome>,
+  mut user_table: ResMut<UserResourceTable>,
+  index: Res<OrderIndex>,
+  orders: Query<&MarketOrder>,
+) {
+  // Cancelled orders are only despawned at the end of the stage.
+  let mut cancelled = HashSet::new();
+
+  events.iter().for_each(|user_game_action| {
+    let UserGameAction { user_id, ticket, .. } = *user_game_action;
+
+    let result = match &user_game_action.action {
+      GameAction::PlaceOrder {
+        resource,
+        side,
+        price,
+        quantity,
+      } => {
+        let order = MarketOrder {
+          id: Uuid::new_v4(),
+          owner: user_id,
+          resource: *resource,
+          side: *side,
+          price: *price,
+          quantity: *quantity,
+          placed_at: Utc::now().naive_utc(),
+        };
+        place_order(&mut commands, &mut user_table, order)
+      },
+      GameAction::CancelOrder { order } => {
+        let open = index.entity(*order).filter(|ent| !cancelled.contains(ent));
+        match open.and_then(|ent| Some((ent, orders.get(ent).ok()?))) {
+          Some((_, order)) if order.owner != user_id => Err(ActionRejection::NotOwner),
+          Some((ent, order)) => {
+            if let Some(user) = user_table.get_mut(&user_id) {
+              user.return_resources(&order.escrow());
+            }
+            cancelled.insert(ent);
+            commands.entity(ent).despawn();
+            Ok(ent)
+          },
+          None => Err(ActionRejection::UnknownEntity),
+        }
+      },
+      // Handled by process_game_actions and process_trade_actions.
+      GameAction::BuildBuilding { .. }
+      | GameAction::PerformBuildingAction { .. }
+      | GameAction::DemolishBuilding { .. }
+      | GameAction::MoveBuilding { .. }
+      | GameAction::UpgradeBuilding { .. }
+      | GameAction::ProposeTrade { .. }
+      | GameAction::AcceptTrade { .. }
+      | GameAction::CancelTrade { .. } => return,
+    };
+
+    outcomes.send(ActionOutcome {
+      user_id,
+      ticket,
+      result,
+    });
+  });
+}
+
+/// Groups orders by price, from the best price for the other side down.
+fn price_levels<'a>(orders: impl Iterator<Item = &'a MarketOrder>) -> Vec<PriceLevel> {
+  orders
+    .filter(|order| order.quantity > 0)
+    .group_by(|order| order.price)
+    .into_iter()
+    .map(|(price, orders)| PriceLevel {
+      price,
+      quantity: orders.map(|order| order.quantity).sum(),
+    })
+    .collect()
+}
+
+/// Matches crossing orders of every resource, then updates the [Market].
+fn match_orders(
+  mut commands: Commands,
+  mut user_table: ResMut<UserResourceTable>,
+  mut market: ResMut<Market>,
+  mut trades: EventWriter<MarketTrade>,
+  mut query: Query<(Entity, &mut MarketOrder)>,
+) {
+  let mut orders = query.iter_mut().collect::<Vec<_>>();
+  let mut depth = HashMap::new();
+
+  Resource::ALL.into_iter().for_each(|resource| {
+    let book = |side| {
+      (0..orders.len())
+        .filter(|index| orders[*index].1.resource == resource && orders[*index].1.side == side)
+        .collect::<Vec<_>>()
+    };
+    let mut bids = book(OrderSide::Buy);
+    let mut asks = book(OrderSide::Sell);
+    bids.sort_by_key(|index| (Reverse(orders[*index].1.price), orders[*index].1.placed_at));
+    asks.sort_by_key(|index| (orders[*index].1.price, orders[*index].1.placed_at));
+
+    let (mut bid_index, mut ask_index) = (0, 0);
+    while bid_index < bids.len() && ask_index < asks.len() {
+      let (bid, ask) = (orders[bids[bid_index]].1.clone(), orders[asks[ask_index]].1.clone());
+      if bid.price < ask.price {
+        break;
+      }
+
+      // The order that was on the book first sets the price.
+      let quantity = bid.quantity.min(ask.quantity);
+      let price = if bid.placed_at <= ask.placed_at {
+        bid.price
+      } else {
+        ask.price
+      };
+
+      // Both sides were paid for from escrow, so they are received in full
+      // regardless of storage capacity.
+      if let Some(buyer) = user_table.get_mut(&bid.owner) {
+        buyer.return_resources(&resource.d(quantity));
+        buyer.return_resources(&Resource::Credit.d((bid.price - price) * quantity));
+      }
+      if let Some(seller) = user_table.get_mut(&ask.owner) {
+        seller.return_resources(&Resource::Credit.d(price * quantity));
+      }
+
+      trades.send(MarketTrade {
+        id: Uuid::new_v4(),
+        resource,
+        price,
+        quantity,
+        buyer: bid.owner,
+        seller: ask.owner,
+        traded_at: Utc::now().naive_utc(),
+      });
+      market.last_prices.insert(resource, price);
+
+      for (book, index) in [(&bids, &mut bid_index), (&asks, &mut ask_index)] {
+        let (ent, order) = &mut orders[book[*index]];
+        order.quantity -= quantity;
+        if order.quantity == 0 {
+          commands.entity(*ent).despawn();
+          *index += 1;
+        }
+      }
+    }
+
+    depth.insert(
+      resource,
+      MarketDepth {
+        bids: price_levels(bids.iter().map(|index| &*orders[*index].1)),
+        asks: price_levels(asks.iter().map(|index| &*orders[*index].1)),
+        last_price: market.last_prices.get(&resource).copied(),
+      },
+    );
+  });
+
+  market.depth = depth;
+}
+
+pub struct MarketPlugin;
+
+impl Plugin for MarketPlugin {
+  fn build(&self, app: &mut App) {
+    info!("Loading Market...");
+    app
+      .init_resource::<Market>()
+      .init_resource::<OrderIndex>()
+      .add_event::<MarketTrade>()
+      .add_system_to_stage(GameStage::Start, process_market_actions)
+      .add_system_to_stage(GameStage::OnResourcesPaid, match_orders)
+      .add_system_to_stage(CoreStage::PostUpdate, index_market_orders);
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use bevy::prelude::*;
+  use hashbrown::HashMap;
+  use uuid::Uuid;
+
+  use super::{Market, MarketDepth, MarketOrder, MarketPlugin, MarketTrade, OrderSide, PriceLevel};
+  use crate::db::models::User;
+  use crate::game::action::{ActionOutcome, ActionRejection, ActionResult, GameAction, UserGameAction};
+  use crate::game::resources::Resource;
+  use crate::game::stages::StagePlugin;
+  use crate::game::user::UserResourceTable;
+
+  fn build_app(users: Vec<User>) -> App {
+    let mut app = App::new();
+    app
+      .add_plugins(MinimalPlugins)
+      .add_plugin(StagePlugin)
+      .add_plugin(MarketPlugin)
+      .add_event::<UserGameAction>()
+      .add_event::<ActionOutcome>()
+      .insert_resource(UserResourceTable::new(
+        users.into_iter().map(|user| (user.id, user)).collect::<HashMap<_, _>>(),
+      ));
+    app
+  }
+
+  fn send(app: &mut App, user_id: Uuid, action: GameAction) -> ActionResult {
+    app.world.send_event(UserGameAction {
+      user_id,
+      action,
+      ticket: None,
+    });
+    app.update();
+
+    let mut outcomes = app.world.resource_mut::<Events<ActionOutcome>>();
+    outcomes.drain().next().unwrap().result
+  }
+
+  fn order(resource: Resource, side: OrderSide, price: i64, quantity: i64) -> GameAction {
+    GameAction::PlaceOrder {
+      resource,
+      side,
+      price,
+      quantity,
+    }
+  }
+
+  fn order_id(app: &App, ent: Entity) -> Uuid {
+    app.world.get::<MarketOrder>(ent).unwrap().id
+  }
+
+  fn balance(app: &App, id: Uuid) -> (i64, i64) {
+    let user = &app.world.resource::<UserResourceTable>()[&id];
+    (user.get(Resource::Credit), user.get(Resource::Iron))
+  }
+
+  #[test]
+  fn place_and_cancel_orders() {
+    let (alice, bob) = (Uuid::new_v4(), Uuid::new_v4());
+    let mut app = build_app(vec![
+      User::with_resources(alice, [(Resource::Credit, 50)]),
+      User::with_resources(bob, []),
+    ]);
+
+    for invalid in [
+      order(Resource::Credit, OrderSide::Buy, 1, 1),
+      order(Resource::Watt, OrderSide::Sell, 1, 1),
+      order(Resource::Iron, OrderSide::Buy, 0, 1),
+      order(Resource::Iron, OrderSide::Buy, 1, -1),
+      order(Resource::Iron, OrderSide::Buy, i64::MAX, 2),
+    ] {
+      assert_eq!(send(&mut app, alice, invalid), Err(ActionRejection::InvalidOrder));
+    }
+    assert_eq!(
+      send(&mut app, alice, order(Resource::Iron, OrderSide::Buy, 6, 10)),
+      Err(ActionRejection::InsufficientResources)
+    );
+
+    // The price of the whole order is held in escrow.
+    let bid = send(&mut app, alice, order(Resource::Iron, OrderSide::Buy, 4, 10)).unwrap();
+    assert_eq!(balance(&app, alice), (10, 0));
+    assert_eq!(
+      app.world.resource::<Market>().depth(Resource::Iron).bids,
+      vec![PriceLevel { price: 4, quantity: 10 }]
+    );
+
+    let bid_id = order_id(&app, bid);
+    let cancel = || GameAction::CancelOrder { order: bid_id };
+    assert_eq!(send(&mut app, bob, cancel()), Err(ActionRejection::NotOwner));
+    assert_eq!(send(&mut app, alice, cancel()), Ok(bid));
+    assert_eq!(balance(&app, alice), (50, 0));
+    assert_eq!(send(&mut app, alice, cancel()), Err(ActionRejection::UnknownEntity));
+    assert_eq!(
+      app.world.resource::<Market>().depth(Resource::Iron),
+      MarketDepth::default()
+    );
+
+    // The next order reuses the entity of the cancelled one, but is still
+    // addressed by its own id.
+    let next = send(&mut app, alice, order(Resource::Iron, OrderSide::Buy, 4, 10)).unwrap();
+    assert_eq!(next.index(), bid.index());
+    assert_eq!(send(&mut app, alice, cancel()), Err(ActionRejection::UnknownEntity));
+    let cancel_next = GameAction::CancelOrder {
+      order: order_id(&app, next),
+    };
+    assert_eq!(send(&mut app, alice, cancel_next), Ok(next));
+    assert_eq!(balance(&app, alice), (50, 0));
+  }
+
+  #[test]
+  fn matches_crossing_orders() {
+    let (alice, bob, carol) = (Uuid::new_v4(), Uuid::new_v4(), Uuid::new_v4());
+    let mut app = build_app(vec![
+      User::with_resources(alice, [(Resource::Iron, 10)]),
+      User::with_resources(bob, [(Resource::Credit, 28)]),
+      User::with_resources(carol, [(Resource::Credit, 40)]),
+    ]);
+
+    let ask = send(&mut app, alice, order(Resource::Iron, OrderSide::Sell, 5, 10)).unwrap();
+    assert_eq!(balance(&app, alice), (0, 0));
+
+    // The bid crosses the older ask, and is filled at the price of the ask.
+    send(&mut app, bob, order(Resource::Iron, OrderSide::Buy, 7, 4)).unwrap();
+    let trades = app
+      .world
+      .resource::<Events<MarketTrade>>()
+      .iter_current_update_events()
+      .map(|trade| (trade.price, trade.quantity, trade.buyer, trade.seller))
+      .collect::<Vec<_>>();
+    assert_eq!(trades, vec![(5, 4, bob, alice)]);
+    assert_eq!(balance(&app, alice), (20, 0));
+    assert_eq!(balance(&app, bob), (8, 4));
+
+    // The ask stays on the book with its remaining quantity.
+    send(&mut app, carol, order(Resource::Iron, OrderSide::Buy, 4, 10)).unwrap();
+    assert_eq!(
+      app.world.resource::<Market>().depth(Resource::Iron),
+      MarketDepth {
+        bids: vec![PriceLevel { price: 4, quantity: 10 }],
+        asks: vec![PriceLevel { price: 5, quantity: 6 }],
+        last_price: Some(5),
+      }
+    );
+
+    let cancel = GameAction::CancelOrder {
+      order: order_id(&app, ask),
+    };
+    assert_eq!(send(&mut app, alice, cancel), Ok(ask));
+    assert_eq!(balance(&app, alice), (20, 6));
+  }
+
+  #[test]
+  fn fills_ignore_storage_capacity() {
+    let (alice, bob) = (Uuid::new_v4(), Uuid::new_v4());
+    let mut app = build_app(vec![
+      User::with_resources(alice, [(Resource::Iron, 10)]),
+      User::with_resources(bob, [(Resource::Credit, 50)]),
+    ]);
+    let mut user_table = app.world.resource_mut::<UserResourceTable>();
+    user_table.set_capacity(alice, HashMap::from([(Resource::Credit, 10)]));
+    user_table.set_capacity(bob, HashMap::from([(Resource::Iron, 5)]));
+
+    send(&mut app, alice, order(Resource::Iron, OrderSide::Sell, 5, 10)).unwrap();
+    send(&mut app, bob, order(Resource::Iron, OrderSide::Buy, 5, 10)).unwrap();
+    assert_eq!(balance(&app, alice), (50, 0));
+    assert_eq!(balance(&app, bob), (0, 10));
+  }
+}
@@ -0,0 +1,218 @@
+//! Mining
+//!
+//! Production lines with a `[buildings.ticked.mining]` definition extract
+//! resources from the deposits underneath their building whenever their costs
+//! are paid.
+
+use bevy::prelude::*;
+use serde::Deserialize;
+
+use super::building::{building_tiles, Building};
+use super::resources::{Resource, ResourceWasted, TickedResourceCost};
+use super::stages::GameStage;
+use super::tick::Ticked;
+use super::user::{UserOwned, UserResourceTable};
+use super::world::{Terrain, TerrainTile};
+
+#[derive(Deserialize, Clone, PartialEq)]
+pub struct BuildingMiningDefinition {
+  /// Resource of the deposits mined, such as `Copper`.
+  pub deposit: Resource,
+  /// Amount extracted every time the building is ticked.
+  pub amount: u32,
+}
+
+/// Represents a production line that mines the deposits its building is built
+/// on.
+#[derive(Component, Clone)]
+pub struct BuildingMiner(pub BuildingMiningDefinition);
+
+/// Extracts up to `amount` of the deposit from the given tiles, returning the
+/// amount extracted.
+pub fn mine_tiles(
+  terrain: &mut Terrain,
+  tiles: impl Iterator<Item = IVec2>,
+  deposit: Resource,
+  mut amount: u32,
+) -> u32 {
+  let mut extracted = 0;
+
+  for tile in tiles {
+    if amount == 0 {
+      break;
+    }
+
+    let position = [tile.x as i64, tile.y as i64];
+    if let TerrainTile::Complex(complex_tile) = terrain.get_tile(position)
+      && complex_tile.resource() == deposit
+    {
+      let (mined, remaining) = complex_tile.extract(amount);
+      terrain.set_tile(position, remaining);
+      amount -= mined;
+      extracted += mined;
+    }
+  }
+
+  extracted
+}
+
+fn mine_deposits(
+  mut terrain: Terrain,
+  mut user_table: ResMut<UserResourceTable>,
+  mut wasted: EventWriter<ResourceWasted>,
+  miners: Query<(&BuildingMiner, &Ticked, &TickedResourceCost, &UserOwned, &Parent)>,
+  buildings: Query<&Transform, With<Building>>,
+) {
+  miners.for_each(|(miner, ticked, cost, owner, parent)| {
+    if !cost.paid() {
+      return;
+    }
+
+    let Ok(transform) = buildings.get(parent.get()) else {
+      return;
+    };
+
+    let BuildingMiningDefinition { deposit, amount } = miner.0;
+    let tiles = building_tiles(
+      transform.translation.truncate().as_ivec2(),
+      transform.scale.truncate().as_ivec2(),
+    );
+    let extracted = mine_tiles(&mut terrain, tiles, deposit, amount * ticked.fire_count());
+
+    if extracted > 0 {
+      let amount = user_table.give_resources(&owner.0, &deposit.d(extracted as i64));
+      if amount > 0 {
+        wasted.send(ResourceWasted {
+          user_id: owner.0,
+          resource: deposit,
+          amount,
+        });
+      }
+    }
+  });
+}
+
+pub struct MiningPlugin;
+
+impl Plugin for MiningPlugin {
+  fn build(&self, app: &mut App) {
+    info!("Loading Mining...");
+    app.add_system_to_stage(GameStage::OnResourcesPaid, mine_deposits);
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use bevy::ecs::system::{CommandQueue, SystemState};
+  use bevy::prelude::*;
+  use chrono::NaiveDateTime;
+  use hashbrown::HashMap;
+  use uuid::Uuid;
+
+  use super::MiningPlugin;
+  use crate::db::models::{User, World, WorldObj};
+  use crate::db::DatabaseManager;
+  use crate::game::building::{BuildingDefinitionTable, BuildingPlugin, BuildingSequence};
+  use crate::game::resources::{Resource, ResourcePlugin};
+  use crate::game::stages::StagePlugin;
+  use crate::game::tick::TickPlugin;
+  use crate::game::user::UserResourceTable;
+  use crate::game::world::{
+    ComplexTerrainTile, LoadedChunkTable, StaticTerrainTile, Terrain, TerrainTile, WorldGenPlugin,
+  };
+  use crate::properties::GameProperties;
+
+  #[test]
+  fn extract_depletes_deposit() {
+    assert_eq!(
+      ComplexTerrainTile::Iron(10).extract(4),
+      (4, TerrainTile::Complex(ComplexTerrainTile::Iron(6)))
+    );
+    assert_eq!(
+      ComplexTerrainTile::Coal(3).extract(4),
+      (3, TerrainTile::Static(StaticTerrainTile::Stone))
+    );
+  }
+
+  #[test]
+  fn mine_copper() {
+    let mut app = App::new();
+    app
+      .add_plugins(MinimalPlugins)
+      .add_plugin(StagePlugin)
+      .insert_resource(DatabaseManager::test_harness())
+      .insert_resource::<World>(
+        WorldObj {
+          id: 0,
+          origin_time: NaiveDateTime::MIN,
+          seed: 1337,
+        }
+        .into(),
+      )
+      .add_plugin(WorldGenPlugin)
+      .add_plugin(TickPlugin)
+      .add_plugin(ResourcePlugin)
+      .add_plugin(BuildingPlugin)
+      .add_plugin(MiningPlugin)
+      .init_resource::<GameProperties>();
+
+    let id = Uuid::new_v4();
+    app.world.insert_resource(UserResourceTable::new(HashMap::from([(
+      id,
+      User {
+        id,
+        ..Default::default()
+      },
+    )])));
+
+    // Copper deposit at (8, 32), shrunk so it depletes quickly.
+    let building_table = BuildingDefinitionTable::load();
+    let copper_mine = &building_table["Copper Mine"];
+    let deposit = [8, 32];
+    let chunk_index = World::get_chunk_and_index_from_tile_position(deposit);
+    let every_n_ticks = copper_mine.ticked.as_ref().unwrap()[0].every_n_ticks;
+    let mining = copper_mine.ticked.as_ref().unwrap()[0].mining.clone().unwrap();
+
+    let mut queue = CommandQueue::default();
+    let commands = &mut Commands::new(&mut queue, &app.world);
+    copper_mine.spawn(commands, BuildingSequence(0), id, IVec2::new(8, 32));
+    queue.apply(&mut app.world);
+
+    let mut terrain_state: SystemState<Terrain> = SystemState::new(&mut app.world);
+    terrain_state.get_mut(&mut app.world).set_tile(
+      deposit,
+      TerrainTile::Complex(ComplexTerrainTile::Copper(mining.amount + 1)),
+    );
+    app.world.resource_mut::<LoadedChunkTable>().take_modified();
+
+    for _ in 0..every_n_ticks {
+      app.update();
+    }
+
+    let user_table: &UserResourceTable = app.world.get_resource().unwrap();
+    assert_eq!(user_table.get(&id).unwrap().get(Resource::Copper), mining.amount as i64);
+
+    let chunk_table = app.world.resource::<LoadedChunkTable>();
+    assert_eq!(
+      chunk_table.get_if_exists(chunk_index.0).unwrap().chunk[chunk_index.1],
+      TerrainTile::Complex(ComplexTerrainTile::Copper(1))
+    );
+
+    for _ in 0..every_n_ticks {
+      app.update();
+    }
+
+    let user_table: &UserResourceTable = app.world.get_resource().unwrap();
+    assert_eq!(
+      user_table.get(&id).unwrap().get(Resource::Copper),
+      mining.amount as i64 + 1
+    );
+
+    let mut chunk_table = app.world.resource_mut::<LoadedChunkTable>();
+    assert_eq!(
+      chunk_table.get_if_exists(chunk_index.0).unwrap().chunk[chunk_index.1],
+      TerrainTile::Static(StaticTerrainTile::Stone)
+    );
+    assert_eq!(chunk_table.take_modified(), vec![chunk_index.0]);
+  }
+}
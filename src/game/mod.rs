@@ -4,17 +4,33 @@ use bevy::app::PluginGroupBuilder;
 use bevy::prelude::*;
 use world::WorldPlugin;
 
+use self::action::GameActionPlugin;
 use self::building::BuildingPlugin;
+use self::definitions::BuildingDefinitionAssetPlugin;
+use self::market::MarketPlugin;
+use self::mining::MiningPlugin;
+use self::persistence::PersistencePlugin;
+use self::power::PowerPlugin;
 use self::resources::ResourcePlugin;
 use self::stages::StagePlugin;
+use self::storage::StoragePlugin;
 use self::tick::TickPlugin;
+use self::trade::TradePlugin;
 use self::user::UserPlugin;
 
 pub mod action;
 pub mod building;
+pub mod definitions;
+pub mod market;
+pub mod mining;
+pub mod onboarding;
+pub mod persistence;
+pub mod power;
 pub mod resources;
 pub mod stages;
+pub mod storage;
 pub mod tick;
+pub mod trade;
 pub mod user;
 pub mod world;
 
@@ -28,6 +44,14 @@ impl PluginGroup for GamePlugins {
       .add(ResourcePlugin)
       .add(TickPlugin)
       .add(BuildingPlugin)
+      .add(BuildingDefinitionAssetPlugin)
+      .add(MiningPlugin)
+      .add(PowerPlugin)
+      .add(StoragePlugin)
+      .add(GameActionPlugin)
+      .add(TradePlugin)
+      .add(MarketPlugin)
       .add(UserPlugin)
+      .add(PersistencePlugin)
   }
 }
@@ -0,0 +1,261 @@
+//! New User Onboarding
+//!
+//! Users join the game with nothing. As soon as they register, they are given
+//! a starter Headquarters on the closest free land to one of the
+//! `spawn_zones`, spread evenly on a ring around the world origin, along with
+//! the `starting_resources` bundle. Both are saved along with the user by the
+//! next autosave, so a crash before then loses the whole spawn.
+
+use std::f32::consts::TAU;
+
+use bevy::ecs::system::SystemParam;
+use bevy::prelude::*;
+use uuid::Uuid;
+
+use super::building::{building_tiles, BuildingDefinitionTable, BuildingPlacement};
+use super::user::UserResourceTable;
+use crate::properties::GameProperties;
+
+/// Building every new user starts with.
+pub const STARTER_BUILDING: &str = "Headquarters";
+
+/// Centers of every spawn zone, starting from the given zone and wrapping
+/// around the ring, or only the world origin without any zones.
+pub fn spawn_zone_centers(zones: u32, radius: u32, first: usize) -> Vec<IVec2> {
+  if zones == 0 {
+    return vec![IVec2::ZERO];
+  }
+
+  (0..zones as usize)
+    .map(|zone| (first + zone) % zones as usize)
+    .map(|zone| {
+      let angle = TAU * zone as f32 / zones as f32;
+      (Vec2::new(angle.cos(), angle.sin()) * radius as f32).round().as_ivec2()
+    })
+    .collect()
+}
+
+/// Positions within `radius` of the center, closest first. Positions are
+/// `spacing` apart so that each one covers new ground.
+pub fn spawn_candidates(center: IVec2, radius: u32, spacing: IVec2) -> Vec<IVec2> {
+  let steps = IVec2::splat(radius as i32) / spacing;
+  let mut candidates = building_tiles(-steps, steps * 2 + 1)
+    .map(|step| center + step * spacing)
+    .collect::<Vec<_>>();
+  candidates.sort_by_key(|position| ((*position - center) / spacing).abs().max_element());
+  candidates
+}
+
+/// System parameter to spawn users that just joined.
+#[derive(SystemParam)]
+pub struct Onboarding<'w, 's> {
+  commands: Commands<'w, 's>,
+  placement: BuildingPlacement<'w, 's>,
+  building_table: Res<'w, BuildingDefinitionTable>,
+  properties: Res<'w, GameProperties>,
+}
+
+impl<'w, 's> Onboarding<'w, 's> {
+  /// Grants the starting resources to a user that just joined, and places
+  /// their starter building.
+  pub fn spawn(&mut self, user_table: &mut UserResourceTable, user_id: Uuid) {
+    if !user_table.contains_key(&user_id) {
+      warn!("Cannot spawn unknown user {}", user_id);
+      return;
+    }
+    // Users are spread across the zones in the order they joined. Every saved
+    // user is loaded on startup, so the count carries over restarts, and the
+    // table already holds the joining user.
+    let first_zone = user_table.len() - 1;
+
+    self.properties.starting_resources.iter().for_each(|delta| {
+      user_table.give_resources(&user_id, &delta.as_product());
+    });
+
+    let Some(building_def) = self.building_table.get(STARTER_BUILDING) else {
+      warn!(
+        "No {} definition, user {} starts without one",
+        STARTER_BUILDING, user_id
+      );
+      return;
+    };
+
+    // Each check may load a chunk, so only a few positions are tried around
+    // each zone.
+    let properties = &self.properties;
+    let spacing = IVec2::from(building_def.size).max(IVec2::ONE);
+    let position = spawn_zone_centers(properties.spawn_zones, properties.spawn_ring_radius, first_zone)
+      .into_iter()
+      .flat_map(|center| {
+        spawn_candidates(center, properties.spawn_search_radius, spacing)
+          .into_iter()
+          .take(properties.spawn_search_attempts as usize)
+      })
+      .find(|position| self.placement.check(building_def, *position).is_ok());
+
+    match position {
+      Some(position) => {
+        let sequence = self.placement.next_sequence();
+        let ent = building_def.spawn(&mut self.commands, sequence, user_id, position);
+        self.placement.occupy(ent, building_def, position);
+        info!("Spawned user {} at {}", user_id, position);
+      },
+      None => warn!("No free spawn location left for user {}", user_id),
+    }
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use bevy::ecs::system::SystemState;
+  use bevy::prelude::*;
+  use chrono::NaiveDateTime;
+  use hashbrown::HashMap;
+  use uuid::Uuid;
+
+  use super::{spawn_candidates, spawn_zone_centers, Onboarding};
+  use crate::db::models::{User, World, WorldObj};
+  use crate::db::DatabaseManager;
+  use crate::game::action::GameActionPlugin;
+  use crate::game::building::{building_tiles, Building, BuildingPlugin};
+  use crate::game::resources::{Resource, ResourcePlugin};
+  use crate::game::stages::StagePlugin;
+  use crate::game::tick::TickPlugin;
+  use crate::game::user::{UserOwned, UserResourceTable};
+  use crate::game::world::WorldGenPlugin;
+  use crate::properties::GameProperties;
+
+  #[test]
+  fn spawn_zones_are_spread_on_a_ring() {
+    assert_eq!(spawn_zone_centers(0, 100, 3), vec![IVec2::ZERO]);
+    assert_eq!(
+      spawn_zone_centers(4, 100, 1),
+      vec![
+        IVec2::new(0, 100),
+        IVec2::new(-100, 0),
+        IVec2::new(0, -100),
+        IVec2::new(100, 0)
+      ]
+    );
+
+    let center = IVec2::new(5, -3);
+    let candidates = spawn_candidates(center, 2, IVec2::ONE);
+    assert_eq!(candidates.len(), 25);
+    assert_eq!(candidates[0], center);
+    assert!(candidates[1..9]
+      .iter()
+      .all(|position| (*position - center).abs().max_element() == 1));
+
+    // Candidates for larger buildings don't overlap.
+    let candidates = spawn_candidates(center, 4, IVec2::new(2, 3));
+    assert_eq!(candidates.len(), 15);
+    assert_eq!(candidates[0], center);
+    assert!(candidates.contains(&IVec2::new(9, 0)));
+    assert!(candidates
+      .iter()
+      .all(|position| (*position - center).abs().cmple(IVec2::splat(4)).all()));
+  }
+
+  fn onboarding_app(properties: GameProperties, users: &[Uuid]) -> App {
+    let mut app = App::new();
+    app
+      .add_plugins(MinimalPlugins)
+      .add_plugin(StagePlugin)
+      .insert_resource(DatabaseManager::test_harness())
+      .insert_resource::<World>(
+        WorldObj {
+          id: 0,
+          origin_time: NaiveDateTime::MIN,
+          seed: 1337,
+        }
+        .into(),
+      )
+      .add_plugin(WorldGenPlugin)
+      .add_plugin(TickPlugin)
+      .add_plugin(ResourcePlugin)
+      .add_plugin(BuildingPlugin)
+      .add_plugin(GameActionPlugin)
+      .insert_resource(properties)
+      .insert_resource(UserResourceTable::new(
+        users
+          .iter()
+          .map(|user_id| (*user_id, User::with_resources(*user_id, [])))
+          .collect(),
+      ));
+    app
+  }
+
+  #[test]
+  fn spawns_new_users_with_a_headquarters() {
+    let (alice, bob) = (Uuid::new_v4(), Uuid::new_v4());
+    let properties = GameProperties {
+      spawn_zones: 0,
+      starting_resources: vec![Resource::Credit.d(200), Resource::Iron.d(5)],
+      ..Default::default()
+    };
+    let mut app = onboarding_app(properties, &[alice, bob]);
+
+    let mut state: SystemState<(ResMut<UserResourceTable>, Onboarding)> = SystemState::new(&mut app.world);
+    let (mut user_table, mut onboarding) = state.get_mut(&mut app.world);
+    [alice, bob, Uuid::new_v4()]
+      .into_iter()
+      .for_each(|user_id| onboarding.spawn(&mut user_table, user_id));
+    state.apply(&mut app.world);
+
+    let user_table = app.world.resource::<UserResourceTable>();
+    for user_id in [alice, bob] {
+      assert_eq!(user_table[&user_id].get(Resource::Credit), 200);
+      assert_eq!(user_table[&user_id].get(Resource::Iron), 5);
+    }
+
+    let mut query = app.world.query::<(&Building, &UserOwned, &Transform)>();
+    let spawned = query
+      .iter(&app.world)
+      .map(|(building, owner, transform)| {
+        assert_eq!(building.0, "Headquarters");
+        (owner.0, transform.translation.truncate().as_ivec2())
+      })
+      .collect::<HashMap<_, _>>();
+
+    assert_eq!(spawned.len(), 2);
+    assert!(spawned.values().all(|position| position.abs().max_element() <= 32));
+    // Each user gets their own spot.
+    let alice_tiles = building_tiles(spawned[&alice], IVec2::splat(2)).collect::<Vec<_>>();
+    assert!(building_tiles(spawned[&bob], IVec2::splat(2)).all(|tile| !alice_tiles.contains(&tile)));
+
+    // Users are spawned right away, and only once.
+    app.update();
+    assert_eq!(query.iter(&app.world).count(), 2);
+    assert_eq!(app.world.resource::<UserResourceTable>()[&alice].get(Resource::Iron), 5);
+  }
+
+  #[test]
+  fn spawns_users_in_the_zone_of_their_join_order() {
+    let (alice, bob) = (Uuid::new_v4(), Uuid::new_v4());
+    let properties = GameProperties {
+      spawn_zones: 4,
+      spawn_ring_radius: 100,
+      spawn_search_radius: 8,
+      ..Default::default()
+    };
+    let mut app = onboarding_app(properties, &[alice]);
+
+    let mut state: SystemState<(ResMut<UserResourceTable>, Onboarding)> = SystemState::new(&mut app.world);
+    let (mut user_table, mut onboarding) = state.get_mut(&mut app.world);
+    onboarding.spawn(&mut user_table, alice);
+    user_table.insert(bob, User::with_resources(bob, []));
+    onboarding.spawn(&mut user_table, bob);
+    state.apply(&mut app.world);
+
+    let mut query = app.world.query::<(&UserOwned, &Transform)>();
+    let spawned = query
+      .iter(&app.world)
+      .map(|(owner, transform)| (owner.0, transform.translation.truncate().as_ivec2()))
+      .collect::<HashMap<_, _>>();
+
+    // The first user to join starts in the first zone, the next one in the
+    // second.
+    assert!((spawned[&alice] - IVec2::new(100, 0)).abs().max_element() <= 8);
+    assert!((spawned[&bob] - IVec2::new(0, 100)).abs().max_element() <= 8);
+  }
+}
@@ -0,0 +1,583 @@
+//! Game Persistence
+//!
+//! Writes the state of the game back to the database, and restores it on
+//! startup. The game is saved every `autosave_interval` ticks, and once more
+//! when the app exits, each time within a single transaction.
+
+use std::ops::Deref;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::Arc;
+
+use bevy::app::AppExit;
+use bevy::ecs::system::{CommandQueue, SystemParam};
+use bevy::prelude::*;
+use chrono::Utc;
+use diesel::{Connection, PgConnection};
+use hashbrown::HashMap;
+use itertools::Itertools;
+use uuid::Uuid;
+
+use super::building::{
+  Building, BuildingCooldown, BuildingDefinitionTable, BuildingId, BuildingLevel, BuildingSequence,
+  NextBuildingSequence, ProductionLine, UnderConstruction, Upgrading,
+};
+use super::market::{Market, MarketOrder, MarketTrade};
+use super::resources::ResourceDelta;
+use super::stages::GameStage;
+use super::tick::Ticked;
+use super::trade::TradeClosed;
+use super::user::{UserOwned, UserResourceTable};
+use super::world::LoadedChunkTable;
+use crate::db::models::{BuildingObj, Chunk, MarketOrderObj, MarketTradeObj, TradeObj, User};
+use crate::db::DatabaseManager;
+use crate::properties::GameProperties;
+
+/// Sent when the game should be saved to the database.
+pub struct Autosave;
+
+/// Label component for the [Ticked] entity that fires [Autosave].
+#[derive(Component)]
+pub struct AutosaveTimer;
+
+/// Set by the interrupt handler to request a graceful exit.
+#[derive(Resource)]
+pub struct ExitRequested(Arc<AtomicBool>);
+
+fn autosave_on_tick(timer: Query<&Ticked, With<AutosaveTimer>>, mut autosave: EventWriter<Autosave>) {
+  timer.for_each(|ticked| {
+    if ticked.fire_count() > 0 {
+      autosave.send(Autosave);
+    }
+  });
+}
+
+fn exit_on_interrupt(exit_requested: Res<ExitRequested>, mut exit: EventWriter<AppExit>) {
+  if exit_requested.0.swap(false, Ordering::Relaxed) {
+    info!("Interrupted, exiting...");
+    exit.send(AppExit);
+  }
+}
+
+fn autosave_on_exit(mut exit: EventReader<AppExit>, mut autosave: EventWriter<Autosave>) {
+  if exit.iter().last().is_some() {
+    autosave.send(Autosave);
+  }
+}
+
+/// Copy of every user as of their last successful save.
+#[derive(Default, Resource)]
+pub struct SavedUsers(HashMap<Uuid, User>);
+
+impl SavedUsers {
+  /// Returns all users that changed since they were last saved.
+  pub fn dirty(&self, user_table: &UserResourceTable) -> Vec<User> {
+    user_table
+      .values()
+      .filter(|user| self.0.get(&user.id) != Some(*user))
+      .cloned()
+      .collect()
+  }
+
+  pub fn mark_saved(&mut self, users: Vec<User>) {
+    users.into_iter().for_each(|user| {
+      self.0.insert(user.id, user);
+    });
+  }
+}
+
+/// Trades closed since the last autosave, saved alongside the balances they
+/// changed.
+#[derive(Default, Resource)]
+pub struct UnsavedTrades(Vec<TradeObj>);
+
+/// Splits a bundle of resources into the names and amounts stored in the
+/// trade history.
+fn bundle_record(bundle: &[ResourceDelta]) -> (Vec<Option<String>>, Vec<Option<i64>>) {
+  bundle
+    .iter()
+    .map(|delta| (Some(delta.resource.name()), Some(delta.value)))
+    .unzip()
+}
+
+fn trade_record(closed: &TradeClosed) -> TradeObj {
+  let (offered_resources, offered_amounts) = bundle_record(&closed.offer.offered);
+  let (requested_resources, requested_amounts) = bundle_record(&closed.offer.requested);
+
+  TradeObj {
+    id: closed.offer.id,
+    proposer: closed.offer.proposer,
+    recipient: closed.offer.recipient,
+    offered_resources,
+    offered_amounts,
+    requested_resources,
+    requested_amounts,
+    status: closed.status.name(),
+    closed_at: Utc::now().naive_utc(),
+  }
+}
+
+fn record_closed_trades(mut unsaved: ResMut<UnsavedTrades>, mut closed: EventReader<TradeClosed>) {
+  unsaved.0.extend(closed.iter().map(trade_record));
+}
+
+/// Market fills since the last autosave, saved alongside the balances they
+/// changed.
+#[derive(Default, Resource)]
+pub struct UnsavedMarketTrades(Vec<MarketTradeObj>);
+
+fn record_market_trades(mut unsaved: ResMut<UnsavedMarketTrades>, mut trades: EventReader<MarketTrade>) {
+  unsaved.0.extend(trades.iter().map(MarketTradeObj::from));
+}
+
+/// Respawns all open market orders and the last traded prices stored in the
+/// database.
+fn restore_market(world: &mut World) {
+  let (records, last_prices) = {
+    let conn = &mut world
+      .get_resource::<DatabaseManager>()
+      .expect("Failed to get DatabasePool. Ensure the DatabasePlugin is added before this plugin.")
+      .try_take()
+      .expect("Failed to get database connection from pool.");
+
+    (
+      MarketOrderObj::get_all(conn).expect("Failed to get all market orders, connection dead?"),
+      MarketTradeObj::last_prices(conn).expect("Failed to get the last market prices, connection dead?"),
+    )
+  };
+
+  let orders = records
+    .into_iter()
+    .filter_map(MarketOrderObj::parse)
+    .collect::<Vec<_>>();
+  info!("Restored {} market orders", orders.len());
+
+  world.spawn_batch(orders);
+  world.insert_resource(Market::new(last_prices));
+}
+
+/// Tracks the [BuildingId] of every building entity, so that their rows can
+/// be deleted by the next autosave once the entity is gone.
+#[derive(Default, Resource)]
+pub struct PersistedBuildings {
+  ids: HashMap<Entity, Uuid>,
+  removed: Vec<Uuid>,
+}
+
+impl PersistedBuildings {
+  pub fn contains(&self, ent: Entity) -> bool {
+    self.ids.contains_key(&ent)
+  }
+
+  /// Ids of the buildings despawned since the last autosave.
+  pub fn removed(&self) -> &[Uuid] {
+    &self.removed
+  }
+}
+
+type BuildingState<'a> = (
+  &'a BuildingId,
+  &'a BuildingSequence,
+  &'a Building,
+  &'a BuildingLevel,
+  &'a UserOwned,
+  &'a Transform,
+  Option<&'a BuildingCooldown>,
+  Option<&'a Children>,
+  Option<(&'a UnderConstruction, &'a Ticked)>,
+  Option<(&'a Upgrading, &'a Ticked)>,
+);
+
+type ProductionLineState<'a> = (&'a ProductionLine, &'a Ticked);
+
+fn building_record(
+  (id, sequence, building, level, owner, transform, cooldown, children, construction, upgrade): BuildingState,
+  lines: &Query<ProductionLineState>,
+) -> BuildingObj {
+  let tick_counters = children
+    .iter()
+    .flat_map(|children| children.iter())
+    .filter_map(|child| lines.get(*child).ok())
+    .sorted_by_key(|(line, _)| line.0)
+    .map(|(_, ticked)| Some(ticked.counter() as i32))
+    .collect();
+
+  BuildingObj {
+    id: id.0,
+    name: building.0.clone(),
+    owner: owner.0,
+    x: transform.translation.x as i32,
+    y: transform.translation.y as i32,
+    cooldown: cooldown.map(|cooldown| cooldown.0 as i32),
+    tick_counters,
+    construction_counter: construction.map(|(_, ticked)| ticked.counter() as i32),
+    level: level.0 as i32,
+    sequence: sequence.0 as i64,
+    upgrade_counter: upgrade.map(|(_, ticked)| ticked.counter() as i32),
+  }
+}
+
+/// Respawns all buildings stored in the database.
+fn restore_buildings(world: &mut World) {
+  let records = {
+    let conn = &mut world
+      .get_resource::<DatabaseManager>()
+      .expect("Failed to get DatabasePool. Ensure the DatabasePlugin is added before this plugin.")
+      .try_take()
+      .expect("Failed to get database connection from pool.");
+
+    BuildingObj::get_all(conn).expect("Failed to get all buildings, connection dead?")
+  };
+  // Buildings placed from now on are younger than every restored one.
+  let next_sequence = records.last().map_or(0, |record| record.sequence as u64 + 1);
+
+  let building_table = world.resource::<BuildingDefinitionTable>();
+  let mut queue = CommandQueue::default();
+  let mut commands = Commands::new(&mut queue, world);
+  let restored = records
+    .into_iter()
+    .filter_map(|record| match building_table.get(&record.name) {
+      Some(building_def) => {
+        let position = IVec2::new(record.x, record.y);
+        let level = record.level as u32;
+        let sequence = BuildingSequence(record.sequence as u64);
+        let ent = building_def.spawn_site(&mut commands, record.id, sequence, record.owner, position, level);
+
+        match record.construction_counter {
+          Some(progress) => building_def.start_construction(&mut commands, ent, level, progress as u32),
+          None => {
+            let counters = record
+              .tick_counters
+              .iter()
+              .map(|counter| counter.unwrap_or_default() as u32)
+              .collect::<Vec<_>>();
+            building_def.spawn_production_lines(&mut commands, ent, sequence, record.owner, level, &counters);
+          },
+        }
+
+        if let Some(progress) = record.upgrade_counter {
+          building_def.start_upgrade(&mut commands, ent, level, progress as u32);
+        }
+
+        if let Some(cooldown) = record.cooldown {
+          commands.entity(ent).insert(BuildingCooldown(cooldown as u32));
+        }
+
+        Some((ent, record.id))
+      },
+      None => {
+        warn!(
+          "Skipping building {} with unknown definition {}",
+          record.id, record.name
+        );
+        None
+      },
+    })
+    .collect::<Vec<_>>();
+  queue.apply(world);
+
+  info!("Restored {} buildings", restored.len());
+
+  world.insert_resource(NextBuildingSequence(next_sequence));
+
+  let mut persisted = world.resource_mut::<PersistedBuildings>();
+  persisted.ids.extend(restored);
+}
+
+/// Tracks spawned and despawned buildings, so that the rows of the despawned
+/// ones are deleted by the next autosave.
+fn track_buildings(
+  mut persisted: ResMut<PersistedBuildings>,
+  spawned: Query<(Entity, &BuildingId), Added<BuildingId>>,
+  removed: RemovedComponents<BuildingId>,
+) {
+  let persisted = &mut *persisted;
+  persisted.ids.extend(spawned.iter().map(|(ent, id)| (ent, id.0)));
+  persisted
+    .removed
+    .extend(removed.iter().filter_map(|ent| persisted.ids.remove(&ent)));
+}
+
+/// Everything written back to the database by an autosave.
+#[derive(SystemParam)]
+pub struct SaveState<'w, 's> {
+  user_table: Res<'w, UserResourceTable>,
+  saved_users: ResMut<'w, SavedUsers>,
+  persisted: ResMut<'w, PersistedBuildings>,
+  chunk_table: ResMut<'w, LoadedChunkTable>,
+  unsaved_trades: ResMut<'w, UnsavedTrades>,
+  unsaved_market_trades: ResMut<'w, UnsavedMarketTrades>,
+  buildings: Query<'w, 's, BuildingState<'static>>,
+  lines: Query<'w, 's, ProductionLineState<'static>>,
+  orders: Query<'w, 's, &'static MarketOrder>,
+}
+
+/// Writes changed users, every building, chunks with changed tiles such as
+/// mined deposits, the trade history and the market back to the database.
+///
+/// Everything is written within a single transaction, so that a failed save
+/// never leaves a partial snapshot behind, such as market orders whose escrow
+/// is missing from the saved balances. Nothing is marked as saved until the
+/// transaction commits, so the next autosave retries all of it.
+fn save_game(database: Res<DatabaseManager>, mut state: SaveState, mut autosave: EventReader<Autosave>) {
+  if autosave.iter().last().is_none() {
+    return;
+  }
+
+  let dirty = state.saved_users.dirty(&state.user_table);
+  let buildings = state
+    .buildings
+    .iter()
+    .map(|building| building_record(building, &state.lines))
+    .collect::<Vec<_>>();
+  let orders = state.orders.iter().map(MarketOrderObj::from).collect::<Vec<_>>();
+  let modified = state.chunk_table.take_modified();
+
+  let result = database.try_take().and_then(|mut conn| {
+    PgConnection::transaction(&mut conn, |conn| {
+      User::save_all(conn, &dirty)?;
+      BuildingObj::save_all(conn, &buildings)?;
+      BuildingObj::delete_all(conn, &state.persisted.removed)?;
+      modified
+        .iter()
+        .filter_map(|&position| {
+          state
+            .chunk_table
+            .get_if_exists(position)
+            .map(|loaded| (position, loaded))
+        })
+        .try_for_each(|([x, y], loaded_chunk)| Chunk::update_chunk(conn, x, y, &loaded_chunk.chunk))?;
+      if !state.unsaved_trades.0.is_empty() {
+        TradeObj::insert_all(conn, &state.unsaved_trades.0)?;
+      }
+      MarketOrderObj::replace_all(conn, &orders)?;
+      if !state.unsaved_market_trades.0.is_empty() {
+        MarketTradeObj::insert_all(conn, &state.unsaved_market_trades.0)?;
+      }
+      Ok(())
+    })
+    .map_err(|err: diesel::result::Error| err.to_string())
+  });
+
+  match result {
+    Ok(()) => {
+      debug!(
+        "Saved {} users, {} buildings, {} chunks, {} trades and {} market orders",
+        dirty.len(),
+        buildings.len(),
+        modified.len(),
+        state.unsaved_trades.0.len(),
+        orders.len()
+      );
+      state.saved_users.mark_saved(dirty);
+      state.persisted.removed.clear();
+      state.unsaved_trades.0.clear();
+      state.unsaved_market_trades.0.clear();
+    },
+    Err(err) => {
+      warn!("Failed to save the game: {}", err);
+      modified
+        .into_iter()
+        .for_each(|position| state.chunk_table.mark_modified(position));
+    },
+  }
+}
+
+pub struct PersistencePlugin;
+
+impl Plugin for PersistencePlugin {
+  fn build(&self, app: &mut App) {
+    info!("Loading Persistence...");
+    let properties: &GameProperties = app
+      .world
+      .get_resource()
+      .expect("Failed to load Game Properties while loading Persistence. Is the PropertiesPlugin loaded?");
+    let autosave_interval = properties.autosave_interval;
+
+    // Users are loaded from the database, so they start out saved.
+    let saved_users = app
+      .world
+      .get_resource::<UserResourceTable>()
+      .map(|user_table| SavedUsers(user_table.deref().clone()))
+      .unwrap_or_default();
+
+    let exit_requested = Arc::new(AtomicBool::new(false));
+    {
+      let exit_requested = exit_requested.clone();
+      if let Err(err) = ctrlc::set_handler(move || exit_requested.store(true, Ordering::Relaxed)) {
+        warn!(
+          "Failed to set the interrupt handler, the game will not be saved when interrupted: {}",
+          err
+        );
+      }
+    }
+
+    app.world.spawn((AutosaveTimer, Ticked::new(autosave_interval)));
+
+    app
+      .insert_resource(saved_users)
+      .insert_resource(ExitRequested(exit_requested))
+      .init_resource::<PersistedBuildings>()
+      .init_resource::<UnsavedTrades>()
+      .init_resource::<UnsavedMarketTrades>()
+      .add_event::<Autosave>()
+      .add_startup_system(restore_buildings)
+      .add_startup_system(restore_market)
+      .add_system(exit_on_interrupt)
+      .add_system_to_stage(GameStage::OnTicked, autosave_on_tick)
+      .add_system_to_stage(CoreStage::PostUpdate, track_buildings)
+      .add_system_to_stage(CoreStage::PostUpdate, record_closed_trades)
+      .add_system_to_stage(CoreStage::PostUpdate, record_market_trades)
+      .add_system_to_stage(CoreStage::Last, autosave_on_exit)
+      .add_system_to_stage(CoreStage::Last, save_game.after(autosave_on_exit));
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use bevy::app::AppExit;
+  use bevy::ecs::system::{CommandQueue, SystemState};
+  use bevy::prelude::*;
+  use hashbrown::HashMap;
+  use uuid::Uuid;
+
+  use super::{
+    autosave_on_exit, autosave_on_tick, building_record, track_buildings, trade_record, Autosave, AutosaveTimer,
+    BuildingState, PersistedBuildings, ProductionLineState, SavedUsers,
+  };
+  use crate::db::models::User;
+  use crate::game::building::{BuildingCooldown, BuildingDefinitionTable, BuildingId, BuildingSequence};
+  use crate::game::resources::Resource;
+  use crate::game::stages::{GameStage, StagePlugin};
+  use crate::game::tick::{TickPlugin, Ticked};
+  use crate::game::trade::{TradeClosed, TradeOffer, TradeStatus};
+  use crate::game::user::UserResourceTable;
+  use crate::properties::GameProperties;
+
+  fn autosaves(app: &App) -> usize {
+    let events = app.world.resource::<Events<Autosave>>();
+    events.iter_current_update_events().count()
+  }
+
+  #[test]
+  fn autosaves_on_interval_and_exit() {
+    let mut app = App::new();
+    app
+      .add_plugins(MinimalPlugins)
+      .add_plugin(StagePlugin)
+      .add_plugin(TickPlugin)
+      .insert_resource(GameProperties {
+        autosave_interval: 3,
+        ..Default::default()
+      })
+      .add_event::<Autosave>()
+      .add_system_to_stage(CoreStage::Last, autosave_on_exit)
+      .add_system_to_stage(GameStage::OnTicked, autosave_on_tick);
+    app.world.spawn((AutosaveTimer, Ticked::new(3)));
+
+    for i in 1..10 {
+      app.update();
+      let expected = if i % 3 == 0 { 1 } else { 0 };
+      assert_eq!(autosaves(&app), expected, "Unexpected autosave on tick {}", i);
+    }
+
+    app.world.send_event(AppExit);
+    app.update();
+    assert_eq!(autosaves(&app), 1);
+  }
+
+  #[test]
+  fn records_closed_trades() {
+    let closed = TradeClosed {
+      offer: TradeOffer {
+        id: Uuid::new_v4(),
+        proposer: Uuid::new_v4(),
+        recipient: Uuid::new_v4(),
+        offered: vec![Resource::Credit.d(10), Resource::Coal.d(2)],
+        requested: vec![],
+      },
+      status: TradeStatus::Expired,
+    };
+
+    let record = trade_record(&closed);
+    assert_eq!(
+      record.offered_resources,
+      vec![Some("Credit".to_string()), Some("Coal".to_string())]
+    );
+    assert_eq!(record.offered_amounts, vec![Some(10), Some(2)]);
+    assert!(record.requested_resources.is_empty());
+    assert_eq!(record.status, "Expired");
+  }
+
+  #[test]
+  fn tracks_dirty_users() {
+    let id = Uuid::new_v4();
+    let other = Uuid::new_v4();
+    let mut user_table = UserResourceTable::new(HashMap::from([
+      (id, User::with_resources(id, [(Resource::Credit, 5)])),
+      (
+        other,
+        User {
+          id: other,
+          ..Default::default()
+        },
+      ),
+    ]));
+
+    let mut saved = SavedUsers::default();
+    assert_eq!(saved.dirty(&user_table).len(), 2);
+
+    saved.mark_saved(saved.dirty(&user_table));
+    assert!(saved.dirty(&user_table).is_empty());
+
+    // Storage capacity is derived, so changing it doesn't need a save.
+    user_table.set_capacity(id, HashMap::from([(Resource::Credit, 10)]));
+    assert!(saved.dirty(&user_table).is_empty());
+
+    user_table.give_resources(&id, &Resource::Credit.d(1));
+    let dirty = saved.dirty(&user_table);
+    assert_eq!(dirty, vec![User::with_resources(id, [(Resource::Credit, 6)])]);
+  }
+
+  #[test]
+  fn tracks_persisted_buildings() {
+    let mut app = App::new();
+    app
+      .add_plugins(MinimalPlugins)
+      .init_resource::<PersistedBuildings>()
+      .add_system_to_stage(CoreStage::PostUpdate, track_buildings);
+
+    let mut queue = CommandQueue::default();
+    let commands = &mut Commands::new(&mut queue, &app.world);
+
+    let owner = Uuid::new_v4();
+    let ent =
+      BuildingDefinitionTable::load()["Headquarters"].spawn(commands, BuildingSequence(7), owner, IVec2 { x: 2, y: 4 });
+    commands.entity(ent).insert(BuildingCooldown(3));
+    queue.apply(&mut app.world);
+    app.update();
+
+    assert!(app.world.resource::<PersistedBuildings>().contains(ent));
+
+    let mut state: SystemState<(Query<BuildingState>, Query<ProductionLineState>)> = SystemState::new(&mut app.world);
+    let (buildings, lines) = state.get(&app.world);
+    let record = building_record(buildings.get(ent).unwrap(), &lines);
+    assert_eq!(record.id, app.world.entity(ent).get::<BuildingId>().unwrap().0);
+    assert_eq!(record.name, "Headquarters");
+    assert_eq!(record.owner, owner);
+    assert_eq!((record.x, record.y), (2, 4));
+    assert_eq!(record.cooldown, Some(3));
+    // Headquarters has a single production line, firing every tick.
+    assert_eq!(record.tick_counters, vec![Some(0)]);
+    assert_eq!(record.construction_counter, None);
+    assert_eq!(record.sequence, 7);
+    assert_eq!(record.upgrade_counter, None);
+
+    let id = app.world.entity(ent).get::<BuildingId>().unwrap().0;
+    app.world.entity_mut(ent).despawn_recursive();
+    app.update();
+
+    let persisted = app.world.resource::<PersistedBuildings>();
+    assert!(!persisted.contains(ent));
+    assert_eq!(persisted.removed(), &[id]);
+  }
+}
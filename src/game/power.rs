@@ -0,0 +1,240 @@
+//! Power Grid
+//!
+//! Watts flow rather than bank. Generators produce Watts each tick, which are
+//! only available to consumers on the following tick, after which any unused
+//! power is lost. Consumers that cannot draw the Watts they need are put in a
+//! [Brownout] and stop producing until power is restored. As ticked costs are
+//! paid in [CostPriority] order, lower priority consumers brown out first.
+//!
+//! [CostPriority]: super::resources::CostPriority
+
+use bevy::prelude::*;
+use hashbrown::HashMap;
+use uuid::Uuid;
+
+use super::building::{
+  demolish_buildings, on_tick_building_ticked_resources, process_actions, BuildingTickedResourceProduct,
+};
+use super::resources::{Resource, ResourceDelta, TickedResourceCost};
+use super::stages::GameStage;
+use super::tick::Ticked;
+use super::user::{UserOwned, UserResourceTable};
+
+/// Marks a consumer that could not draw the Watts it needed on the last tick
+/// it fired.
+#[derive(Component, Debug)]
+pub struct Brownout;
+
+/// Power flowing through the grid of a single user during the last tick.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct PowerSummary {
+  /// Watts generated, available to consumers on the next tick.
+  pub produced: i64,
+  /// Watts requested by consumers.
+  pub demand: i64,
+  /// Watts actually drawn by consumers.
+  pub consumed: i64,
+  /// Number of consumers in a [Brownout].
+  pub browned_out: u32,
+}
+
+/// The [PowerSummary] of every user with a generator or consumer.
+#[derive(Resource, Default, Deref)]
+pub struct PowerGrid(HashMap<Uuid, PowerSummary>);
+
+/// Total Watts of the deltas, repeated for every time the line fired.
+fn watts(deltas: &[ResourceDelta], fire_count: u32) -> i64 {
+  deltas
+    .iter()
+    .filter(|delta| delta.resource == Resource::Watt)
+    .map(|delta| delta.value.abs())
+    .sum::<i64>()
+    * fire_count as i64
+}
+
+/// Discards the Watts left over from the previous tick, once consumers and
+/// building actions have drawn what they need and before generators produce
+/// more.
+fn expire_unused_watts(mut user_table: ResMut<UserResourceTable>) {
+  user_table
+    .values_mut()
+    .filter(|user| user.get(Resource::Watt) != 0)
+    .for_each(|user| user.set(Resource::Watt, 0));
+}
+
+type PowerLineState<'a> = (
+  Entity,
+  &'a Ticked,
+  &'a UserOwned,
+  &'a TickedResourceCost,
+  Option<&'a BuildingTickedResourceProduct>,
+  Option<&'a Brownout>,
+);
+
+/// Puts consumers that could not be powered in a [Brownout], restores those
+/// that were, and summarises the grid of every user.
+fn update_power_grid(mut commands: Commands, mut grid: ResMut<PowerGrid>, lines: Query<PowerLineState>) {
+  let mut summaries = HashMap::<Uuid, PowerSummary>::new();
+
+  lines.for_each(|(ent, ticked, owner, cost, product, brownout)| {
+    if ticked.fire_count() == 0 {
+      return;
+    }
+
+    let demand = watts(cost.costs(), ticked.fire_count());
+    let produced = product.map_or(0, |product| watts(&product.0, ticked.fire_count()));
+    if demand == 0 && produced == 0 {
+      return;
+    }
+
+    let summary = summaries.entry(owner.0).or_default();
+    summary.demand += demand;
+    if cost.paid() {
+      summary.produced += produced;
+      summary.consumed += demand;
+      if brownout.is_some() {
+        commands.entity(ent).remove::<Brownout>();
+      }
+    } else if demand > 0 {
+      summary.browned_out += 1;
+      if brownout.is_none() {
+        commands.entity(ent).insert(Brownout);
+      }
+    }
+  });
+
+  grid.0 = summaries;
+}
+
+pub struct PowerPlugin;
+
+impl Plugin for PowerPlugin {
+  fn build(&self, app: &mut App) {
+    info!("Loading Power Grid...");
+    app
+      .init_resource::<PowerGrid>()
+      .add_system_to_stage(
+        GameStage::OnResourcesPaid,
+        expire_unused_watts
+          .after(process_actions)
+          .before(on_tick_building_ticked_resources),
+      )
+      // Brownouts are queued on the production lines, which are despawned
+      // along with demolished buildings.
+      .add_system_to_stage(GameStage::OnResourcesPaid, update_power_grid.before(demolish_buildings));
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use bevy::ecs::system::CommandQueue;
+  use bevy::prelude::*;
+  use hashbrown::HashMap;
+  use uuid::Uuid;
+
+  use super::{Brownout, PowerGrid, PowerPlugin, PowerSummary};
+  use crate::db::models::User;
+  use crate::game::building::{BuildingDefinitionTable, BuildingDemolish, BuildingPlugin, BuildingSequence};
+  use crate::game::resources::{Resource, ResourcePlugin};
+  use crate::game::stages::StagePlugin;
+  use crate::game::tick::TickPlugin;
+  use crate::game::user::UserResourceTable;
+  use crate::properties::GameProperties;
+
+  #[test]
+  fn brownouts_and_expiring_watts() {
+    let mut app = App::new();
+    app
+      .add_plugins(MinimalPlugins)
+      .add_plugin(StagePlugin)
+      .add_plugin(TickPlugin)
+      .add_plugin(ResourcePlugin)
+      .add_plugin(BuildingPlugin)
+      .add_plugin(PowerPlugin)
+      .init_resource::<GameProperties>();
+
+    let id = Uuid::new_v4();
+    let user = User::with_resources(id, [(Resource::Coal, 100)]);
+    app
+      .world
+      .insert_resource(UserResourceTable::new(HashMap::from([(id, user)])));
+
+    // One generator powers two of the three data centers.
+    let building_table = BuildingDefinitionTable::load();
+    let mut queue = CommandQueue::default();
+    let commands = &mut Commands::new(&mut queue, &app.world);
+    building_table["Coal Generator"].spawn(commands, BuildingSequence(0), id, IVec2::ZERO);
+    let data_centers = [1, 2, 3]
+      .map(|n| building_table["Data Center"].spawn(commands, BuildingSequence(n), id, IVec2::new(n as i32 * 2, 0)));
+    queue.apply(&mut app.world);
+
+    let browned_out = |app: &mut App| {
+      data_centers.map(|ent| {
+        let line = app.world.entity(ent).get::<Children>().unwrap()[0];
+        app.world.entity(line).contains::<Brownout>()
+      })
+    };
+    let watts = |app: &App| app.world.resource::<UserResourceTable>()[&id].get(Resource::Watt);
+
+    // Nothing has been generated yet.
+    app.update();
+    assert_eq!(browned_out(&mut app), [true, true, true]);
+    assert_eq!(watts(&app), 10);
+
+    // Lower priority consumers, then younger ones, brown out first.
+    app.update();
+    assert_eq!(browned_out(&mut app), [false, false, true]);
+    assert_eq!(
+      app.world.resource::<PowerGrid>()[&id],
+      PowerSummary {
+        produced: 10,
+        demand: 15,
+        consumed: 10,
+        browned_out: 1,
+      }
+    );
+
+    // Unused power does not bank.
+    data_centers
+      .iter()
+      .for_each(|ent| app.world.entity_mut(*ent).despawn_recursive());
+    app.update();
+    app.update();
+    assert_eq!(watts(&app), 10);
+    assert_eq!(app.world.resource::<PowerGrid>()[&id].demand, 0);
+  }
+
+  #[test]
+  fn demolishing_a_browned_out_consumer() {
+    let mut app = App::new();
+    app
+      .add_plugins(MinimalPlugins)
+      .add_plugin(StagePlugin)
+      .add_plugin(TickPlugin)
+      .add_plugin(ResourcePlugin)
+      .add_plugin(BuildingPlugin)
+      .add_plugin(PowerPlugin)
+      .init_resource::<GameProperties>();
+
+    let id = Uuid::new_v4();
+    app.world.insert_resource(UserResourceTable::new(HashMap::from([(
+      id,
+      User::with_resources(id, []),
+    )])));
+
+    let building_table = BuildingDefinitionTable::load();
+    let mut queue = CommandQueue::default();
+    let commands = &mut Commands::new(&mut queue, &app.world);
+    let data_center = building_table["Data Center"].spawn(commands, BuildingSequence(0), id, IVec2::ZERO);
+    queue.apply(&mut app.world);
+
+    // The data center browns out on the same tick it is demolished.
+    app.world.entity_mut(data_center).insert(BuildingDemolish {
+      user_origin: id,
+      ticket: None,
+    });
+    app.update();
+    assert!(app.world.get_entity(data_center).is_none());
+    assert_eq!(app.world.resource::<PowerGrid>()[&id].browned_out, 1);
+  }
+}
@@ -1,22 +1,44 @@
-use std::slice::Iter;
-
 use bevy::prelude::*;
-use serde::Deserialize;
+use serde::de::value::{Error as ValueError, StrDeserializer};
+use serde::de::IntoDeserializer;
+use serde::{Deserialize, Serialize};
+use uuid::Uuid;
 
 use super::tick::Ticked;
 use super::user::{UserOwned, UserResourceTable};
 use crate::game::stages::GameStage;
 
 /// A Resource in the game.
-#[derive(Deserialize, Clone, Copy, Debug)]
+#[derive(Serialize, Deserialize, Clone, Copy, Debug, PartialEq, Eq, Hash)]
 pub enum Resource {
-  /// The basic unit of energy.
+  /// The basic unit of energy. Unused Watts expire every tick, see
+  /// [PowerPlugin](super::power::PowerPlugin).
   Watt,
   /// The basic unit of money. Generated by Headquarters.
   Credit,
+  /// Mined from copper deposits.
+  Copper,
+  /// Mined from iron deposits.
+  Iron,
+  /// Mined from coal deposits.
+  Coal,
 }
 
 impl Resource {
+  /// Every resource in the game.
+  pub const ALL: [Self; 5] = [Self::Watt, Self::Credit, Self::Copper, Self::Iron, Self::Coal];
+
+  /// Name of the resource, as used in building definitions and the database.
+  pub fn name(&self) -> String {
+    format!("{:?}", self)
+  }
+
+  /// Parses a resource from its [Resource::name].
+  pub fn from_name(name: &str) -> Option<Self> {
+    let deserializer: StrDeserializer<ValueError> = name.into_deserializer();
+    Self::deserialize(deserializer).ok()
+  }
+
   /// A shorthand function to easily create a [ResourceDelta]
   pub fn d(self, value: i64) -> ResourceDelta {
     ResourceDelta { resource: self, value }
@@ -33,7 +55,7 @@ impl Resource {
 
 /// Represents information about how a resource changes. May be used as a
 /// producer (positive number) or consumer (negative number).
-#[derive(Deserialize, Clone, Copy, Debug)]
+#[derive(Serialize, Deserialize, Clone, Copy, Debug, PartialEq)]
 pub struct ResourceDelta {
   pub resource: Resource,
   pub value: i64,
@@ -55,6 +77,15 @@ impl ResourceDelta {
   }
 }
 
+/// Emitted when production is lost because a user's storage for the resource
+/// is full.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct ResourceWasted {
+  pub user_id: Uuid,
+  pub resource: Resource,
+  pub amount: i64,
+}
+
 /// Represents a resource cost that occures when the entity is [Ticked].
 #[derive(Component)]
 pub struct TickedResourceCost {
@@ -63,14 +94,12 @@ pub struct TickedResourceCost {
 }
 
 impl TickedResourceCost {
+  /// Costs may be given as positive amounts, as in building definitions.
   pub fn new(costs: Vec<ResourceDelta>) -> Self {
-    Self { paid: false, costs }
-  }
-
-  /// Begins an iterator to verify if costs can be paid, and pay them if so.
-  pub fn iter_pay_costs(&mut self) -> Iter<'_, ResourceDelta> {
-    self.paid = false;
-    self.costs.iter()
+    Self {
+      paid: false,
+      costs: costs.into_iter().map(ResourceDelta::as_cost).collect(),
+    }
   }
 
   /// Confirms costs were paid for the pay tick.
@@ -78,24 +107,47 @@ impl TickedResourceCost {
     self.paid = true;
   }
 
+  /// Costs paid every time the entity fires.
+  pub fn costs(&self) -> &[ResourceDelta] {
+    &self.costs
+  }
+
   /// Were costs paid?
   pub fn paid(&self) -> bool {
     self.paid
   }
 }
 
+/// Order in which [TickedResourceCost]s are settled when a user cannot pay for
+/// all of them. Lower priorities are paid first, then lower orders, such as
+/// older buildings. Costs without a priority are paid last.
+#[derive(Component, Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+pub struct CostPriority {
+  priority: u32,
+  order: u64,
+}
+
+impl CostPriority {
+  pub fn new(priority: u32, order: u64) -> Self {
+    Self { priority, order }
+  }
+}
+
 fn pay_ticked_resource_costs(
   mut res: ResMut<UserResourceTable>,
-  mut query: Query<(&Ticked, &UserOwned, &mut TickedResourceCost)>,
+  mut query: Query<(&Ticked, &UserOwned, &mut TickedResourceCost, Option<&CostPriority>)>,
 ) {
-  query.for_each_mut(|(ticked, user, mut cost)| {
+  let mut costs = query.iter_mut().collect::<Vec<_>>();
+  costs.sort_by_key(|(.., priority)| (priority.is_none(), priority.copied()));
+
+  costs.into_iter().for_each(|(ticked, user, mut cost, _)| {
+    // Costs are only considered paid on ticks the entity fired.
+    cost.paid = false;
     ticked.fire(|| {
       if let Some(user) = res.get_mut(&user.0) {
-        if cost
-          .iter_pay_costs()
-          .map(|x| user.pay_resources(x))
-          .fold(true, |acc, i| acc && i)
-        {
+        // Costs are paid as a whole, so that a line that cannot afford all of
+        // them leaves the rest to lines paid after it.
+        if user.pay_resource_transaction(cost.costs().to_vec()) {
           cost.pay_costs();
         }
       } else {
@@ -113,7 +165,9 @@ pub struct ResourcePlugin;
 impl Plugin for ResourcePlugin {
   fn build(&self, app: &mut App) {
     info!("Loading Resource System...");
-    app.add_system_to_stage(GameStage::OnTicked, pay_ticked_resource_costs);
+    app
+      .add_event::<ResourceWasted>()
+      .add_system_to_stage(GameStage::OnTicked, pay_ticked_resource_costs);
   }
 }
 
@@ -123,15 +177,15 @@ mod tests {
   use hashbrown::HashMap;
   use uuid::Uuid;
 
-  use super::{Resource, ResourcePlugin, TickedResourceCost};
+  use super::{CostPriority, Resource, ResourcePlugin, TickedResourceCost};
   use crate::db::models::User;
   use crate::game::stages::StagePlugin;
   use crate::game::tick::{TickPlugin, Ticked};
   use crate::game::user::{UserOwned, UserResourceTable};
   use crate::properties::GameProperties;
 
-  #[test]
-  fn test_resource_cost() {
+  /// Pays a ticked cost of 5 of the resource from a user holding 14.
+  fn verify_resource_cost(resource: Resource) {
     // Build App
     let mut app = App::new();
     app
@@ -142,7 +196,7 @@ mod tests {
       .init_resource::<GameProperties>();
 
     let id = Uuid::new_v4();
-    let user = User { id, credits: 14 };
+    let user = User::with_resources(id, [(resource, 14)]);
 
     // Insert a User with Data
     app
@@ -155,7 +209,7 @@ mod tests {
       .spawn((
         Ticked::every_tick(),
         UserOwned(id),
-        TickedResourceCost::new(vec![Resource::Credit.cost(5)]),
+        TickedResourceCost::new(vec![resource.cost(5)]),
       ))
       .id();
 
@@ -166,7 +220,7 @@ mod tests {
     assert!(cost.paid());
 
     let user_table: &UserResourceTable = app.world.get_resource().unwrap();
-    assert_eq!(user_table.get(&id).unwrap().credits, 9);
+    assert_eq!(user_table.get(&id).unwrap().get(resource), 9);
 
     // Pay Cost #2
     app.update();
@@ -175,7 +229,7 @@ mod tests {
     assert!(cost.paid());
 
     let user_table: &UserResourceTable = app.world.get_resource().unwrap();
-    assert_eq!(user_table.get(&id).unwrap().credits, 4);
+    assert_eq!(user_table.get(&id).unwrap().get(resource), 4);
 
     // Pay Cost #3, cannot pay
     app.update();
@@ -184,6 +238,123 @@ mod tests {
     assert!(!cost.paid());
 
     let user_table: &UserResourceTable = app.world.get_resource().unwrap();
-    assert_eq!(user_table.get(&id).unwrap().credits, 4);
+    assert_eq!(user_table.get(&id).unwrap().get(resource), 4);
+  }
+
+  #[test]
+  fn test_resource_cost() {
+    verify_resource_cost(Resource::Credit);
+  }
+
+  #[test]
+  fn test_watt_resource_cost() {
+    verify_resource_cost(Resource::Watt);
+  }
+
+  #[test]
+  fn test_cost_priority() {
+    let mut app = App::new();
+    app
+      .add_plugins(MinimalPlugins)
+      .add_plugin(StagePlugin)
+      .add_plugin(TickPlugin)
+      .add_plugin(ResourcePlugin)
+      .init_resource::<GameProperties>();
+
+    let id = Uuid::new_v4();
+    let user = User::with_resources(id, [(Resource::Credit, 5)]);
+    app
+      .world
+      .insert_resource(UserResourceTable::new(HashMap::from([(id, user)])));
+
+    let mut spawn = |priority, order| {
+      app
+        .world
+        .spawn((
+          Ticked::every_tick(),
+          UserOwned(id),
+          TickedResourceCost::new(vec![Resource::Credit.cost(5)]),
+          CostPriority::new(priority, order),
+        ))
+        .id()
+    };
+    let new = spawn(1, 2);
+    let critical = spawn(0, 1);
+    let old = spawn(1, 0);
+    let paid = |app: &App| [old, critical, new].map(|ent| app.world.get::<TickedResourceCost>(ent).unwrap().paid());
+
+    app.update();
+    assert_eq!(paid(&app), [false, true, false]);
+
+    // Lower orders of the same priority are paid first.
+    for _ in 0..10 {
+      app
+        .world
+        .resource_mut::<UserResourceTable>()
+        .get_mut(&id)
+        .unwrap()
+        .set(Resource::Credit, 10);
+      app.update();
+      assert_eq!(paid(&app), [true, true, false]);
+    }
+  }
+
+  #[test]
+  fn test_starved_cost_is_not_charged() {
+    let mut app = App::new();
+    app
+      .add_plugins(MinimalPlugins)
+      .add_plugin(StagePlugin)
+      .add_plugin(TickPlugin)
+      .add_plugin(ResourcePlugin)
+      .init_resource::<GameProperties>();
+
+    let id = Uuid::new_v4();
+    let user = User::with_resources(id, [(Resource::Credit, 5)]);
+    app
+      .world
+      .insert_resource(UserResourceTable::new(HashMap::from([(id, user)])));
+
+    // The critical line also needs iron, which the user doesn't have.
+    let mut spawn = |costs, priority| {
+      app
+        .world
+        .spawn((
+          Ticked::every_tick(),
+          UserOwned(id),
+          TickedResourceCost::new(costs),
+          CostPriority::new(priority, 0),
+        ))
+        .id()
+    };
+    let starved = spawn(vec![Resource::Credit.cost(5), Resource::Iron.cost(1)], 0);
+    let fed = spawn(vec![Resource::Credit.cost(5)], 1);
+
+    app.update();
+    let paid = |ent| app.world.get::<TickedResourceCost>(ent).unwrap().paid();
+    assert_eq!([paid(starved), paid(fed)], [false, true]);
+    assert_eq!(app.world.resource::<UserResourceTable>()[&id].get(Resource::Credit), 0);
+  }
+
+  #[test]
+  fn test_watt_transaction() {
+    let mut user = User::with_resources(Uuid::new_v4(), [(Resource::Watt, 3), (Resource::Credit, 10)]);
+
+    // Paying fails as a whole, refunding the credits
+    assert!(!user.pay_resource_transaction(vec![Resource::Credit.d(2), Resource::Watt.d(4)]));
+    assert_eq!((user.get(Resource::Credit), user.get(Resource::Watt)), (10, 3));
+
+    assert!(user.pay_resource_transaction(vec![Resource::Credit.d(2), Resource::Watt.d(3)]));
+    assert_eq!((user.get(Resource::Credit), user.get(Resource::Watt)), (8, 0));
+
+    user.return_resources(&Resource::Watt.d(7));
+    assert_eq!(user.get(Resource::Watt), 7);
+  }
+
+  #[test]
+  fn test_resource_names() {
+    assert_eq!(Resource::Credit.name(), "Credit");
+    assert_eq!(Resource::from_name("Watt"), Some(Resource::Watt));
+    assert_eq!(Resource::from_name("Unobtainium"), None);
   }
 }
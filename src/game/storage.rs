@@ -0,0 +1,210 @@
+//! Resource Storage
+//!
+//! Every user can store up to `base_storage` of each resource, increased by
+//! the `[[buildings.storage]]` of their finished buildings. Production and
+//! action products beyond the capacity are lost, emitting a [ResourceWasted]
+//! event. Refunds, escrow and trades are always returned in full.
+//!
+//! [ResourceWasted]: super::resources::ResourceWasted
+
+use bevy::prelude::*;
+use hashbrown::HashMap;
+use uuid::Uuid;
+
+use super::building::{Building, BuildingDefinitionTable, BuildingLevel, UnderConstruction};
+use super::resources::Resource;
+use super::stages::GameStage;
+use super::user::{UserOwned, UserResourceTable};
+use crate::properties::GameProperties;
+
+/// Recomputes the storage capacity of every user from their buildings.
+fn update_storage_capacity(
+  properties: Res<GameProperties>,
+  building_table: Res<BuildingDefinitionTable>,
+  mut user_table: ResMut<UserResourceTable>,
+  buildings: Query<(&Building, &BuildingLevel, &UserOwned), Without<UnderConstruction>>,
+) {
+  let mut storage = HashMap::<Uuid, HashMap<Resource, i64>>::new();
+  buildings.for_each(|(building, level, owner)| {
+    let Some(building_def) = building_table.get(&building.0) else {
+      return;
+    };
+
+    let capacity = storage.entry(owner.0).or_default();
+    building_def
+      .storage_at(level.0)
+      .into_iter()
+      .flatten()
+      .for_each(|delta| {
+        *capacity.entry(delta.resource).or_default() += delta.value.abs();
+      });
+  });
+
+  let users = user_table.keys().copied().collect::<Vec<_>>();
+  users.into_iter().for_each(|user_id| {
+    let extra = storage.remove(&user_id).unwrap_or_default();
+    let capacity = Resource::ALL
+      .into_iter()
+      .map(|resource| {
+        (
+          resource,
+          properties.base_storage + extra.get(&resource).copied().unwrap_or_default(),
+        )
+      })
+      .collect();
+
+    user_table.set_capacity(user_id, capacity);
+  });
+}
+
+pub struct StoragePlugin;
+
+impl Plugin for StoragePlugin {
+  fn build(&self, app: &mut App) {
+    info!("Loading Resource Storage...");
+    app.add_system_to_stage(GameStage::Start, update_storage_capacity);
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use bevy::ecs::system::CommandQueue;
+  use bevy::prelude::*;
+  use hashbrown::HashMap;
+  use uuid::Uuid;
+
+  use super::StoragePlugin;
+  use crate::db::models::User;
+  use crate::game::building::{BuildingDefinitionTable, BuildingPerformAction, BuildingPlugin, BuildingSequence};
+  use crate::game::resources::{Resource, ResourcePlugin, ResourceWasted};
+  use crate::game::stages::StagePlugin;
+  use crate::game::tick::TickPlugin;
+  use crate::game::user::UserResourceTable;
+  use crate::properties::GameProperties;
+
+  #[test]
+  fn production_is_capped_by_storage() {
+    let mut app = App::new();
+    app
+      .add_plugins(MinimalPlugins)
+      .add_plugin(StagePlugin)
+      .add_plugin(TickPlugin)
+      .add_plugin(ResourcePlugin)
+      .add_plugin(BuildingPlugin)
+      .add_plugin(StoragePlugin)
+      .insert_resource(GameProperties {
+        base_storage: 10,
+        ..Default::default()
+      });
+
+    let id = Uuid::new_v4();
+    let user = User::with_resources(id, [(Resource::Credit, 20)]);
+    app
+      .world
+      .insert_resource(UserResourceTable::new(HashMap::from([(id, user)])));
+
+    let building_table = BuildingDefinitionTable::load();
+    let mut queue = CommandQueue::default();
+    let commands = &mut Commands::new(&mut queue, &app.world);
+    let headquarters = building_table["Headquarters"].spawn(commands, BuildingSequence(0), id, IVec2::ZERO);
+    queue.apply(&mut app.world);
+
+    let headquarters_storage = building_table["Headquarters"]
+      .storage_at(1)
+      .unwrap()
+      .iter()
+      .find(|delta| delta.resource == Resource::Credit)
+      .unwrap()
+      .value;
+
+    app.update();
+    let capacity = app.world.resource::<UserResourceTable>().capacity(&id).unwrap();
+    assert_eq!(capacity[&Resource::Credit], 10 + headquarters_storage);
+    assert_eq!(capacity[&Resource::Iron], 10);
+
+    // Without its storage building, the user is back at the base capacity.
+    app.world.entity_mut(headquarters).despawn_recursive();
+    app.update();
+
+    let mut user_table = app.world.resource_mut::<UserResourceTable>();
+    assert_eq!(user_table.capacity(&id).unwrap()[&Resource::Credit], 10);
+    // Stock above the capacity is kept, but nothing more can be stored.
+    let stock = user_table[&id].get(Resource::Credit);
+    assert!(stock > 10);
+    assert_eq!(user_table.give_resources(&id, &Resource::Credit.d(3)), 3);
+    assert_eq!(user_table[&id].get(Resource::Credit), stock);
+  }
+
+  #[test]
+  fn wasted_production_emits_events() {
+    let mut app = App::new();
+    app
+      .add_plugins(MinimalPlugins)
+      .add_plugin(StagePlugin)
+      .add_plugin(TickPlugin)
+      .add_plugin(ResourcePlugin)
+      .add_plugin(BuildingPlugin)
+      .add_plugin(StoragePlugin)
+      .insert_resource(GameProperties {
+        base_storage: 0,
+        ..Default::default()
+      });
+
+    let id = Uuid::new_v4();
+    app.world.insert_resource(UserResourceTable::new(HashMap::from([(
+      id,
+      User {
+        id,
+        ..Default::default()
+      },
+    )])));
+
+    let mut queue = CommandQueue::default();
+    let commands = &mut Commands::new(&mut queue, &app.world);
+    let building_table = BuildingDefinitionTable::load();
+    let headquarters = building_table["Headquarters"].spawn(commands, BuildingSequence(0), id, IVec2::ZERO);
+    queue.apply(&mut app.world);
+
+    // Fill the Headquarters storage, so that the next tick of income and the
+    // products of its action are lost.
+    app.update();
+    let capacity = app.world.resource::<UserResourceTable>().capacity(&id).unwrap()[&Resource::Credit];
+    app
+      .world
+      .resource_mut::<UserResourceTable>()
+      .get_mut(&id)
+      .unwrap()
+      .set(Resource::Credit, capacity);
+    app.world.entity_mut(headquarters).insert(BuildingPerformAction {
+      user_origin: id,
+      id: "increase_cash_flow".to_string(),
+      ticket: None,
+    });
+    app.update();
+
+    let events = app.world.resource::<Events<ResourceWasted>>();
+    let mut wasted = events.iter_current_update_events().copied().collect::<Vec<_>>();
+    wasted.sort_by_key(|event| event.amount);
+    // The action pays 1 Credit out of the full storage, so only 1 of its 3
+    // products fits.
+    assert_eq!(
+      wasted,
+      vec![
+        ResourceWasted {
+          user_id: id,
+          resource: Resource::Credit,
+          amount: 1,
+        },
+        ResourceWasted {
+          user_id: id,
+          resource: Resource::Credit,
+          amount: 2,
+        }
+      ]
+    );
+    assert_eq!(
+      app.world.resource::<UserResourceTable>()[&id].get(Resource::Credit),
+      capacity
+    );
+  }
+}
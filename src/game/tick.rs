@@ -59,6 +59,15 @@ impl Ticked {
   pub fn fire_count(&self) -> u32 {
     self.fired
   }
+
+  /// Progress towards the next fire.
+  pub fn counter(&self) -> u32 {
+    self.counter
+  }
+
+  pub fn set_counter(&mut self, counter: u32) {
+    self.counter = counter;
+  }
 }
 
 fn tick_system(mut query: Query<&mut Ticked>, properties: Res<GameProperties>) {
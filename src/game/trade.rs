@@ -0,0 +1,576 @@
+//! Trading
+//!
+//! Users may offer a bundle of their resources to another user in exchange
+//! for another bundle. Open offers are entities, addressed by the id of their
+//! [TradeOffer] in the accept and cancel actions, that expire after
+//! `trade_expiry` ticks. Once closed, a [TradeClosed] event is sent so that the
+//! trade can be recorded in the trade history.
+
+use bevy::prelude::*;
+use hashbrown::{HashMap, HashSet};
+use uuid::Uuid;
+
+use super::action::{ActionOutcome, ActionRejection, ActionTicket, GameAction, UserGameAction};
+use super::resources::ResourceDelta;
+use super::stages::GameStage;
+use super::tick::Ticked;
+use super::user::UserResourceTable;
+use crate::properties::GameProperties;
+
+/// An open offer of resources from one user to another.
+#[derive(Component, Debug, Clone, PartialEq)]
+pub struct TradeOffer {
+  /// Persistent identifier of the trade, used in the trade history.
+  pub id: Uuid,
+  pub proposer: Uuid,
+  pub recipient: Uuid,
+  /// Resources given by the proposer.
+  pub offered: Vec<ResourceDelta>,
+  /// Resources given by the recipient in exchange.
+  pub requested: Vec<ResourceDelta>,
+}
+
+impl TradeOffer {
+  /// Exchanges the resources of both users, or neither if one of them cannot
+  /// pay their side of the trade. Traded resources are received in full, even
+  /// beyond the storage capacity of the user, as they were already paid for.
+  fn settle(&self, user_table: &mut UserResourceTable) -> Result<(), ActionRejection> {
+    let mut proposer = user_table
+      .get(&self.proposer)
+      .ok_or(ActionRejection::UnknownUser)?
+      .clone();
+    let mut recipient = user_table
+      .get(&self.recipient)
+      .ok_or(ActionRejection::UnknownUser)?
+      .clone();
+
+    if !proposer.pay_resource_transaction(self.offered.clone())
+      || !recipient.pay_resource_transaction(self.requested.clone())
+    {
+      return Err(ActionRejection::InsufficientResources);
+    }
+
+    self
+      .offered
+      .iter()
+      .for_each(|delta| recipient.return_resources(&delta.as_product()));
+    self
+      .requested
+      .iter()
+      .for_each(|delta| proposer.return_resources(&delta.as_product()));
+
+    user_table.insert(proposer.id, proposer);
+    user_table.insert(recipient.id, recipient);
+    Ok(())
+  }
+}
+
+/// How a [TradeOffer] was closed.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum TradeStatus {
+  /// The recipient accepted, and resources were exchanged.
+  Accepted,
+  /// Either user cancelled the offer.
+  Cancelled,
+  /// Nobody acted on the offer in time.
+  Expired,
+}
+
+impl TradeStatus {
+  /// Name of the status, as stored in the trade history.
+  pub fn name(&self) -> String {
+    format!("{:?}", self)
+  }
+}
+
+/// Sent once a [TradeOffer] is closed, right before its entity is despawned.
+#[derive(Debug, Clone)]
+pub struct TradeClosed {
+  pub offer: TradeOffer,
+  pub status: TradeStatus,
+}
+
+/// Entity of every open [TradeOffer] by its id.
+#[derive(Resource, Default)]
+pub struct TradeIndex {
+  entities: HashMap<Uuid, Entity>,
+  ids: HashMap<Entity, Uuid>,
+}
+
+impl TradeIndex {
+  pub fn entity(&self, id: Uuid) -> Option<Entity> {
+    self.entities.get(&id).copied()
+  }
+
+  /// Id of the offer entity, kept until the end of the frame in which the
+  /// offer is closed.
+  pub fn id(&self, ent: Entity) -> Option<Uuid> {
+    self.ids.get(&ent).copied()
+  }
+
+  fn insert(&mut self, id: Uuid, ent: Entity) {
+    self.entities.insert(id, ent);
+    self.ids.insert(ent, id);
+  }
+
+  fn remove(&mut self, ent: Entity) {
+    if let Some(id) = self.ids.remove(&ent) {
+      self.entities.remove(&id);
+    }
+  }
+}
+
+fn index_trade_offers(
+  mut index: ResMut<TradeIndex>,
+  opened: Query<(Entity, &TradeOffer), Added<TradeOffer>>,
+  removed: RemovedComponents<TradeOffer>,
+) {
+  removed.iter().for_each(|ent| index.remove(ent));
+  opened.for_each(|(ent, offer)| index.insert(offer.id, ent));
+}
+
+/// Represents a request to accept a trade offer.
+#[derive(Component)]
+pub struct TradeAccept {
+  pub user_origin: Uuid,
+  pub ticket: Option<ActionTicket>,
+}
+
+/// Represents a request to cancel a trade offer.
+#[derive(Component)]
+pub struct TradeCancel {
+  pub user_origin: Uuid,
+  pub ticket: Option<ActionTicket>,
+}
+
+/// Checks that a proposed trade exchanges something between two existing
+/// users.
+fn validate_proposal(
+  user_table: &UserResourceTable,
+  proposer: Uuid,
+  recipient: Uuid,
+  offered: &[ResourceDelta],
+  requested: &[ResourceDelta],
+) -> Result<(), ActionRejection> {
+  if !user_table.contains_key(&proposer) || !user_table.contains_key(&recipient) {
+    return Err(ActionRejection::UnknownUser);
+  }
+
+  let bundles_valid = offered.iter().chain(requested).all(|delta| delta.value > 0);
+  if proposer == recipient || (offered.is_empty() && requested.is_empty()) || !bundles_valid {
+    return Err(ActionRejection::InvalidTrade);
+  }
+
+  Ok(())
+}
+
+pub fn process_trade_actions(
+  mut commands: Commands,
+  mut events: EventReader<UserGameAction>,
+  mut outcomes: EventWriter<ActionOutcome>,
+  properties: Res<GameProperties>,
+  user_table: Res<UserResourceTable>,
+  index: Res<TradeIndex>,
+  offers: Query<(), With<TradeOffer>>,
+) {
+  // Only one accept and one cancel request fit on an offer per tick.
+  let mut accepted = HashSet::new();
+  let mut cancelled = HashSet::new();
+
+  events.iter().for_each(|user_game_action| {
+    let UserGameAction { user_id, ticket, .. } = *user_game_action;
+    let outcome = |result| ActionOutcome {
+      user_id,
+      ticket,
+      result,
+    };
+
+    match &user_game_action.action {
+      GameAction::ProposeTrade {
+        recipient,
+        offered,
+        requested,
+      } => {
+        let result = validate_proposal(&user_table, user_id, *recipient, offered, requested).map(|_| {
+          commands
+            .spawn((
+              TradeOffer {
+                id: Uuid::new_v4(),
+                proposer: user_id,
+                recipient: *recipient,
+                offered: offered.clone(),
+                requested: requested.clone(),
+              },
+              Ticked::new(properties.trade_expiry),
+            ))
+            .id()
+        });
+
+        outcomes.send(outcome(result));
+      },
+      GameAction::AcceptTrade { trade } => match index.entity(*trade).filter(|ent| offers.contains(*ent)) {
+        None => outcomes.send(outcome(Err(ActionRejection::UnknownEntity))),
+        Some(ent) if !accepted.insert(ent) => outcomes.send(outcome(Err(ActionRejection::Busy))),
+        Some(ent) => {
+          commands.entity(ent).insert(TradeAccept {
+            user_origin: user_id,
+            ticket,
+          });
+        },
+      },
+      GameAction::CancelTrade { trade } => match index.entity(*trade).filter(|ent| offers.contains(*ent)) {
+        None => outcomes.send(outcome(Err(ActionRejection::UnknownEntity))),
+        Some(ent) if !cancelled.insert(ent) => outcomes.send(outcome(Err(ActionRejection::Busy))),
+        Some(ent) => {
+          commands.entity(ent).insert(TradeCancel {
+            user_origin: user_id,
+            ticket,
+          });
+        },
+      },
+      // Handled by process_game_actions and process_market_actions.
+      GameAction::BuildBuilding { .. }
+      | GameAction::PerformBuildingAction { .. }
+      | GameAction::DemolishBuilding { .. }
+      | GameAction::MoveBuilding { .. }
+      | GameAction::UpgradeBuilding { .. }
+      | GameAction::PlaceOrder { .. }
+      | GameAction::CancelOrder { .. } => {},
+    }
+  });
+}
+
+type TradeRequests<'a> = (Entity, &'a TradeOffer, Option<&'a TradeAccept>, Option<&'a TradeCancel>);
+
+/// Offers with an accept or cancel request.
+type PendingTradeRequest = Or<(With<TradeAccept>, With<TradeCancel>)>;
+
+/// Closes offers cancelled by either of their users, then settles accepted
+/// offers. Offers that cannot be paid for stay open.
+fn close_trades(
+  mut commands: Commands,
+  mut user_table: ResMut<UserResourceTable>,
+  mut outcomes: EventWriter<ActionOutcome>,
+  mut closed: EventWriter<TradeClosed>,
+  query: Query<TradeRequests, PendingTradeRequest>,
+) {
+  query.for_each(|(e, offer, accept, cancel)| {
+    let mut close = |status| {
+      closed.send(TradeClosed {
+        offer: offer.clone(),
+        status,
+      });
+      commands.entity(e).despawn();
+    };
+
+    let mut open = true;
+    if let Some(cancel) = cancel {
+      let result = if cancel.user_origin == offer.proposer || cancel.user_origin == offer.recipient {
+        close(TradeStatus::Cancelled);
+        open = false;
+        Ok(e)
+      } else {
+        Err(ActionRejection::NotOwner)
+      };
+
+      outcomes.send(ActionOutcome {
+        user_id: cancel.user_origin,
+        ticket: cancel.ticket,
+        result,
+      });
+    }
+
+    if let Some(accept) = accept {
+      let result = if !open {
+        Err(ActionRejection::UnknownEntity)
+      } else if accept.user_origin != offer.recipient {
+        Err(ActionRejection::NotOwner)
+      } else {
+        offer.settle(&mut user_table).map(|_| {
+          close(TradeStatus::Accepted);
+          open = false;
+          e
+        })
+      };
+
+      outcomes.send(ActionOutcome {
+        user_id: accept.user_origin,
+        ticket: accept.ticket,
+        result,
+      });
+    }
+
+    if open {
+      commands.entity(e).remove::<(TradeAccept, TradeCancel)>();
+    }
+  });
+}
+
+/// Closes offers nobody acted on in time. Runs once pending requests were
+/// processed, so that an offer accepted on its last tick is still settled.
+fn expire_trades(
+  mut commands: Commands,
+  mut closed: EventWriter<TradeClosed>,
+  query: Query<(Entity, &TradeOffer, &Ticked)>,
+) {
+  query.for_each(|(e, offer, ticked)| {
+    if ticked.fire_count() > 0 {
+      closed.send(TradeClosed {
+        offer: offer.clone(),
+        status: TradeStatus::Expired,
+      });
+      commands.entity(e).despawn();
+    }
+  });
+}
+
+pub struct TradePlugin;
+
+impl Plugin for TradePlugin {
+  fn build(&self, app: &mut App) {
+    info!("Loading Trading...");
+    app
+      .add_event::<TradeClosed>()
+      .init_resource::<TradeIndex>()
+      .add_system_to_stage(GameStage::Start, process_trade_actions)
+      .add_system_to_stage(GameStage::OnResourcesPaid, close_trades)
+      .add_system_to_stage(GameStage::Cleanup, expire_trades)
+      .add_system_to_stage(CoreStage::PostUpdate, index_trade_offers);
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use bevy::prelude::*;
+  use hashbrown::HashMap;
+  use uuid::Uuid;
+
+  use super::{TradeClosed, TradeIndex, TradeOffer, TradePlugin, TradeStatus};
+  use crate::db::models::User;
+  use crate::game::action::{ActionOutcome, ActionRejection, GameAction, UserGameAction};
+  use crate::game::resources::{Resource, ResourceDelta};
+  use crate::game::stages::StagePlugin;
+  use crate::game::tick::TickPlugin;
+  use crate::game::user::UserResourceTable;
+  use crate::properties::GameProperties;
+
+  fn build_app(users: Vec<User>) -> App {
+    let mut app = App::new();
+    app
+      .add_plugins(MinimalPlugins)
+      .add_plugin(StagePlugin)
+      .add_plugin(TickPlugin)
+      .add_plugin(TradePlugin)
+      .add_event::<UserGameAction>()
+      .add_event::<ActionOutcome>()
+      .insert_resource(GameProperties {
+        trade_expiry: 3,
+        ..Default::default()
+      })
+      .insert_resource(UserResourceTable::new(
+        users.into_iter().map(|user| (user.id, user)).collect::<HashMap<_, _>>(),
+      ));
+    app
+  }
+
+  fn send(app: &mut App, user_id: Uuid, action: GameAction) -> Vec<ActionOutcome> {
+    app.world.send_event(UserGameAction {
+      user_id,
+      action,
+      ticket: None,
+    });
+    app.update();
+
+    let mut outcomes = app.world.resource_mut::<Events<ActionOutcome>>();
+    outcomes.drain().collect()
+  }
+
+  fn propose(app: &mut App, proposer: Uuid, recipient: Uuid, offered: Vec<ResourceDelta>) -> Vec<ActionOutcome> {
+    let action = GameAction::ProposeTrade {
+      recipient,
+      offered,
+      requested: vec![Resource::Iron.d(5)],
+    };
+    send(app, proposer, action)
+  }
+
+  fn closed(app: &App) -> Vec<TradeStatus> {
+    let events = app.world.resource::<Events<TradeClosed>>();
+    events
+      .iter_current_update_events()
+      .map(|closed| closed.status)
+      .collect()
+  }
+
+  fn trade_id(app: &App, ent: Entity) -> Uuid {
+    app.world.get::<TradeOffer>(ent).unwrap().id
+  }
+
+  fn balance(app: &App, id: Uuid) -> (i64, i64) {
+    let user = &app.world.resource::<UserResourceTable>()[&id];
+    (user.get(Resource::Credit), user.get(Resource::Iron))
+  }
+
+  #[test]
+  fn accept_trade_outcomes() {
+    let (alice, bob, eve) = (Uuid::new_v4(), Uuid::new_v4(), Uuid::new_v4());
+    let mut app = build_app(vec![
+      User::with_resources(alice, [(Resource::Credit, 10)]),
+      User::with_resources(bob, [(Resource::Iron, 3)]),
+      User::with_resources(eve, [(Resource::Iron, 5)]),
+    ]);
+
+    let outcomes = propose(&mut app, alice, alice, vec![Resource::Credit.d(10)]);
+    assert_eq!(outcomes[0].result, Err(ActionRejection::InvalidTrade));
+    let outcomes = propose(&mut app, alice, Uuid::new_v4(), vec![Resource::Credit.d(10)]);
+    assert_eq!(outcomes[0].result, Err(ActionRejection::UnknownUser));
+
+    let offer = propose(&mut app, alice, bob, vec![Resource::Credit.d(10)])[0]
+      .result
+      .unwrap();
+    let trade = trade_id(&app, offer);
+    let accept = || GameAction::AcceptTrade { trade };
+
+    let outcomes = send(&mut app, eve, accept());
+    assert_eq!(outcomes[0].result, Err(ActionRejection::NotOwner));
+
+    // Bob cannot pay his side, so neither user is charged.
+    let outcomes = send(&mut app, bob, accept());
+    assert_eq!(outcomes[0].result, Err(ActionRejection::InsufficientResources));
+    assert_eq!((balance(&app, alice), balance(&app, bob)), ((10, 0), (0, 3)));
+
+    app
+      .world
+      .resource_mut::<UserResourceTable>()
+      .get_mut(&bob)
+      .unwrap()
+      .set(Resource::Iron, 5);
+    let outcomes = send(&mut app, bob, accept());
+    assert_eq!(outcomes[0].result, Ok(offer));
+    assert_eq!((balance(&app, alice), balance(&app, bob)), ((0, 5), (10, 0)));
+    assert_eq!(closed(&app), vec![TradeStatus::Accepted]);
+    assert!(app.world.get_entity(offer).is_none());
+
+    let outcomes = send(&mut app, bob, accept());
+    assert_eq!(outcomes[0].result, Err(ActionRejection::UnknownEntity));
+  }
+
+  #[test]
+  fn accept_trade_once_per_tick() {
+    let (alice, bob) = (Uuid::new_v4(), Uuid::new_v4());
+    let mut app = build_app(vec![
+      User::with_resources(alice, [(Resource::Credit, 10)]),
+      User::with_resources(bob, [(Resource::Iron, 5)]),
+    ]);
+
+    let offer = propose(&mut app, alice, bob, vec![Resource::Credit.d(10)])[0]
+      .result
+      .unwrap();
+    let trade = trade_id(&app, offer);
+    (0..2).for_each(|_| {
+      app.world.send_event(UserGameAction {
+        user_id: bob,
+        action: GameAction::AcceptTrade { trade },
+        ticket: None,
+      });
+    });
+    app.update();
+
+    let outcomes = app
+      .world
+      .resource_mut::<Events<ActionOutcome>>()
+      .drain()
+      .collect::<Vec<_>>();
+    let results = outcomes.iter().map(|outcome| outcome.result).collect::<Vec<_>>();
+    assert_eq!(results, vec![Err(ActionRejection::Busy), Ok(offer)]);
+    assert_eq!((balance(&app, alice), balance(&app, bob)), ((0, 5), (10, 0)));
+  }
+
+  #[test]
+  fn cancelled_and_expired_trades() {
+    let (alice, bob, eve) = (Uuid::new_v4(), Uuid::new_v4(), Uuid::new_v4());
+    let mut app = build_app(vec![
+      User::with_resources(alice, [(Resource::Credit, 10)]),
+      User::with_resources(bob, [(Resource::Iron, 5)]),
+      User {
+        id: eve,
+        ..Default::default()
+      },
+    ]);
+
+    let offer = propose(&mut app, alice, bob, vec![Resource::Credit.d(10)])[0]
+      .result
+      .unwrap();
+    let trade = trade_id(&app, offer);
+    let cancel = || GameAction::CancelTrade { trade };
+
+    let outcomes = send(&mut app, eve, cancel());
+    assert_eq!(outcomes[0].result, Err(ActionRejection::NotOwner));
+
+    // The recipient may decline the offer.
+    let outcomes = send(&mut app, bob, cancel());
+    assert_eq!(outcomes[0].result, Ok(offer));
+    assert_eq!(closed(&app), vec![TradeStatus::Cancelled]);
+    assert!(app.world.get_entity(offer).is_none());
+    assert_eq!(app.world.resource::<TradeIndex>().entity(trade), None);
+
+    let offer = propose(&mut app, alice, bob, vec![Resource::Credit.d(10)])[0]
+      .result
+      .unwrap();
+    app.update();
+    app.update();
+    assert!(app.world.get_entity(offer).is_some());
+
+    app.update();
+    assert_eq!(closed(&app), vec![TradeStatus::Expired]);
+    assert!(app.world.get_entity(offer).is_none());
+    assert_eq!((balance(&app, alice), balance(&app, bob)), ((10, 0), (0, 5)));
+  }
+
+  #[test]
+  fn offers_are_addressed_by_id() {
+    let (alice, bob) = (Uuid::new_v4(), Uuid::new_v4());
+    let mut app = build_app(vec![
+      User::with_resources(alice, [(Resource::Credit, 10)]),
+      User::with_resources(bob, [(Resource::Iron, 5)]),
+    ]);
+
+    let first = propose(&mut app, alice, bob, vec![Resource::Credit.d(10)])[0]
+      .result
+      .unwrap();
+    let first_trade = trade_id(&app, first);
+    send(&mut app, alice, GameAction::CancelTrade { trade: first_trade });
+
+    // The second offer reuses the entity of the first.
+    let second = propose(&mut app, alice, bob, vec![Resource::Credit.d(10)])[0]
+      .result
+      .unwrap();
+    assert_eq!(second.index(), first.index());
+
+    let outcomes = send(&mut app, bob, GameAction::AcceptTrade { trade: first_trade });
+    assert_eq!(outcomes[0].result, Err(ActionRejection::UnknownEntity));
+
+    let trade = trade_id(&app, second);
+    let outcomes = send(&mut app, alice, GameAction::CancelTrade { trade });
+    assert_eq!(outcomes[0].result, Ok(second));
+  }
+
+  #[test]
+  fn traded_resources_are_never_lost() {
+    let (alice, bob) = (Uuid::new_v4(), Uuid::new_v4());
+    let full = User::with_resources(bob, [(Resource::Credit, 5), (Resource::Iron, 5)]);
+    let mut app = build_app(vec![User::with_resources(alice, [(Resource::Credit, 10)]), full]);
+    app
+      .world
+      .resource_mut::<UserResourceTable>()
+      .set_capacity(bob, HashMap::from([(Resource::Credit, 5)]));
+
+    let offer = propose(&mut app, alice, bob, vec![Resource::Credit.d(10)])[0]
+      .result
+      .unwrap();
+    let trade = trade_id(&app, offer);
+    let outcomes = send(&mut app, bob, GameAction::AcceptTrade { trade });
+    assert_eq!(outcomes[0].result, Ok(offer));
+    assert_eq!((balance(&app, alice), balance(&app, bob)), ((0, 5), (15, 0)));
+  }
+}
@@ -4,16 +4,58 @@ use bevy::prelude::*;
 use hashbrown::HashMap;
 use uuid::Uuid;
 
+use super::resources::{Resource, ResourceDelta};
 use crate::db::models::User;
 use crate::db::DatabaseManager;
 
+/// Most of each resource a user can store. Resources missing from the map are
+/// not capped.
+pub type StorageCapacity = HashMap<Resource, i64>;
+
 /// Contains the table of all users in the world.
 #[derive(Clone, Resource)]
-pub struct UserResourceTable(HashMap<Uuid, User>);
+pub struct UserResourceTable {
+  users: HashMap<Uuid, User>,
+  /// Storage capacity of every user, derived from their storage buildings and
+  /// not persisted.
+  capacity: HashMap<Uuid, StorageCapacity>,
+}
 
 impl UserResourceTable {
   pub fn new(users: HashMap<Uuid, User>) -> Self {
-    Self(users)
+    Self {
+      users,
+      capacity: HashMap::new(),
+    }
+  }
+
+  pub fn capacity(&self, user_id: &Uuid) -> Option<&StorageCapacity> {
+    self.capacity.get(user_id)
+  }
+
+  pub fn set_capacity(&mut self, user_id: Uuid, capacity: StorageCapacity) {
+    self.capacity.insert(user_id, capacity);
+  }
+
+  /// Gives resources up to the capacity of the user, returning the amount
+  /// that did not fit. Unknown users are given nothing.
+  pub fn give_resources(&mut self, user_id: &Uuid, delta: &ResourceDelta) -> i64 {
+    let Some(user) = self.users.get_mut(user_id) else {
+      return 0;
+    };
+
+    let amount = user.get(delta.resource);
+    let capacity = self
+      .capacity
+      .get(user_id)
+      .and_then(|capacity| capacity.get(&delta.resource));
+    let given = match capacity {
+      Some(capacity) if delta.value > 0 => delta.value.min((capacity - amount).max(0)),
+      _ => delta.value,
+    };
+
+    user.set(delta.resource, amount + given);
+    delta.value - given
   }
 }
 
@@ -21,13 +63,13 @@ impl Deref for UserResourceTable {
   type Target = HashMap<Uuid, User>;
 
   fn deref(&self) -> &Self::Target {
-    &self.0
+    &self.users
   }
 }
 
 impl DerefMut for UserResourceTable {
   fn deref_mut(&mut self) -> &mut Self::Target {
-    &mut self.0
+    &mut self.users
   }
 }
 
@@ -54,6 +96,6 @@ impl Plugin for UserPlugin {
 
     info!("Found users {:?}", all_users);
 
-    app.insert_resource(UserResourceTable(all_users));
+    app.insert_resource(UserResourceTable::new(all_users));
   }
 }
@@ -1,11 +1,15 @@
+use std::marker::PhantomData;
+
+use bevy::ecs::system::SystemParam;
 use bevy::prelude::*;
-use hashbrown::HashMap;
+use hashbrown::{HashMap, HashSet};
 use itertools::Itertools;
 use lazy_static::lazy_static;
 
 use super::resources::*;
 use crate::db::models::{Chunk, World};
 use crate::db::{AcquiredDatabaseConnection, DatabaseManager};
+use crate::game::resources::Resource;
 
 #[repr(u8)]
 #[derive(Debug, Clone, Copy, PartialEq, Eq)]
@@ -22,6 +26,40 @@ pub enum ComplexTerrainTile {
   Iron(u32),
 }
 
+impl ComplexTerrainTile {
+  /// The [Resource] mined from this deposit.
+  pub fn resource(&self) -> Resource {
+    match self {
+      Self::Copper(_) => Resource::Copper,
+      Self::Coal(_) => Resource::Coal,
+      Self::Iron(_) => Resource::Iron,
+    }
+  }
+
+  /// Amount of the resource left in the deposit.
+  pub fn amount(&self) -> u32 {
+    match self {
+      Self::Copper(amount) | Self::Coal(amount) | Self::Iron(amount) => *amount,
+    }
+  }
+
+  /// Removes up to `amount` from the deposit, returning the amount extracted
+  /// and the tile left behind. Depleted deposits turn into stone.
+  pub fn extract(&self, amount: u32) -> (u32, TerrainTile) {
+    let extracted = amount.min(self.amount());
+    let remaining = self.amount() - extracted;
+
+    let tile = match self {
+      _ if remaining == 0 => TerrainTile::Static(StaticTerrainTile::Stone),
+      Self::Copper(_) => TerrainTile::Complex(Self::Copper(remaining)),
+      Self::Coal(_) => TerrainTile::Complex(Self::Coal(remaining)),
+      Self::Iron(_) => TerrainTile::Complex(Self::Iron(remaining)),
+    };
+
+    (extracted, tile)
+  }
+}
+
 #[repr(u8)]
 #[derive(Debug, Clone, Copy, PartialEq, Eq)]
 pub enum TerrainTile {
@@ -101,6 +139,16 @@ impl World {
     [x, y]
   }
 
+  /// Returns the position of the chunk containing a tile, and the index of the
+  /// tile within that chunk.
+  #[inline(always)]
+  pub fn get_chunk_and_index_from_tile_position([x, y]: [i64; 2]) -> ([i64; 2], usize) {
+    let side_length = World::CHUNK_SIDE_LENGTH as i64;
+    let chunk = [x.div_euclid(side_length), y.div_euclid(side_length)];
+    let index = World::get_chunk_index([x.rem_euclid(side_length) as u32, y.rem_euclid(side_length) as u32]);
+    (chunk, index)
+  }
+
   /// Returns the index of a tile in a chunk list given it's offset coordinates.
   #[inline(always)]
   pub fn get_chunk_index([x_offset, y_offset]: [u32; 2]) -> usize {
@@ -133,7 +181,11 @@ pub struct LoadedChunk {
 }
 
 #[derive(Default, Resource)]
-pub struct LoadedChunkTable(HashMap<[i64; 2], LoadedChunk>);
+pub struct LoadedChunkTable {
+  chunks: HashMap<[i64; 2], LoadedChunk>,
+  /// Chunks changed since they were last saved.
+  modified: HashSet<[i64; 2]>,
+}
 
 impl LoadedChunkTable {
   pub fn get(
@@ -143,11 +195,11 @@ impl LoadedChunkTable {
     world: &World,
     position: [i64; 2],
   ) -> &LoadedChunk {
-    if !self.0.contains_key(&position) {
+    if !self.chunks.contains_key(&position) {
       let [chunk_x, chunk_y] = position;
       if let Some(mut conn) = conn {
         if let Ok(chunk) = Chunk::from_xy(&mut *conn, chunk_x, chunk_y) {
-          self.0.insert(
+          self.chunks.insert(
             position,
             LoadedChunk {
               chunk,
@@ -156,19 +208,19 @@ impl LoadedChunkTable {
           );
         } else {
           self.gen(generator, world, position);
-          Chunk::save_chunk(&mut *conn, chunk_x, chunk_y, &self.0.get(&position).unwrap().chunk).unwrap()
+          Chunk::save_chunk(&mut *conn, chunk_x, chunk_y, &self.chunks.get(&position).unwrap().chunk).unwrap()
         }
       } else {
         self.gen(generator, world, position);
       }
     }
 
-    self.0.get(&position).unwrap()
+    self.chunks.get(&position).unwrap()
   }
 
   pub fn gen(&mut self, generator: &WorldGenerator, world: &World, position: [i64; 2]) {
     let chunk = world.get_chunk(generator, position);
-    self.0.insert(
+    self.chunks.insert(
       position,
       LoadedChunk {
         chunk,
@@ -178,17 +230,70 @@ impl LoadedChunkTable {
   }
 
   pub fn update_entity(&mut self, position: [i64; 2], ent: Entity) {
-    if let Some(loaded_chunk) = self.0.get_mut(&position) {
+    if let Some(loaded_chunk) = self.chunks.get_mut(&position) {
       loaded_chunk.spawned_entity = Some(ent);
     }
   }
 
   pub fn get_mut_if_exists(&mut self, position: [i64; 2]) -> Option<&mut LoadedChunk> {
-    self.0.get_mut(&position)
+    self.chunks.get_mut(&position)
   }
 
   pub fn get_if_exists(&self, position: [i64; 2]) -> Option<&LoadedChunk> {
-    self.0.get(&position)
+    self.chunks.get(&position)
+  }
+
+  /// Replaces a tile of a loaded chunk, marking the chunk as modified.
+  pub fn set_tile(&mut self, position: [i64; 2], index: usize, tile: TerrainTile) {
+    if let Some(loaded_chunk) = self.chunks.get_mut(&position) {
+      loaded_chunk.chunk[index] = tile;
+      self.modified.insert(position);
+    }
+  }
+
+  pub fn mark_modified(&mut self, position: [i64; 2]) {
+    self.modified.insert(position);
+  }
+
+  /// Takes the positions of all chunks modified since this was last called.
+  pub fn take_modified(&mut self) -> Vec<[i64; 2]> {
+    self.modified.drain().collect()
+  }
+}
+
+/// System parameter providing access to the terrain of the world, loading
+/// chunks on demand.
+#[derive(SystemParam)]
+pub struct Terrain<'w, 's> {
+  chunk_table: ResMut<'w, LoadedChunkTable>,
+  generator: Res<'w, WorldGenerator>,
+  world: Res<'w, World>,
+  database: Res<'w, DatabaseManager>,
+  #[system_param(ignore)]
+  marker: PhantomData<&'s ()>,
+}
+
+impl<'w, 's> Terrain<'w, 's> {
+  /// Returns the tile at the given world position.
+  pub fn get_tile(&mut self, position: [i64; 2]) -> TerrainTile {
+    let (chunk, index) = World::get_chunk_and_index_from_tile_position(position);
+
+    if let Some(loaded_chunk) = self.chunk_table.get_if_exists(chunk) {
+      return loaded_chunk.chunk[index];
+    }
+
+    let conn = self.database.try_take().ok();
+    self.chunk_table.get(conn, &self.generator, &self.world, chunk).chunk[index]
+  }
+
+  /// Replaces the tile at the given world position. The change is saved with
+  /// the next autosave.
+  pub fn set_tile(&mut self, position: [i64; 2], tile: TerrainTile) {
+    // Ensures the chunk is loaded.
+    self.get_tile(position);
+
+    let (chunk, index) = World::get_chunk_and_index_from_tile_position(position);
+    self.chunk_table.set_tile(chunk, index, tile);
   }
 }
 
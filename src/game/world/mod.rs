@@ -2,7 +2,6 @@ use bevy::prelude::*;
 
 use crate::db::models::World;
 use crate::db::DatabaseManager;
-use crate::game::world::gen::WorldGenPlugin;
 use crate::properties::GameProperties;
 
 mod gen;
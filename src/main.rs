@@ -18,6 +18,7 @@ pub mod db;
 pub mod debug;
 pub mod game;
 pub mod properties;
+pub mod rpc;
 
 fn main() {
   dotenv::dotenv().ok();
@@ -30,12 +31,24 @@ fn main() {
     return;
   }
 
+  // Game content is loaded relative to the working directory, and reloaded
+  // whenever it changes.
+  let asset_plugin = AssetPlugin {
+    asset_folder: ".".to_string(),
+    watch_for_changes: true,
+  };
+
   app = if Some(ArgsSideEffect::AddDebuggingWindowPlugins) == args_effect {
-    app.add_plugins(DefaultPlugins).add_plugin(DebugCameraPlugin);
+    app
+      .add_plugins(DefaultPlugins.set(asset_plugin))
+      .add_plugin(DebugCameraPlugin);
     info!("Debug Window Enabled");
     app
   } else {
-    app.add_plugins(MinimalPlugins).add_plugin(LogPlugin::default())
+    app
+      .add_plugins(MinimalPlugins)
+      .add_plugin(LogPlugin::default())
+      .add_plugin(asset_plugin)
   };
 
   info!("Loading plugins...");
@@ -43,7 +56,8 @@ fn main() {
   app = app
     .add_plugin(properties::PropertiesPlugin)
     .add_plugin(db::DatabasePlugin)
-    .add_plugins(game::GamePlugins);
+    .add_plugins(game::GamePlugins)
+    .add_plugin(rpc::RpcPlugin);
 
   info!("Starting game...");
 
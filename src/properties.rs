@@ -5,6 +5,8 @@ use bevy::prelude::*;
 use rand::{thread_rng, Rng};
 use serde::{Deserialize, Serialize};
 
+use crate::game::resources::{Resource, ResourceDelta};
+
 /// Game properties file, stored at `properties.toml`
 #[derive(Serialize, Deserialize, Resource)]
 pub struct GameProperties {
@@ -14,6 +16,39 @@ pub struct GameProperties {
   pub tick_speed: u32,
   /// Seed of the world
   pub seed: i64,
+  /// Number of ticks between saving the game to the database, default is 60
+  #[serde(default = "GameProperties::default_autosave_interval")]
+  pub autosave_interval: u32,
+  /// Fraction of the construction costs refunded when demolishing a
+  /// building, default is 0.5
+  #[serde(default = "GameProperties::default_demolish_refund")]
+  pub demolish_refund: f32,
+  /// Storage capacity every user has for each resource before storage
+  /// buildings, default is 1000
+  #[serde(default = "GameProperties::default_base_storage")]
+  pub base_storage: i64,
+  /// Number of ticks before an open trade offer expires, default is 600
+  #[serde(default = "GameProperties::default_trade_expiry")]
+  pub trade_expiry: u32,
+  /// Number of spawn zones evenly spaced on a ring around the world origin.
+  /// New users are spread across the zones, or spawn near the origin when
+  /// there are none. Default is 8
+  #[serde(default = "GameProperties::default_spawn_zones")]
+  pub spawn_zones: u32,
+  /// Distance of the spawn zones from the world origin, default is 256
+  #[serde(default = "GameProperties::default_spawn_ring_radius")]
+  pub spawn_ring_radius: u32,
+  /// How far from the center of a spawn zone a starter Headquarters may be
+  /// placed, default is 32
+  #[serde(default = "GameProperties::default_spawn_search_radius")]
+  pub spawn_search_radius: u32,
+  /// Number of positions tried around each spawn zone before moving on to the
+  /// next one, default is 64
+  #[serde(default = "GameProperties::default_spawn_search_attempts")]
+  pub spawn_search_attempts: u32,
+  /// Resources granted to every new user, default is 200 Credits
+  #[serde(default = "GameProperties::default_starting_resources")]
+  pub starting_resources: Vec<ResourceDelta>,
 }
 
 impl Default for GameProperties {
@@ -23,6 +58,15 @@ impl Default for GameProperties {
       rpc_port: 1337,
       tick_speed: 1,
       seed: rng.gen(),
+      autosave_interval: Self::default_autosave_interval(),
+      demolish_refund: Self::default_demolish_refund(),
+      base_storage: Self::default_base_storage(),
+      trade_expiry: Self::default_trade_expiry(),
+      spawn_zones: Self::default_spawn_zones(),
+      spawn_ring_radius: Self::default_spawn_ring_radius(),
+      spawn_search_radius: Self::default_spawn_search_radius(),
+      spawn_search_attempts: Self::default_spawn_search_attempts(),
+      starting_resources: Self::default_starting_resources(),
     }
   }
 }
@@ -37,6 +81,42 @@ pub enum GamePropertiesError {
 impl GameProperties {
   pub const LOCATION: &'static str = "properties.toml";
 
+  fn default_autosave_interval() -> u32 {
+    60
+  }
+
+  fn default_demolish_refund() -> f32 {
+    0.5
+  }
+
+  fn default_base_storage() -> i64 {
+    1000
+  }
+
+  fn default_trade_expiry() -> u32 {
+    600
+  }
+
+  fn default_spawn_zones() -> u32 {
+    8
+  }
+
+  fn default_spawn_ring_radius() -> u32 {
+    256
+  }
+
+  fn default_spawn_search_radius() -> u32 {
+    32
+  }
+
+  fn default_spawn_search_attempts() -> u32 {
+    64
+  }
+
+  fn default_starting_resources() -> Vec<ResourceDelta> {
+    vec![Resource::Credit.d(200)]
+  }
+
   pub fn from_file() -> Result<Self, GamePropertiesError> {
     let config = fs::read_to_string(Self::LOCATION).map_err(GamePropertiesError::FileError)?;
     toml::from_str(&config).map_err(GamePropertiesError::ParsingError)
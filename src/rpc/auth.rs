@@ -0,0 +1,374 @@
+//! User Authentication
+//!
+//! Users register and log in over RPC with a username and password, stored as
+//! an argon2 hash in `user_credentials`. Logging in issues a random session
+//! token, and every request made with that token acts as the user it was
+//! issued to. Sessions are held by the RPC server, and end on logout, once
+//! unused for [SESSION_IDLE_TIMEOUT], or when the server restarts. After
+//! [MAX_LOGIN_ATTEMPTS] logins without success, a username cannot log in until
+//! [LOGIN_LOCKOUT] has passed since the first of them.
+
+use std::fmt;
+use std::time::{Duration, Instant};
+
+use argon2::password_hash::rand_core::OsRng;
+use argon2::password_hash::SaltString;
+use argon2::{Argon2, PasswordHash, PasswordHasher, PasswordVerifier};
+use diesel::result::{DatabaseErrorKind, Error as DieselError};
+use hashbrown::HashMap;
+use lazy_static::lazy_static;
+use rand::{thread_rng, Rng};
+use uuid::Uuid;
+
+use crate::db::models::{User, UserCredentials};
+use crate::db::DatabaseManager;
+
+pub const MAX_USERNAME_LENGTH: usize = 32;
+pub const MIN_PASSWORD_LENGTH: usize = 8;
+pub const SESSION_IDLE_TIMEOUT: Duration = Duration::from_secs(60 * 60);
+pub const MAX_LOGIN_ATTEMPTS: u32 = 5;
+pub const LOGIN_LOCKOUT: Duration = Duration::from_secs(15 * 60);
+
+/// Reasons a user could not register or log in.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum AuthError {
+  /// The username is empty, too long, or contains whitespace.
+  InvalidUsername,
+  /// The password is shorter than [MIN_PASSWORD_LENGTH].
+  WeakPassword,
+  UsernameTaken,
+  /// No user exists with the username, or the password is wrong.
+  InvalidCredentials,
+  /// The username made [MAX_LOGIN_ATTEMPTS] logins without success.
+  TooManyAttempts,
+  /// The credentials could not be read or written.
+  Unavailable,
+}
+
+impl fmt::Display for AuthError {
+  fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+    match self {
+      Self::InvalidUsername => write!(
+        f,
+        "Usernames must be 1 to {} characters, without whitespace",
+        MAX_USERNAME_LENGTH
+      ),
+      Self::WeakPassword => write!(f, "Passwords must be at least {} characters", MIN_PASSWORD_LENGTH),
+      Self::UsernameTaken => write!(f, "Username is already taken"),
+      Self::InvalidCredentials => write!(f, "Invalid username or password"),
+      Self::TooManyAttempts => write!(f, "Too many login attempts, try again later"),
+      Self::Unavailable => write!(f, "Authentication is unavailable, try again later"),
+    }
+  }
+}
+
+impl From<DieselError> for AuthError {
+  fn from(err: DieselError) -> Self {
+    match err {
+      DieselError::DatabaseError(DatabaseErrorKind::UniqueViolation, _) => Self::UsernameTaken,
+      _ => Self::Unavailable,
+    }
+  }
+}
+
+pub fn validate_registration(username: &str, password: &str) -> Result<(), AuthError> {
+  let length = username.chars().count();
+  if length == 0 || length > MAX_USERNAME_LENGTH || username.chars().any(char::is_whitespace) {
+    return Err(AuthError::InvalidUsername);
+  }
+
+  if password.chars().count() < MIN_PASSWORD_LENGTH {
+    return Err(AuthError::WeakPassword);
+  }
+
+  Ok(())
+}
+
+/// Hashes a password with a random salt. This is deliberately slow, and should
+/// be kept off of the game thread.
+pub fn hash_password(password: &str) -> Result<String, AuthError> {
+  Argon2::default()
+    .hash_password(password.as_bytes(), &SaltString::generate(&mut OsRng))
+    .map(|hash| hash.to_string())
+    .map_err(|_| AuthError::Unavailable)
+}
+
+/// Checks a password against a hash from [hash_password].
+pub fn verify_password(password: &str, password_hash: &str) -> bool {
+  PasswordHash::new(password_hash)
+    .map(|hash| Argon2::default().verify_password(password.as_bytes(), &hash).is_ok())
+    .unwrap_or(false)
+}
+
+lazy_static! {
+  /// Checked against when logging in with an unknown username, so that it
+  /// takes as long as a wrong password and doesn't reveal which usernames
+  /// exist.
+  static ref DUMMY_PASSWORD_HASH: String = hash_password("not a password").expect("Failed to hash the dummy password");
+}
+
+/// Checks a password against the credentials found for a username, returning
+/// the user it belongs to.
+pub fn verify_login(password: &str, credentials: Option<UserCredentials>) -> Result<Uuid, AuthError> {
+  match credentials {
+    Some(credentials) if verify_password(password, &credentials.password_hash) => Ok(credentials.user_id),
+    Some(_) => Err(AuthError::InvalidCredentials),
+    None => {
+      verify_password(password, &DUMMY_PASSWORD_HASH);
+      Err(AuthError::InvalidCredentials)
+    },
+  }
+}
+
+/// Creates a user with an already hashed password, returning their id. Like
+/// every query, this blocks and should be kept off of the game thread.
+pub fn register_user(database: &DatabaseManager, username: &str, password_hash: &str) -> Result<Uuid, AuthError> {
+  let mut conn = database.try_take().map_err(|_| AuthError::Unavailable)?;
+  Ok(User::register(&mut conn, username, password_hash)?.id)
+}
+
+pub fn find_credentials(database: &DatabaseManager, username: &str) -> Result<Option<UserCredentials>, AuthError> {
+  let mut conn = database.try_take().map_err(|_| AuthError::Unavailable)?;
+  Ok(UserCredentials::find(&mut conn, username)?)
+}
+
+/// User logged in with a session token, and when the token was last used.
+struct Session {
+  user_id: Uuid,
+  last_used: Instant,
+}
+
+/// Users logged in with each session token.
+pub struct Sessions {
+  sessions: HashMap<String, Session>,
+  idle_timeout: Duration,
+}
+
+impl Default for Sessions {
+  fn default() -> Self {
+    Self::new(SESSION_IDLE_TIMEOUT)
+  }
+}
+
+impl Sessions {
+  /// Sessions that end once unused for `idle_timeout`.
+  pub fn new(idle_timeout: Duration) -> Self {
+    Self {
+      sessions: HashMap::new(),
+      idle_timeout,
+    }
+  }
+
+  /// Starts a session for the user, returning its token.
+  pub fn start(&mut self, user_id: Uuid) -> String {
+    let token = thread_rng()
+      .gen::<[u8; 32]>()
+      .iter()
+      .map(|byte| format!("{:02x}", byte))
+      .collect::<String>();
+
+    // Abandoned sessions are never ended, so they are dropped here instead.
+    let idle_timeout = self.idle_timeout;
+    self
+      .sessions
+      .retain(|_, session| session.last_used.elapsed() < idle_timeout);
+
+    self.sessions.insert(
+      token.clone(),
+      Session {
+        user_id,
+        last_used: Instant::now(),
+      },
+    );
+    token
+  }
+
+  /// User logged in with the token, if the session is still active. Using
+  /// the token keeps the session alive.
+  pub fn user(&mut self, token: &str) -> Option<Uuid> {
+    let session = self.sessions.get_mut(token)?;
+    if session.last_used.elapsed() >= self.idle_timeout {
+      self.sessions.remove(token);
+      return None;
+    }
+
+    session.last_used = Instant::now();
+    Some(session.user_id)
+  }
+
+  /// Ends the session, returning whether it was active.
+  pub fn end(&mut self, token: &str) -> bool {
+    self.sessions.remove(token).is_some()
+  }
+}
+
+/// Logins made with a username since the first one without success.
+struct Attempts {
+  count: u32,
+  since: Instant,
+}
+
+/// Limits the logins made with each username, so that passwords can't be
+/// guessed at the rate the server answers.
+pub struct LoginAttempts {
+  attempts: HashMap<String, Attempts>,
+  max_attempts: u32,
+  lockout: Duration,
+}
+
+impl Default for LoginAttempts {
+  fn default() -> Self {
+    Self::new(MAX_LOGIN_ATTEMPTS, LOGIN_LOCKOUT)
+  }
+}
+
+impl LoginAttempts {
+  /// Allows `max_attempts` logins without success within each `lockout`.
+  pub fn new(max_attempts: u32, lockout: Duration) -> Self {
+    Self {
+      attempts: HashMap::new(),
+      max_attempts,
+      lockout,
+    }
+  }
+
+  /// Counts a login with the username, before its password is checked, so
+  /// that concurrent logins count as well. Fails if the username used up its
+  /// attempts.
+  pub fn begin(&mut self, username: &str) -> Result<(), AuthError> {
+    let lockout = self.lockout;
+    self.attempts.retain(|_, attempts| attempts.since.elapsed() < lockout);
+
+    let attempts = self.attempts.entry(username.to_string()).or_insert(Attempts {
+      count: 0,
+      since: Instant::now(),
+    });
+    if attempts.count >= self.max_attempts {
+      return Err(AuthError::TooManyAttempts);
+    }
+
+    attempts.count += 1;
+    Ok(())
+  }
+
+  /// Clears the attempts of a username that logged in.
+  pub fn succeeded(&mut self, username: &str) {
+    self.attempts.remove(username);
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use std::time::Duration;
+
+  use uuid::Uuid;
+
+  use super::{
+    find_credentials, hash_password, register_user, validate_registration, verify_login, verify_password, AuthError,
+    LoginAttempts, Sessions,
+  };
+  use crate::db::models::UserCredentials;
+  use crate::db::DatabaseManager;
+
+  #[test]
+  fn validates_registrations() {
+    assert_eq!(validate_registration("alice", "correct horse"), Ok(()));
+    assert_eq!(
+      validate_registration("", "correct horse"),
+      Err(AuthError::InvalidUsername)
+    );
+    assert_eq!(
+      validate_registration("alice smith", "correct horse"),
+      Err(AuthError::InvalidUsername)
+    );
+    assert_eq!(
+      validate_registration(&"a".repeat(33), "correct horse"),
+      Err(AuthError::InvalidUsername)
+    );
+    assert_eq!(validate_registration("alice", "hunter2"), Err(AuthError::WeakPassword));
+  }
+
+  #[test]
+  fn verifies_hashed_passwords() {
+    let hash = hash_password("correct horse").unwrap();
+    assert_ne!(hash, "correct horse");
+    assert!(verify_password("correct horse", &hash));
+    assert!(!verify_password("battery staple", &hash));
+    assert!(!verify_password("correct horse", "not a hash"));
+
+    // Every hash is salted differently.
+    assert_ne!(hash_password("correct horse").unwrap(), hash);
+
+    let user_id = Uuid::new_v4();
+    let credentials = UserCredentials {
+      user_id,
+      username: "alice".to_string(),
+      password_hash: hash,
+    };
+    assert_eq!(verify_login("correct horse", Some(credentials.clone())), Ok(user_id));
+    assert_eq!(
+      verify_login("battery staple", Some(credentials)),
+      Err(AuthError::InvalidCredentials)
+    );
+    assert_eq!(verify_login("correct horse", None), Err(AuthError::InvalidCredentials));
+  }
+
+  #[test]
+  fn credentials_require_a_database() {
+    let database = DatabaseManager::test_harness();
+    assert_eq!(register_user(&database, "alice", "hash"), Err(AuthError::Unavailable));
+    assert_eq!(find_credentials(&database, "alice").err(), Some(AuthError::Unavailable));
+  }
+
+  #[test]
+  fn sessions_are_bound_to_users() {
+    let (alice, bob) = (Uuid::new_v4(), Uuid::new_v4());
+    let mut sessions = Sessions::default();
+
+    let alice_token = sessions.start(alice);
+    let bob_token = sessions.start(bob);
+    assert_ne!(alice_token, bob_token);
+    assert_eq!(sessions.user(&alice_token), Some(alice));
+    assert_eq!(sessions.user(&bob_token), Some(bob));
+    assert_eq!(sessions.user("forged"), None);
+
+    assert!(sessions.end(&alice_token));
+    assert!(!sessions.end(&alice_token));
+    assert_eq!(sessions.user(&alice_token), None);
+    assert_eq!(sessions.user(&bob_token), Some(bob));
+  }
+
+  #[test]
+  fn sessions_expire_when_idle() {
+    let alice = Uuid::new_v4();
+
+    let mut sessions = Sessions::new(Duration::ZERO);
+    let token = sessions.start(alice);
+    assert_eq!(sessions.user(&token), None);
+    assert!(!sessions.end(&token));
+
+    let mut sessions = Sessions::new(Duration::from_secs(60));
+    let token = sessions.start(alice);
+    assert_eq!(sessions.user(&token), Some(alice));
+    assert_eq!(sessions.user(&token), Some(alice));
+  }
+
+  #[test]
+  fn limits_login_attempts() {
+    let mut attempts = LoginAttempts::new(2, Duration::from_secs(60));
+    assert_eq!(attempts.begin("alice"), Ok(()));
+    assert_eq!(attempts.begin("alice"), Ok(()));
+    assert_eq!(attempts.begin("alice"), Err(AuthError::TooManyAttempts));
+    // Other usernames have their own attempts.
+    assert_eq!(attempts.begin("bob"), Ok(()));
+
+    attempts.succeeded("bob");
+    assert_eq!(attempts.begin("bob"), Ok(()));
+    assert_eq!(attempts.begin("bob"), Ok(()));
+
+    // Attempts are allowed again once the lockout is over.
+    let mut attempts = LoginAttempts::new(1, Duration::ZERO);
+    assert_eq!(attempts.begin("alice"), Ok(()));
+    assert_eq!(attempts.begin("alice"), Ok(()));
+  }
+}
@@ -0,0 +1,428 @@
+//! Bridges requests from the RPC thread into the ECS.
+
+use std::sync::Mutex;
+
+use bevy::ecs::system::SystemParam;
+use bevy::prelude::*;
+use hashbrown::HashMap;
+use tokio::sync::mpsc::error::TryRecvError;
+use tokio::sync::mpsc::{UnboundedReceiver, UnboundedSender};
+use tokio::sync::oneshot;
+use uuid::Uuid;
+
+use crate::db::models::User;
+use crate::game::action::{ActionOutcome, ActionRejection, ActionTicket, GameAction, UserGameAction};
+use crate::game::building::{BuildingId, BuildingIndex};
+use crate::game::market::{Market, MarketDepth, MarketOrder, OrderIndex};
+use crate::game::onboarding::Onboarding;
+use crate::game::power::{PowerGrid, PowerSummary};
+use crate::game::resources::Resource;
+use crate::game::trade::{TradeIndex, TradeOffer};
+use crate::game::user::{StorageCapacity, UserResourceTable};
+
+/// What an accepted action was performed on, as reported to RPC clients.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ActionTarget {
+  Building(BuildingId),
+  /// Id of the trade offer.
+  Trade(Uuid),
+  /// Id of the market order.
+  Order(Uuid),
+  /// The target has no persistent id, so it can't be addressed by clients.
+  Unknown,
+}
+
+pub type ActionReply = Result<ActionTarget, ActionRejection>;
+
+/// A request made by an RPC client that requires access to the game world.
+/// Each request carries a [oneshot::Sender] used to reply once the game has
+/// processed it.
+pub enum RpcRequest {
+  /// Adds a user that was just registered, replying once they are part of
+  /// the game.
+  Join {
+    user_id: Uuid,
+    respond: oneshot::Sender<()>,
+  },
+  GetUser {
+    user_id: Uuid,
+    respond: oneshot::Sender<Option<(User, StorageCapacity)>>,
+  },
+  GetPower {
+    user_id: Uuid,
+    respond: oneshot::Sender<PowerSummary>,
+  },
+  GetMarket {
+    resource: Resource,
+    respond: oneshot::Sender<MarketDepth>,
+  },
+  SubmitAction {
+    user_id: Uuid,
+    action: GameAction,
+    respond: oneshot::Sender<ActionReply>,
+  },
+}
+
+pub type RpcRequestSender = UnboundedSender<RpcRequest>;
+
+/// Receiving end of the requests sent by the RPC thread.
+#[derive(Resource)]
+pub struct RpcBridge(Mutex<UnboundedReceiver<RpcRequest>>);
+
+impl RpcBridge {
+  pub fn new(receiver: UnboundedReceiver<RpcRequest>) -> Self {
+    Self(Mutex::new(receiver))
+  }
+
+  /// Takes all requests currently waiting to be processed.
+  pub fn drain(&self) -> Vec<RpcRequest> {
+    let mut receiver = self.0.lock().unwrap();
+    let mut requests = Vec::new();
+
+    loop {
+      match receiver.try_recv() {
+        Ok(request) => requests.push(request),
+        Err(TryRecvError::Empty) => break,
+        Err(TryRecvError::Disconnected) => {
+          warn!("RPC server disconnected from the game.");
+          break;
+        },
+      }
+    }
+
+    requests
+  }
+}
+
+/// Game actions received over RPC waiting for the next game tick, and the
+/// clients waiting on their [ActionOutcome].
+#[derive(Default, Resource)]
+pub struct PendingGameActions {
+  actions: Vec<UserGameAction>,
+  replies: HashMap<ActionTicket, oneshot::Sender<ActionReply>>,
+  next_ticket: u64,
+}
+
+impl PendingGameActions {
+  pub fn push(&mut self, user_id: Uuid, action: GameAction, respond: oneshot::Sender<ActionReply>) {
+    let ticket = ActionTicket(self.next_ticket);
+    self.next_ticket += 1;
+
+    self.replies.insert(ticket, respond);
+    self.actions.push(UserGameAction {
+      user_id,
+      action,
+      ticket: Some(ticket),
+    });
+  }
+}
+
+pub fn process_rpc_requests(
+  bridge: Res<RpcBridge>,
+  mut user_table: ResMut<UserResourceTable>,
+  mut onboarding: Onboarding,
+  power_grid: Res<PowerGrid>,
+  market: Res<Market>,
+  mut pending_actions: ResMut<PendingGameActions>,
+) {
+  bridge.drain().into_iter().for_each(|request| match request {
+    RpcRequest::Join { user_id, respond } => {
+      user_table.insert(user_id, User::with_resources(user_id, []));
+      onboarding.spawn(&mut user_table, user_id);
+      respond.send(()).ok();
+    },
+    RpcRequest::GetUser { user_id, respond } => {
+      // The client may have disconnected, in which case there is no one to
+      // reply to.
+      let capacity = user_table.capacity(&user_id).cloned().unwrap_or_default();
+      respond
+        .send(user_table.get(&user_id).map(|user| (user.clone(), capacity)))
+        .ok();
+    },
+    RpcRequest::GetPower { user_id, respond } => {
+      // Users without generators or consumers have an empty grid.
+      respond.send(power_grid.get(&user_id).copied().unwrap_or_default()).ok();
+    },
+    RpcRequest::GetMarket { resource, respond } => {
+      respond.send(market.depth(resource)).ok();
+    },
+    RpcRequest::SubmitAction {
+      user_id,
+      action,
+      respond,
+    } => pending_actions.push(user_id, action, respond),
+  });
+}
+
+/// Sends pending actions into the [UserGameAction] event queue. This runs
+/// within the game tick, as events sent between ticks would otherwise expire
+/// before being read.
+pub fn submit_pending_game_actions(
+  mut pending_actions: ResMut<PendingGameActions>,
+  mut events: EventWriter<UserGameAction>,
+) {
+  events.send_batch(pending_actions.actions.drain(..));
+}
+
+/// System parameter to find what an action was performed on.
+#[derive(SystemParam)]
+pub struct ActionTargets<'w, 's> {
+  building_index: Res<'w, BuildingIndex>,
+  trade_index: Res<'w, TradeIndex>,
+  order_index: Res<'w, OrderIndex>,
+  buildings: Query<'w, 's, &'static BuildingId>,
+  offers: Query<'w, 's, &'static TradeOffer>,
+  orders: Query<'w, 's, &'static MarketOrder>,
+}
+
+impl<'w, 's> ActionTargets<'w, 's> {
+  /// Entities despawned this tick, such as demolished buildings, closed
+  /// offers or cancelled orders, are only left in their index.
+  pub fn get(&self, ent: Entity) -> ActionTarget {
+    match (self.buildings.get(ent), self.offers.get(ent), self.orders.get(ent)) {
+      (Ok(id), ..) => ActionTarget::Building(*id),
+      (_, Ok(offer), _) => ActionTarget::Trade(offer.id),
+      (.., Ok(order)) => ActionTarget::Order(order.id),
+      _ => (self.building_index.id(ent).map(ActionTarget::Building))
+        .or_else(|| self.trade_index.id(ent).map(ActionTarget::Trade))
+        .or_else(|| self.order_index.id(ent).map(ActionTarget::Order))
+        .unwrap_or(ActionTarget::Unknown),
+    }
+  }
+}
+
+/// Replies to RPC clients once their actions have been processed.
+pub fn reply_to_action_outcomes(
+  mut pending_actions: ResMut<PendingGameActions>,
+  mut outcomes: EventReader<ActionOutcome>,
+  targets: ActionTargets,
+) {
+  outcomes.iter().for_each(|outcome| {
+    if let Some(ticket) = outcome.ticket
+      && let Some(respond) = pending_actions.replies.remove(&ticket)
+    {
+      respond.send(outcome.result.map(|ent| targets.get(ent))).ok();
+    }
+  });
+}
+
+#[cfg(test)]
+mod tests {
+  use bevy::prelude::*;
+  use chrono::NaiveDateTime;
+  use hashbrown::HashMap;
+  use tokio::sync::{mpsc, oneshot};
+  use uuid::Uuid;
+
+  use super::{
+    process_rpc_requests, reply_to_action_outcomes, submit_pending_game_actions, ActionTarget, PendingGameActions,
+    RpcBridge, RpcRequest,
+  };
+  use crate::db::models::{User, World, WorldObj};
+  use crate::db::DatabaseManager;
+  use crate::game::action::{process_game_actions, ActionRejection, GameAction, GameActionPlugin};
+  use crate::game::building::{
+    BuildingDefinitionTable, BuildingId, BuildingIndex, BuildingOccupancy, NextBuildingSequence,
+  };
+  use crate::game::market::{process_market_actions, Market, MarketDepth, MarketPlugin, OrderIndex, OrderSide};
+  use crate::game::power::{PowerGrid, PowerSummary};
+  use crate::game::resources::Resource;
+  use crate::game::stages::{GameStage, StagePlugin};
+  use crate::game::trade::TradeIndex;
+  use crate::game::user::{UserOwned, UserResourceTable};
+  use crate::game::world::WorldGenPlugin;
+  use crate::properties::GameProperties;
+
+  #[test]
+  fn bridge_answers_get_user() {
+    let (sender, receiver) = mpsc::unbounded_channel();
+
+    let mut app = App::new();
+    app
+      .add_plugins(MinimalPlugins)
+      .insert_resource(DatabaseManager::test_harness())
+      .insert_resource::<World>(
+        WorldObj {
+          id: 0,
+          origin_time: NaiveDateTime::MIN,
+          seed: 1337,
+        }
+        .into(),
+      )
+      .add_plugin(WorldGenPlugin)
+      .init_resource::<BuildingOccupancy>()
+      .init_resource::<NextBuildingSequence>()
+      .insert_resource(BuildingDefinitionTable::load())
+      .insert_resource(GameProperties::default())
+      .insert_resource(RpcBridge::new(receiver))
+      .init_resource::<PendingGameActions>()
+      .init_resource::<PowerGrid>()
+      .init_resource::<Market>()
+      .add_system(process_rpc_requests);
+
+    let id = Uuid::new_v4();
+    let user = User::with_resources(id, [(Resource::Credit, 12)]);
+
+    app
+      .world
+      .insert_resource(UserResourceTable::new(HashMap::from([(id, user)])));
+
+    let (respond, mut found) = oneshot::channel();
+    sender.send(RpcRequest::GetUser { user_id: id, respond }).ok();
+    let (respond, mut missing) = oneshot::channel();
+    sender
+      .send(RpcRequest::GetUser {
+        user_id: Uuid::new_v4(),
+        respond,
+      })
+      .ok();
+    let (respond, mut power) = oneshot::channel();
+    sender.send(RpcRequest::GetPower { user_id: id, respond }).ok();
+    let (respond, mut market) = oneshot::channel();
+    sender
+      .send(RpcRequest::GetMarket {
+        resource: Resource::Iron,
+        respond,
+      })
+      .ok();
+
+    let joined = Uuid::new_v4();
+    let (respond, mut join) = oneshot::channel();
+    sender
+      .send(RpcRequest::Join {
+        user_id: joined,
+        respond,
+      })
+      .ok();
+
+    app.update();
+
+    assert_eq!(found.try_recv().unwrap().unwrap().0.get(Resource::Credit), 12);
+    assert!(missing.try_recv().unwrap().is_none());
+    assert_eq!(power.try_recv().unwrap(), PowerSummary::default());
+    assert_eq!(market.try_recv().unwrap(), MarketDepth::default());
+
+    // Joining users are added and spawned right away.
+    assert_eq!(join.try_recv(), Ok(()));
+    assert!(app.world.resource::<UserResourceTable>().contains_key(&joined));
+    assert!(app
+      .world
+      .query::<&UserOwned>()
+      .iter(&app.world)
+      .any(|owner| owner.0 == joined));
+  }
+
+  #[test]
+  fn bridge_submits_actions() {
+    let (sender, receiver) = mpsc::unbounded_channel();
+
+    let mut app = App::new();
+    app
+      .add_plugins(MinimalPlugins)
+      .add_plugin(StagePlugin)
+      .insert_resource(DatabaseManager::test_harness())
+      .insert_resource::<World>(
+        WorldObj {
+          id: 0,
+          origin_time: NaiveDateTime::MIN,
+          seed: 1337,
+        }
+        .into(),
+      )
+      .add_plugin(WorldGenPlugin)
+      .add_plugin(GameActionPlugin)
+      .add_plugin(MarketPlugin)
+      .init_resource::<BuildingOccupancy>()
+      .init_resource::<BuildingIndex>()
+      .init_resource::<NextBuildingSequence>()
+      .init_resource::<TradeIndex>()
+      .init_resource::<OrderIndex>()
+      .insert_resource(BuildingDefinitionTable::load())
+      .insert_resource(GameProperties::default())
+      .insert_resource(RpcBridge::new(receiver))
+      .init_resource::<PendingGameActions>()
+      .init_resource::<PowerGrid>()
+      .init_resource::<Market>()
+      .insert_resource(UserResourceTable::new(HashMap::new()))
+      .add_system(process_rpc_requests)
+      .add_system_to_stage(
+        GameStage::Start,
+        submit_pending_game_actions
+          .before(process_game_actions)
+          .before(process_market_actions),
+      )
+      .add_system_to_stage(GameStage::Cleanup, reply_to_action_outcomes);
+
+    let id = Uuid::new_v4();
+    app
+      .world
+      .resource_mut::<UserResourceTable>()
+      .insert(id, User::with_resources(id, [(Resource::Credit, 1)]));
+
+    let (respond, mut built) = oneshot::channel();
+    sender
+      .send(RpcRequest::SubmitAction {
+        user_id: id,
+        action: GameAction::BuildBuilding {
+          building_id: "Headquarters".to_string(),
+          position: IVec2::ZERO,
+        },
+        respond,
+      })
+      .ok();
+    let (respond, mut unknown) = oneshot::channel();
+    sender
+      .send(RpcRequest::SubmitAction {
+        user_id: id,
+        action: GameAction::BuildBuilding {
+          building_id: "Not a Building".to_string(),
+          position: IVec2::ZERO,
+        },
+        respond,
+      })
+      .ok();
+
+    app.update();
+
+    // Buildings are reported by their persistent id.
+    let Ok(ActionTarget::Building(building)) = built.try_recv().unwrap() else {
+      panic!("Expected the built building");
+    };
+    assert!(app
+      .world
+      .query::<&BuildingId>()
+      .iter(&app.world)
+      .any(|id| *id == building));
+    assert_eq!(unknown.try_recv().unwrap(), Err(ActionRejection::UnknownBuilding));
+
+    // So are market orders, even once cancelled.
+    let (respond, mut placed) = oneshot::channel();
+    sender
+      .send(RpcRequest::SubmitAction {
+        user_id: id,
+        action: GameAction::PlaceOrder {
+          resource: Resource::Iron,
+          side: OrderSide::Buy,
+          price: 1,
+          quantity: 1,
+        },
+        respond,
+      })
+      .ok();
+    app.update();
+
+    let Ok(ActionTarget::Order(order)) = placed.try_recv().unwrap() else {
+      panic!("Expected the placed order");
+    };
+    let (respond, mut cancelled) = oneshot::channel();
+    sender
+      .send(RpcRequest::SubmitAction {
+        user_id: id,
+        action: GameAction::CancelOrder { order },
+        respond,
+      })
+      .ok();
+    app.update();
+    assert_eq!(cancelled.try_recv().unwrap(), Ok(ActionTarget::Order(order)));
+  }
+}
@@ -0,0 +1,80 @@
+//! Cap'n Proto RPC Server.
+//!
+//! The server runs on its own thread with a single threaded tokio runtime, as
+//! [capnp_rpc::RpcSystem] is not [Send]. Incoming calls are forwarded to the
+//! Bevy app as [RpcRequest]s and answered by systems with access to the ECS.
+//! Credentials are queried directly from the database on the blocking thread
+//! pool, keeping registration and login off of the game thread.
+
+use std::net::SocketAddr;
+use std::thread;
+
+use bevy::prelude::*;
+use tokio::runtime;
+use tokio::sync::mpsc;
+use tokio::task::LocalSet;
+
+use crate::db::DatabaseManager;
+use crate::game::action::process_game_actions;
+use crate::game::market::process_market_actions;
+use crate::game::stages::GameStage;
+use crate::game::trade::process_trade_actions;
+use crate::properties::GameProperties;
+
+mod auth;
+mod bridge;
+mod server;
+
+pub use auth::*;
+pub use bridge::*;
+
+pub mod game_capnp {
+  include!(concat!(env!("OUT_DIR"), "/game_capnp.rs"));
+}
+
+pub struct RpcPlugin;
+
+impl Plugin for RpcPlugin {
+  fn build(&self, app: &mut App) {
+    let properties: &GameProperties = app
+      .world
+      .get_resource()
+      .expect("Failed to load Game Properties while loading RPC. Is the PropertiesPlugin loaded?");
+
+    let port = u16::try_from(properties.rpc_port).expect("rpc_port must be a valid port number");
+    let database = app
+      .world
+      .get_resource::<DatabaseManager>()
+      .expect("Failed to get DatabasePool. Ensure the DatabasePlugin is added before this plugin.")
+      .clone();
+    let addr = SocketAddr::from(([0, 0, 0, 0], port));
+    let (sender, receiver) = mpsc::unbounded_channel();
+
+    thread::Builder::new()
+      .name("rpc".to_string())
+      .spawn(move || {
+        let runtime = runtime::Builder::new_current_thread()
+          .enable_all()
+          .build()
+          .expect("Failed to build RPC runtime");
+
+        if let Err(err) = LocalSet::new().block_on(&runtime, server::serve(addr, sender, database)) {
+          error!("RPC server stopped: {}", err);
+        }
+      })
+      .expect("Failed to spawn RPC thread");
+
+    app
+      .insert_resource(RpcBridge::new(receiver))
+      .init_resource::<PendingGameActions>()
+      .add_system(process_rpc_requests)
+      .add_system_to_stage(
+        GameStage::Start,
+        submit_pending_game_actions
+          .before(process_game_actions)
+          .before(process_trade_actions)
+          .before(process_market_actions),
+      )
+      .add_system_to_stage(GameStage::Cleanup, reply_to_action_outcomes);
+  }
+}
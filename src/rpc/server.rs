@@ -0,0 +1,397 @@
+//! Cap'n Proto implementation of the [game] interface.
+
+use std::cell::RefCell;
+use std::error::Error;
+use std::net::SocketAddr;
+use std::rc::Rc;
+
+use bevy::prelude::*;
+use capnp::capability::Promise;
+use capnp_rpc::rpc_twoparty_capnp::Side;
+use capnp_rpc::twoparty::VatNetwork;
+use capnp_rpc::{pry, RpcSystem};
+use futures::{AsyncReadExt, FutureExt};
+use hashbrown::HashMap;
+use tokio::net::TcpListener;
+use tokio::sync::oneshot;
+use tokio_util::compat::TokioAsyncReadCompatExt;
+use uuid::Uuid;
+
+use super::game_capnp::{action_result, game, game_action, price_level, resource_amount, session};
+use super::{
+  find_credentials, game_capnp, hash_password, register_user, validate_registration, verify_login, ActionReply,
+  ActionTarget, AuthError, LoginAttempts, RpcRequest, RpcRequestSender, Sessions,
+};
+use crate::db::DatabaseManager;
+use crate::game::action::{ActionRejection, GameAction};
+use crate::game::building::{BuildingId, PlacementError, PrerequisiteError};
+use crate::game::market::{OrderSide, PriceLevel};
+use crate::game::resources::{Resource, ResourceDelta};
+
+struct GameImpl {
+  requests: RpcRequestSender,
+  /// Used for credentials, which are queried without involving the game.
+  database: DatabaseManager,
+  /// Shared with the futures of pending logins.
+  sessions: Rc<RefCell<Sessions>>,
+  login_attempts: Rc<RefCell<LoginAttempts>>,
+}
+
+impl GameImpl {
+  /// Forwards a request to the game, returning a receiver for its reply.
+  fn request<T, F>(&self, build: F) -> Result<oneshot::Receiver<T>, capnp::Error>
+  where
+    F: FnOnce(oneshot::Sender<T>) -> RpcRequest,
+  {
+    forward(&self.requests, build)
+  }
+
+  /// User logged in with the session token.
+  fn authenticate(&self, token: &str) -> Result<Uuid, capnp::Error> {
+    self
+      .sessions
+      .borrow_mut()
+      .user(token)
+      .ok_or_else(|| capnp::Error::failed("Invalid or expired session".to_string()))
+  }
+}
+
+fn forward<T, F>(requests: &RpcRequestSender, build: F) -> Result<oneshot::Receiver<T>, capnp::Error>
+where
+  F: FnOnce(oneshot::Sender<T>) -> RpcRequest,
+{
+  let (respond, response) = oneshot::channel();
+  requests
+    .send(build(respond))
+    .map_err(|_| capnp::Error::disconnected("Game is no longer running".to_string()))?;
+  Ok(response)
+}
+
+fn parse_uuid(value: &str) -> Result<Uuid, capnp::Error> {
+  Uuid::parse_str(value).map_err(|err| capnp::Error::failed(format!("Invalid id {}: {}", value, err)))
+}
+
+fn dropped(_: oneshot::error::RecvError) -> capnp::Error {
+  capnp::Error::failed("Game dropped the request".to_string())
+}
+
+fn auth_failed(err: AuthError) -> capnp::Error {
+  capnp::Error::failed(err.to_string())
+}
+
+/// Runs password hashing and database queries on the blocking thread pool, so
+/// that they hold up neither the game nor other clients.
+async fn run_blocking<T, F>(work: F) -> Result<T, capnp::Error>
+where
+  T: Send + 'static,
+  F: FnOnce() -> T + Send + 'static,
+{
+  tokio::task::spawn_blocking(work)
+    .await
+    .map_err(|err| capnp::Error::failed(format!("Blocking task failed: {}", err)))
+}
+
+fn write_session(mut builder: session::Builder, token: &str, user_id: Uuid) {
+  builder.set_token(token);
+  builder.set_user_id(&user_id.to_string());
+}
+
+fn parse_resource(name: &str) -> Result<Resource, capnp::Error> {
+  Resource::from_name(name).ok_or_else(|| capnp::Error::failed(format!("Unknown resource {}", name)))
+}
+
+fn read_action(action: game_action::Reader) -> Result<GameAction, capnp::Error> {
+  Ok(match action.which()? {
+    game_action::BuildBuilding(build) => GameAction::BuildBuilding {
+      building_id: build.get_building_id()?.to_string(),
+      position: IVec2::new(build.get_x(), build.get_y()),
+    },
+    game_action::PerformBuildingAction(perform) => GameAction::PerformBuildingAction {
+      building: BuildingId(parse_uuid(perform.get_building_id()?)?),
+      action_id: perform.get_action_id()?.to_string(),
+    },
+    game_action::DemolishBuilding(demolish) => GameAction::DemolishBuilding {
+      building: BuildingId(parse_uuid(demolish.get_building_id()?)?),
+    },
+    game_action::MoveBuilding(building_move) => GameAction::MoveBuilding {
+      building: BuildingId(parse_uuid(building_move.get_building_id()?)?),
+      position: IVec2::new(building_move.get_x(), building_move.get_y()),
+    },
+    game_action::UpgradeBuilding(upgrade) => GameAction::UpgradeBuilding {
+      building: BuildingId(parse_uuid(upgrade.get_building_id()?)?),
+    },
+    game_action::ProposeTrade(trade) => GameAction::ProposeTrade {
+      recipient: parse_uuid(trade.get_recipient_id()?)?,
+      offered: read_resource_amounts(trade.get_offered()?)?,
+      requested: read_resource_amounts(trade.get_requested()?)?,
+    },
+    game_action::AcceptTrade(accept) => GameAction::AcceptTrade {
+      trade: parse_uuid(accept.get_trade_id()?)?,
+    },
+    game_action::CancelTrade(cancel) => GameAction::CancelTrade {
+      trade: parse_uuid(cancel.get_trade_id()?)?,
+    },
+    game_action::PlaceOrder(order) => GameAction::PlaceOrder {
+      resource: parse_resource(order.get_resource()?)?,
+      side: match order.get_side()? {
+        game_capnp::OrderSide::Buy => OrderSide::Buy,
+        game_capnp::OrderSide::Sell => OrderSide::Sell,
+      },
+      price: order.get_price(),
+      quantity: order.get_quantity(),
+    },
+    game_action::CancelOrder(cancel) => GameAction::CancelOrder {
+      order: parse_uuid(cancel.get_order_id()?)?,
+    },
+  })
+}
+
+fn read_resource_amounts(
+  amounts: capnp::struct_list::Reader<resource_amount::Owned>,
+) -> Result<Vec<ResourceDelta>, capnp::Error> {
+  amounts
+    .iter()
+    .map(|amount| Ok(parse_resource(amount.get_resource()?)?.d(amount.get_amount())))
+    .collect()
+}
+
+fn write_action_result(mut builder: action_result::Builder, reply: ActionReply) {
+  match reply {
+    Ok(ActionTarget::Building(id)) => builder.set_building(&id.0.to_string()),
+    Ok(ActionTarget::Trade(id)) => builder.set_trade(&id.to_string()),
+    Ok(ActionTarget::Order(id)) => builder.set_order(&id.to_string()),
+    Ok(ActionTarget::Unknown) => builder.set_accepted(()),
+    Err(rejection) => builder.set_rejected(match rejection {
+      ActionRejection::UnknownBuilding => game_capnp::ActionRejection::UnknownBuilding,
+      ActionRejection::UnknownEntity => game_capnp::ActionRejection::UnknownEntity,
+      ActionRejection::UnknownAction => game_capnp::ActionRejection::UnknownAction,
+      ActionRejection::UnknownUser => game_capnp::ActionRejection::UnknownUser,
+      ActionRejection::NotOwner => game_capnp::ActionRejection::NotOwner,
+      ActionRejection::InsufficientResources => game_capnp::ActionRejection::InsufficientResources,
+      ActionRejection::OnCooldown => game_capnp::ActionRejection::OnCooldown,
+      ActionRejection::UnderConstruction => game_capnp::ActionRejection::UnderConstruction,
+      ActionRejection::MaxLevel => game_capnp::ActionRejection::MaxLevel,
+      ActionRejection::InvalidPlacement(PlacementError::Impassable) => game_capnp::ActionRejection::PlacedOnImpassable,
+      ActionRejection::InvalidPlacement(PlacementError::Water) => game_capnp::ActionRejection::PlacedOnWater,
+      ActionRejection::InvalidPlacement(PlacementError::Mineral) => game_capnp::ActionRejection::PlacedOnMineral,
+      ActionRejection::InvalidPlacement(PlacementError::Occupied) => game_capnp::ActionRejection::TileOccupied,
+      ActionRejection::Locked(PrerequisiteError::MissingBuilding) => game_capnp::ActionRejection::MissingBuilding,
+      ActionRejection::Locked(PrerequisiteError::BuildingLevelTooLow) => {
+        game_capnp::ActionRejection::BuildingLevelTooLow
+      },
+      ActionRejection::Locked(PrerequisiteError::MissingResearch) => game_capnp::ActionRejection::MissingResearch,
+      ActionRejection::InvalidTrade => game_capnp::ActionRejection::InvalidTrade,
+      ActionRejection::InvalidOrder => game_capnp::ActionRejection::InvalidOrder,
+      ActionRejection::Busy => game_capnp::ActionRejection::Busy,
+    }),
+  }
+}
+
+fn write_resource_amounts(
+  mut builder: capnp::struct_list::Builder<resource_amount::Owned>,
+  amounts: &HashMap<Resource, i64>,
+) {
+  amounts.iter().enumerate().for_each(|(index, (resource, amount))| {
+    let mut entry = builder.reborrow().get(index as u32);
+    entry.set_resource(&resource.name());
+    entry.set_amount(*amount);
+  });
+}
+
+fn write_price_levels(mut builder: capnp::struct_list::Builder<price_level::Owned>, levels: &[PriceLevel]) {
+  levels.iter().enumerate().for_each(|(index, level)| {
+    let mut entry = builder.reborrow().get(index as u32);
+    entry.set_price(level.price);
+    entry.set_quantity(level.quantity);
+  });
+}
+
+impl game::Server for GameImpl {
+  fn get_user(&mut self, params: game::GetUserParams, mut results: game::GetUserResults) -> Promise<(), capnp::Error> {
+    let user_id = pry!(self.authenticate(pry!(pry!(params.get()).get_token())));
+    let response = pry!(self.request(|respond| RpcRequest::GetUser { user_id, respond }));
+
+    Promise::from_future(async move {
+      let (user, capacity) = response
+        .await
+        .map_err(dropped)?
+        .ok_or_else(|| capnp::Error::failed(format!("User {} does not exist", user_id)))?;
+
+      let mut builder = results.get().init_user();
+      builder.set_id(&user.id.to_string());
+      write_resource_amounts(
+        builder.reborrow().init_resources(user.resources.len() as u32),
+        &user.resources,
+      );
+      write_resource_amounts(builder.init_capacity(capacity.len() as u32), &capacity);
+      Ok(())
+    })
+  }
+
+  fn get_power(
+    &mut self,
+    params: game::GetPowerParams,
+    mut results: game::GetPowerResults,
+  ) -> Promise<(), capnp::Error> {
+    let user_id = pry!(self.authenticate(pry!(pry!(params.get()).get_token())));
+    let response = pry!(self.request(|respond| RpcRequest::GetPower { user_id, respond }));
+
+    Promise::from_future(async move {
+      let power = response.await.map_err(dropped)?;
+
+      let mut builder = results.get().init_power();
+      builder.set_produced(power.produced);
+      builder.set_demand(power.demand);
+      builder.set_consumed(power.consumed);
+      builder.set_browned_out(power.browned_out);
+      Ok(())
+    })
+  }
+
+  fn get_market(
+    &mut self,
+    params: game::GetMarketParams,
+    mut results: game::GetMarketResults,
+  ) -> Promise<(), capnp::Error> {
+    let resource = pry!(parse_resource(pry!(pry!(params.get()).get_resource())));
+    let response = pry!(self.request(|respond| RpcRequest::GetMarket { resource, respond }));
+
+    Promise::from_future(async move {
+      let depth = response.await.map_err(dropped)?;
+
+      let mut builder = results.get().init_market();
+      write_price_levels(builder.reborrow().init_bids(depth.bids.len() as u32), &depth.bids);
+      write_price_levels(builder.reborrow().init_asks(depth.asks.len() as u32), &depth.asks);
+      match depth.last_price {
+        Some(price) => builder.get_last_trade().set_price(price),
+        None => builder.get_last_trade().set_none(()),
+      }
+      Ok(())
+    })
+  }
+
+  fn submit_action(
+    &mut self,
+    params: game::SubmitActionParams,
+    mut results: game::SubmitActionResults,
+  ) -> Promise<(), capnp::Error> {
+    let params = pry!(params.get());
+    let user_id = pry!(self.authenticate(pry!(params.get_token())));
+    let action = pry!(read_action(pry!(params.get_action())));
+    let response = pry!(self.request(|respond| RpcRequest::SubmitAction {
+      user_id,
+      action,
+      respond,
+    }));
+
+    Promise::from_future(async move {
+      let result = response.await.map_err(dropped)?;
+      write_action_result(results.get().init_result(), result);
+      Ok(())
+    })
+  }
+
+  fn register(
+    &mut self,
+    params: game::RegisterParams,
+    mut results: game::RegisterResults,
+  ) -> Promise<(), capnp::Error> {
+    let params = pry!(params.get());
+    let username = pry!(params.get_username()).to_string();
+    let password = pry!(params.get_password()).to_string();
+    pry!(validate_registration(&username, &password).map_err(auth_failed));
+
+    let requests = self.requests.clone();
+    let database = self.database.clone();
+    let sessions = self.sessions.clone();
+    Promise::from_future(async move {
+      let password_hash = run_blocking(move || hash_password(&password))
+        .await?
+        .map_err(auth_failed)?;
+      let user_id = run_blocking(move || {
+        register_user(&database, &username, &password_hash).map_err(|err| {
+          debug!("Failed to register user {}: {:?}", username, err);
+          err
+        })
+      })
+      .await?
+      .map_err(auth_failed)?;
+
+      let response = forward(&requests, |respond| RpcRequest::Join { user_id, respond })?;
+      response.await.map_err(dropped)?;
+
+      let token = sessions.borrow_mut().start(user_id);
+      write_session(results.get().init_session(), &token, user_id);
+      Ok(())
+    })
+  }
+
+  fn login(&mut self, params: game::LoginParams, mut results: game::LoginResults) -> Promise<(), capnp::Error> {
+    let params = pry!(params.get());
+    let username = pry!(params.get_username()).to_string();
+    let password = pry!(params.get_password()).to_string();
+    pry!(self.login_attempts.borrow_mut().begin(&username).map_err(auth_failed));
+
+    let database = self.database.clone();
+    let sessions = self.sessions.clone();
+    let login_attempts = self.login_attempts.clone();
+    Promise::from_future(async move {
+      let lookup = username.clone();
+      let credentials = run_blocking(move || find_credentials(&database, &lookup))
+        .await?
+        .map_err(auth_failed)?;
+      let user_id = run_blocking(move || verify_login(&password, credentials))
+        .await?
+        .map_err(auth_failed)?;
+
+      login_attempts.borrow_mut().succeeded(&username);
+      let token = sessions.borrow_mut().start(user_id);
+      write_session(results.get().init_session(), &token, user_id);
+      Ok(())
+    })
+  }
+
+  fn logout(&mut self, params: game::LogoutParams, _: game::LogoutResults) -> Promise<(), capnp::Error> {
+    let token = pry!(pry!(params.get()).get_token());
+    self.sessions.borrow_mut().end(token);
+    Promise::ok(())
+  }
+}
+
+/// Accepts RPC clients on the given address. Must be run within a [LocalSet].
+///
+/// [LocalSet]: tokio::task::LocalSet
+pub async fn serve(
+  addr: SocketAddr,
+  requests: RpcRequestSender,
+  database: DatabaseManager,
+) -> Result<(), Box<dyn Error>> {
+  let listener = TcpListener::bind(addr).await.map_err(|err| {
+    error!("Failed to bind RPC server to {}: {}", addr, err);
+    err
+  })?;
+  info!("RPC server listening on {}", addr);
+
+  let client: game::Client = capnp_rpc::new_client(GameImpl {
+    requests,
+    database,
+    sessions: Default::default(),
+    login_attempts: Default::default(),
+  });
+
+  loop {
+    let (stream, peer) = listener.accept().await?;
+    stream.set_nodelay(true)?;
+    debug!("RPC client connected from {}", peer);
+
+    let (reader, writer) = stream.compat().split();
+    let network = VatNetwork::new(reader, writer, Side::Server, Default::default());
+    let rpc_system = RpcSystem::new(Box::new(network), Some(client.clone().client));
+
+    tokio::task::spawn_local(rpc_system.map(move |result| {
+      if let Err(err) = result {
+        warn!("RPC connection from {} closed with error: {}", peer, err);
+      }
+    }));
+  }
+}